@@ -0,0 +1,128 @@
+#!/usr/bin/env python3
+# Writes the kdebug fixtures of the decoder tests, run from kperf-rs with:
+#
+# python3 fixtures/kdebug/generate.py
+#
+# The records are laid out as xnu's kd_buf (bsd/sys/kdebug_private.h): the timestamp, four
+# arguments, the thread, the debugid, the CPU and an unused word, 64 bytes in all. Their
+# arguments are what kperf logs, in the order of the functions of osfmk/kperf named next to
+# each record. Nothing here is shared with kperf-rs, so the tests check the decoder against the
+# layout of the kernel rather than against its own encoder.
+import struct
+from pathlib import Path
+
+DBG_PERF = 37
+DBG_FUNC_START = 1
+DBG_FUNC_END = 2
+
+# kperf/buffer.h
+PERF_GENERIC, PERF_THREADINFO, PERF_CALLSTACK, PERF_KPC = 0, 1, 2, 6
+PERF_GEN_EVENT = 0
+PERF_TI_DATA, PERF_TI_SCHEDDATA_2 = 1, 19
+PERF_CS_KDATA, PERF_CS_UDATA, PERF_CS_KHDR, PERF_CS_UHDR = 3, 4, 5, 6
+PERF_KPC_DATA, PERF_KPC_CONFIG, PERF_KPC_DATA_THREAD, PERF_KPC_CPU_SAMPLE = 3, 4, 8, 10
+
+# kperf/kperf.h
+SAMPLER_TH_INFO, SAMPLER_KSTACK, SAMPLER_USTACK = 1 << 0, 1 << 2, 1 << 3
+SAMPLER_PMC_THREAD, SAMPLER_PMC_CPU, SAMPLER_TH_SCHEDULING = 1 << 4, 1 << 5, 1 << 8
+
+# kperf/callstack.h
+CALLSTACK_VALID, CALLSTACK_64BIT, CALLSTACK_KERNEL, CALLSTACK_TRUNCATED = 1, 4, 8, 0x10
+
+# KPC_CLASS_FIXED_MASK | KPC_CLASS_CONFIGURABLE_MASK
+KPC_CLASSES = 0b11
+
+
+def debugid(class_, subclass, code, func=0):
+    return class_ << 24 | subclass << 16 | code << 2 | func
+
+
+def perf(subclass, code, func=0):
+    return debugid(DBG_PERF, subclass, code, func)
+
+
+def kd_buf(timestamp, cpu, thread, id, *args):
+    args = list(args) + [0] * (4 - len(args))
+    return struct.pack("<QQQQQQIIQ", timestamp, *args, thread, id, cpu, 0)
+
+
+def frames(timestamp, cpu, thread, id, addresses):
+    """Records of callstack_log, 4 frames each, the last one padded with zeros."""
+    return [
+        kd_buf(timestamp + idx, cpu, thread, id, *addresses[idx * 4:idx * 4 + 4])
+        for idx in range((len(addresses) + 3) // 4)
+    ]
+
+
+def interleaved():
+    """Two CPUs sampling at once: CPU 0 samples thread info, kernel and user stacks and 6 CPU
+    counters, CPU 1 thread info, scheduling and 2 thread counters. CPU 1 ends last."""
+    cpu_samplers = SAMPLER_TH_INFO | SAMPLER_USTACK | SAMPLER_KSTACK | SAMPLER_PMC_CPU
+    thread_samplers = SAMPLER_TH_INFO | SAMPLER_TH_SCHEDULING | SAMPLER_PMC_THREAD
+    # kperf_thread_scheduling_log: base priority, scheduled priority, state, then the effective,
+    # requested and override QoS 3 bits each
+    scheduling = 31 << 48 | 47 << 32 | 4 << 24 | 4 << 6 | 3 << 3
+    return [
+        # kperf_kpc_cpu_sample, logged at the info level when the counters are read, before
+        # the sample: the sample config, then the running classes and number of counters
+        kd_buf(990, 0, 0x101, perf(PERF_KPC, PERF_KPC_CPU_SAMPLE, DBG_FUNC_START), 0),
+        kd_buf(995, 0, 0x101, perf(PERF_KPC, PERF_KPC_CPU_SAMPLE, DBG_FUNC_END), KPC_CLASSES, 6),
+        # kperf_sample_internal: the samplers, action, user data and sample flags
+        kd_buf(1000, 0, 0x101, perf(PERF_GENERIC, PERF_GEN_EVENT, DBG_FUNC_START),
+               cpu_samplers, 1),
+        kd_buf(1001, 1, 0x202, perf(PERF_GENERIC, PERF_GEN_EVENT, DBG_FUNC_START),
+               thread_samplers, 2),
+        # kperf_thread_info_log: pid, tid, dispatch queue and run mode
+        kd_buf(1002, 0, 0x101, perf(PERF_THREADINFO, PERF_TI_DATA), 42, 0x101, 0, 1),
+        kd_buf(1003, 1, 0x202, perf(PERF_THREADINFO, PERF_TI_DATA), 7, 0x202, 0, 0),
+        # kperf_thread_scheduling_log: user and system time, then the packed fields
+        kd_buf(1004, 1, 0x202, perf(PERF_THREADINFO, PERF_TI_SCHEDDATA_2), 5000, 700,
+               scheduling),
+        # callstack_log: the flags and number of frames, then the frames
+        kd_buf(1005, 0, 0x101, perf(PERF_CALLSTACK, PERF_CS_KHDR),
+               CALLSTACK_VALID | CALLSTACK_64BIT | CALLSTACK_KERNEL | CALLSTACK_TRUNCATED, 2),
+        *frames(1006, 0, 0x101, perf(PERF_CALLSTACK, PERF_CS_KDATA),
+                [0xffff_ff80_0000_1000, 0xffff_ff80_0000_2000]),
+        # kperf_kpc_config_log: the running classes, number of counters, number of fixed
+        # counters and number of configs, then the counters 4 per record
+        kd_buf(1007, 1, 0x202, perf(PERF_KPC, PERF_KPC_CONFIG), KPC_CLASSES, 2, 2, 0),
+        kd_buf(1008, 0, 0x101, perf(PERF_CALLSTACK, PERF_CS_UHDR),
+               CALLSTACK_VALID | CALLSTACK_64BIT, 5),
+        kd_buf(1009, 1, 0x202, perf(PERF_KPC, PERF_KPC_DATA_THREAD), 11, 22),
+        kd_buf(1010, 0, 0x101, perf(PERF_CALLSTACK, PERF_CS_UDATA),
+               0x1000_0010, 0x1000_0020, 0x1000_0030, 0x1000_0040),
+        kd_buf(1012, 0, 0x101, perf(PERF_CALLSTACK, PERF_CS_UDATA), 0x1000_0050),
+        kd_buf(1013, 0, 0x101, perf(PERF_KPC, PERF_KPC_CONFIG), KPC_CLASSES, 6, 2, 0),
+        kd_buf(1014, 0, 0x101, perf(PERF_KPC, PERF_KPC_DATA), 100, 200, 300, 400),
+        kd_buf(1015, 0, 0x101, perf(PERF_KPC, PERF_KPC_DATA), 500, 600),
+        # kperf_sample_internal: the samplers that were sampled
+        kd_buf(1016, 0, 0x101, perf(PERF_GENERIC, PERF_GEN_EVENT, DBG_FUNC_END), cpu_samplers),
+        kd_buf(1017, 1, 0x202, perf(PERF_GENERIC, PERF_GEN_EVENT, DBG_FUNC_END),
+               thread_samplers),
+    ]
+
+
+def truncated():
+    """A sample cut by the start of the trace, a complete one, and one cut by its end, among
+    records of other classes."""
+    samplers = SAMPLER_USTACK
+    user = CALLSTACK_VALID | CALLSTACK_64BIT
+    other_class = kd_buf(1500, 0, 0x303, debugid(1, PERF_CALLSTACK, PERF_CS_UDATA), 0x9999)
+    return [
+        kd_buf(1000, 0, 0x303, perf(PERF_CALLSTACK, PERF_CS_UDATA), 0x1000, 0x2000),
+        kd_buf(1001, 0, 0x303, perf(PERF_GENERIC, PERF_GEN_EVENT, DBG_FUNC_END), samplers),
+        other_class,
+        kd_buf(2000, 0, 0x303, perf(PERF_GENERIC, PERF_GEN_EVENT, DBG_FUNC_START), samplers, 1),
+        other_class,
+        kd_buf(2001, 0, 0x303, perf(PERF_CALLSTACK, PERF_CS_UHDR), user, 1),
+        kd_buf(2002, 0, 0x303, perf(PERF_CALLSTACK, PERF_CS_UDATA), 0x4000),
+        kd_buf(2003, 0, 0x303, perf(PERF_GENERIC, PERF_GEN_EVENT, DBG_FUNC_END), samplers),
+        kd_buf(3000, 0, 0x303, perf(PERF_GENERIC, PERF_GEN_EVENT, DBG_FUNC_START), samplers, 1),
+        kd_buf(3001, 0, 0x303, perf(PERF_CALLSTACK, PERF_CS_UHDR), user, 1),
+    ]
+
+
+if __name__ == "__main__":
+    out = Path(__file__).parent
+    (out / "interleaved.kdbuf").write_bytes(b"".join(interleaved()))
+    (out / "truncated.kdbuf").write_bytes(b"".join(truncated()))
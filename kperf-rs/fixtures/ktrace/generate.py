@@ -0,0 +1,65 @@
+#!/usr/bin/env python3
+# Writes the trace file of the ktrace tests, run from kperf-rs, after the kdebug fixtures, with:
+#
+# python3 fixtures/ktrace/generate.py
+#
+# The chunks are laid out as kdbg_write_v3_header and the chunk writers of xnu's
+# bsd/kern/kdebug.c write them, with the structures of bsd/sys/kdebug_private.h. Nothing here is
+# shared with kperf-rs.
+import struct
+from pathlib import Path
+
+RAW_VERSION1 = 0x55AA0101
+RAW_VERSION3 = 0x00001000
+V3_CPU_MAP = 0x00001C00
+V3_THREAD_MAP = 0x00001D00
+V3_RAW_EVENTS = 0x00001E00
+V3_NULL_CHUNK = 0x00002000
+V3_HEADER_VERSION = 3
+KDBG_CPUMAP_IS_IOP = 0x1
+KD_BUF_SIZE = 64
+
+
+def chunk(tag, sub_tag, data):
+    """kd_chunk_header_v3: the tag, sub tag and length of the data following it."""
+    return struct.pack("<IIQ", tag, sub_tag, len(data)) + data
+
+
+def header():
+    """kd_header_v3 after its chunk header: the timebase, the time the trace started, the wall
+    clock time, the time zone and the flags."""
+    numer, denom = 125, 3
+    timestamp, secs, usecs = 900, 1_700_000_000, 250_000
+    minuteswest, dst, flags = 0, 0, 1
+    return struct.pack("<IIQQIIII", numer, denom, timestamp, secs, usecs, minuteswest, dst, flags)
+
+
+def cpu_map():
+    """kd_cpumap_header, then a kd_cpumap of an id, flags and an 8 byte name per CPU."""
+    cpus = [(0, 0, b"AP0"), (1, 0, b"AP1"), (2, KDBG_CPUMAP_IS_IOP, b"SMC")]
+    entries = b"".join(struct.pack("<II8s", *cpu) for cpu in cpus)
+    return struct.pack("<II", RAW_VERSION1, len(cpus)) + entries
+
+
+def thread_map():
+    """kd_threadmap of each thread: its id, the pid and a 20 byte NUL terminated command."""
+    threads = [(0x101, 42, b"bench"), (0x202, 7, b"a_very_long_command")]
+    return b"".join(struct.pack("<Qi20s", *thread) for thread in threads)
+
+
+if __name__ == "__main__":
+    fixtures = Path(__file__).parent.parent
+    events = (fixtures / "kdebug" / "interleaved.kdbuf").read_bytes()
+    split = 10 * KD_BUF_SIZE
+    trace = b"".join([
+        chunk(RAW_VERSION3, V3_HEADER_VERSION, header()),
+        # Padding up to the next chunk
+        chunk(V3_NULL_CHUNK, 0, bytes(24)),
+        chunk(V3_CPU_MAP, 0, cpu_map()),
+        chunk(V3_THREAD_MAP, 0, thread_map()),
+        chunk(V3_RAW_EVENTS, 0, events[:split]),
+        # A chunk the reader keeps as it is, like the stackshot ktrace appends
+        chunk(0x8002, 0, b"stackshot"),
+        chunk(V3_RAW_EVENTS, 0, events[split:]),
+    ])
+    (fixtures / "ktrace" / "interleaved.ktrace").write_bytes(trace)
@@ -0,0 +1,51 @@
+// Binaries of the symbolication tests, built from kperf-rs with:
+//
+// rustc +nightly --crate-type bin --crate-name fixture -g -C opt-level=0 -C panic=abort \
+//     --emit obj --remap-path-prefix=$PWD=/src --target x86_64-unknown-linux-gnu \
+//     fixtures/symbols/fixture.rs -o fixtures/symbols/fixture.elf.o
+// ld -static -e _start -o fixtures/symbols/fixture.elf fixtures/symbols/fixture.elf.o
+// objcopy --strip-debug fixtures/symbols/fixture.elf fixtures/symbols/fixture.stripped.elf
+//
+// rustc +nightly --crate-type bin --crate-name fixture -g -C opt-level=0 -C panic=abort \
+//     --emit obj --remap-path-prefix=$PWD=/src --target x86_64-apple-darwin \
+//     fixtures/symbols/fixture.rs -o fixtures/symbols/fixture.macho.o
+// rust-lld -flavor darwin -arch x86_64 -platform_version macos 11.0 11.0 -e __start \
+//     -oso_prefix $PWD/ -o fixtures/symbols/fixture.macho fixtures/symbols/fixture.macho.o
+//
+// The Mach-O executable has no DWARF, its debug map points to fixture.macho.o, relatively to
+// kperf-rs. Without core, no other code ends up in the binaries.
+#![feature(no_core, lang_items)]
+#![allow(internal_features)]
+#![no_core]
+#![no_main]
+
+#[lang = "pointee_sized"]
+pub trait PointeeSized {}
+#[lang = "meta_sized"]
+pub trait MetaSized: PointeeSized {}
+#[lang = "sized"]
+pub trait Sized: MetaSized {}
+#[lang = "copy"]
+pub trait Copy {}
+impl Copy for u64 {}
+
+#[inline(never)]
+#[no_mangle]
+pub extern "C" fn leaf(x: u64) -> u64 {
+    x
+}
+
+#[inline(always)]
+fn helper(x: u64) -> u64 {
+    leaf(x)
+}
+
+#[inline(never)]
+pub fn compute(x: u64) -> u64 {
+    helper(x)
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> u64 {
+    compute(1)
+}
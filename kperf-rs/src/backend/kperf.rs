@@ -0,0 +1,364 @@
+use crate::backend::CounterBackend;
+#[cfg(feature = "dynamic")]
+use crate::error::KperfError;
+#[cfg(not(feature = "dynamic"))]
+pub(crate) use kperf_sys::functions;
+use kperf_sys::structs::{kpc_config_t, kpep_config, kpep_db, kpep_event};
+use libc::{c_char, c_int, c_uint, c_ulonglong, size_t};
+
+/// Same functions as `kperf_sys::functions`, called through the table of `kperf_sys::dynamic`.
+/// When the frameworks couldn't be loaded, they set `errno` to `ENOSYS` and return -1, or
+/// `KPEP_CONFIG_ERROR_CUR_SYSTEM_UNKNOWN` for kpep. [`KperfBackend::check_available`] reports why.
+#[cfg(feature = "dynamic")]
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+pub(crate) mod functions {
+    use kperf_sys::constants::kpep_config_error_code::KPEP_CONFIG_ERROR_CUR_SYSTEM_UNKNOWN;
+    use kperf_sys::structs::{kpc_config_t, kpep_config, kpep_db, kpep_event};
+    use libc::{c_char, c_int, c_uint, c_ulonglong, size_t, ENOSYS};
+
+    const KPEP_UNAVAILABLE: c_int = KPEP_CONFIG_ERROR_CUR_SYSTEM_UNKNOWN as c_int;
+
+    fn unavailable() -> c_int {
+        crate::backend::fail_with_errno(ENOSYS)
+    }
+
+    macro_rules! dynamic_functions {
+        ($(fn $name:ident($($arg:ident: $ty:ty),* $(,)?) $(-> $ret:ty = $unavailable:expr)?;)*) => {
+            $(
+                pub unsafe fn $name($($arg: $ty),*) $(-> $ret)? {
+                    match kperf_sys::dynamic::load() {
+                        Ok(functions) => (functions.$name)($($arg),*),
+                        Err(_) => { $($unavailable)? }
+                    }
+                }
+            )*
+        };
+    }
+
+    // Plain sysctls, available whether the frameworks load or not
+    #[cfg(target_os = "macos")]
+    pub use kperf_sys::functions::{kperf_lightweight_pet_get, kperf_lightweight_pet_set};
+
+    #[cfg(not(target_os = "macos"))]
+    pub unsafe fn kperf_lightweight_pet_get(_enabled: *mut c_uint) -> c_int {
+        unavailable()
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    pub unsafe fn kperf_lightweight_pet_set(_enabled: c_uint) -> c_int {
+        unavailable()
+    }
+
+    dynamic_functions! {
+        fn kpc_get_counting() -> c_uint = 0;
+        fn kpc_set_counting(classes: c_uint) -> c_int = unavailable();
+        fn kpc_get_thread_counting() -> c_uint = 0;
+        fn kpc_set_thread_counting(classes: c_uint) -> c_int = unavailable();
+        fn kpc_set_config(classes: c_uint, config: *mut kpc_config_t) -> c_int = unavailable();
+        fn kpc_get_counter_count(classes: c_uint) -> c_uint = 0;
+        fn kpc_get_cpu_counters(
+            all_cpus: bool,
+            classes: c_uint,
+            curcpu: *mut c_int,
+            buf: *mut c_ulonglong,
+        ) -> c_int = unavailable();
+        fn kpc_get_thread_counters(
+            tid: c_uint,
+            buf_count: c_uint,
+            buf: *mut c_ulonglong,
+        ) -> c_int = unavailable();
+        fn kpc_force_all_ctrs_set(val: c_int) -> c_int = unavailable();
+        fn kpc_force_all_ctrs_get(val_out: *mut c_int) -> c_int = unavailable();
+        fn kperf_reset() -> c_int = unavailable();
+        fn kperf_action_count_set(count: c_uint) -> c_int = unavailable();
+        fn kperf_action_samplers_set(actionid: c_uint, sample: c_uint) -> c_int = unavailable();
+        fn kperf_timer_count_set(count: c_uint) -> c_int = unavailable();
+        fn kperf_timer_period_set(actionid: c_uint, tick: c_ulonglong) -> c_int = unavailable();
+        fn kperf_timer_action_set(actionid: c_uint, timerid: c_uint) -> c_int = unavailable();
+        fn kperf_timer_pet_set(timerid: c_uint) -> c_int = unavailable();
+        fn kperf_timer_pet_get(timerid: *mut c_uint) -> c_int = unavailable();
+        fn kperf_sample_set(enabled: c_uint) -> c_int = unavailable();
+        fn kperf_sample_get(enabled: *mut c_uint) -> c_int = unavailable();
+        fn kperf_ns_to_ticks(ns: c_ulonglong) -> c_ulonglong = 0;
+        fn kperf_ticks_to_ns(ticks: c_ulonglong) -> c_ulonglong = 0;
+        fn kperf_tick_frequency() -> c_ulonglong = 0;
+        fn kpep_config_create(
+            db: *mut kpep_db,
+            cfg_ptr: *mut *mut kpep_config,
+        ) -> c_int = KPEP_UNAVAILABLE;
+        fn kpep_config_free(cfg: *mut kpep_config);
+        fn kpep_config_add_event(
+            cfg: *mut kpep_config,
+            ev_ptr: *mut *mut kpep_event,
+            flag: c_uint,
+            err: *mut c_uint,
+        ) -> c_int = KPEP_UNAVAILABLE;
+        fn kpep_config_force_counters(cfg: *mut kpep_config) -> c_int = KPEP_UNAVAILABLE;
+        fn kpep_config_kpc(
+            cfg: *mut kpep_config,
+            buf: *mut kpc_config_t,
+            buf_size: size_t,
+        ) -> c_int = KPEP_UNAVAILABLE;
+        fn kpep_config_kpc_count(
+            cfg: *mut kpep_config,
+            count_ptr: *mut size_t,
+        ) -> c_int = KPEP_UNAVAILABLE;
+        fn kpep_config_kpc_classes(
+            cfg: *mut kpep_config,
+            classes_ptr: *mut c_uint,
+        ) -> c_int = KPEP_UNAVAILABLE;
+        fn kpep_config_kpc_map(
+            cfg: *mut kpep_config,
+            buf: *mut size_t,
+            buf_size: size_t,
+        ) -> c_int = KPEP_UNAVAILABLE;
+        fn kpep_db_create(
+            name: *const c_char,
+            db_ptr: *mut *mut kpep_db,
+        ) -> c_int = KPEP_UNAVAILABLE;
+        fn kpep_db_free(db: *mut kpep_db);
+        fn kpep_db_events_count(db: *mut kpep_db, count: *mut size_t) -> c_int = KPEP_UNAVAILABLE;
+        fn kpep_db_events(
+            db: *mut kpep_db,
+            buf: *mut *mut kpep_event,
+            buf_size: size_t,
+        ) -> c_int = KPEP_UNAVAILABLE;
+        fn kpep_db_event(
+            db: *mut kpep_db,
+            name: *const c_char,
+            ev_ptr: *mut *mut kpep_event,
+        ) -> c_int = KPEP_UNAVAILABLE;
+    }
+}
+
+/// Backend calling into the kperf and kperfdata private frameworks, either linked or loaded at
+/// runtime with the `dynamic` feature.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct KperfBackend;
+
+impl CounterBackend for KperfBackend {
+    #[cfg(feature = "dynamic")]
+    fn check_available(&self) -> Result<(), KperfError> {
+        kperf_sys::dynamic::load()?;
+        Ok(())
+    }
+
+    unsafe fn kpc_get_counting(&self) -> c_uint {
+        functions::kpc_get_counting()
+    }
+
+    unsafe fn kpc_set_counting(&self, classes: c_uint) -> c_int {
+        functions::kpc_set_counting(classes)
+    }
+
+    unsafe fn kpc_get_thread_counting(&self) -> c_uint {
+        functions::kpc_get_thread_counting()
+    }
+
+    unsafe fn kpc_set_thread_counting(&self, classes: c_uint) -> c_int {
+        functions::kpc_set_thread_counting(classes)
+    }
+
+    unsafe fn kpc_set_config(&self, classes: c_uint, config: *mut kpc_config_t) -> c_int {
+        functions::kpc_set_config(classes, config)
+    }
+
+    unsafe fn kpc_get_counter_count(&self, classes: c_uint) -> c_uint {
+        functions::kpc_get_counter_count(classes)
+    }
+
+    unsafe fn kpc_get_cpu_counters(
+        &self,
+        all_cpus: bool,
+        classes: c_uint,
+        curcpu: *mut c_int,
+        buf: *mut c_ulonglong,
+    ) -> c_int {
+        functions::kpc_get_cpu_counters(all_cpus, classes, curcpu, buf)
+    }
+
+    unsafe fn kpc_get_thread_counters(
+        &self,
+        tid: c_uint,
+        buf_count: c_uint,
+        buf: *mut c_ulonglong,
+    ) -> c_int {
+        functions::kpc_get_thread_counters(tid, buf_count, buf)
+    }
+
+    unsafe fn kpc_force_all_ctrs_set(&self, val: c_int) -> c_int {
+        functions::kpc_force_all_ctrs_set(val)
+    }
+
+    unsafe fn kpc_force_all_ctrs_get(&self, val_out: *mut c_int) -> c_int {
+        functions::kpc_force_all_ctrs_get(val_out)
+    }
+
+    unsafe fn kperf_reset(&self) -> c_int {
+        functions::kperf_reset()
+    }
+
+    unsafe fn kperf_action_count_set(&self, count: c_uint) -> c_int {
+        functions::kperf_action_count_set(count)
+    }
+
+    unsafe fn kperf_action_samplers_set(&self, actionid: c_uint, sample: c_uint) -> c_int {
+        functions::kperf_action_samplers_set(actionid, sample)
+    }
+
+    unsafe fn kperf_timer_count_set(&self, count: c_uint) -> c_int {
+        functions::kperf_timer_count_set(count)
+    }
+
+    unsafe fn kperf_timer_period_set(&self, timerid: c_uint, tick: c_ulonglong) -> c_int {
+        functions::kperf_timer_period_set(timerid, tick)
+    }
+
+    unsafe fn kperf_timer_action_set(&self, timerid: c_uint, actionid: c_uint) -> c_int {
+        functions::kperf_timer_action_set(timerid, actionid)
+    }
+
+    unsafe fn kperf_timer_pet_set(&self, timerid: c_uint) -> c_int {
+        functions::kperf_timer_pet_set(timerid)
+    }
+
+    unsafe fn kperf_timer_pet_get(&self, timerid: *mut c_uint) -> c_int {
+        functions::kperf_timer_pet_get(timerid)
+    }
+
+    unsafe fn kperf_lightweight_pet_set(&self, enabled: c_uint) -> c_int {
+        functions::kperf_lightweight_pet_set(enabled)
+    }
+
+    unsafe fn kperf_lightweight_pet_get(&self, enabled: *mut c_uint) -> c_int {
+        functions::kperf_lightweight_pet_get(enabled)
+    }
+
+    unsafe fn kperf_sample_set(&self, enabled: c_uint) -> c_int {
+        functions::kperf_sample_set(enabled)
+    }
+
+    unsafe fn kperf_sample_get(&self, enabled: *mut c_uint) -> c_int {
+        functions::kperf_sample_get(enabled)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn cpu_count(&self) -> usize {
+        let mut ncpu: c_int = 0;
+        let mut size = std::mem::size_of::<c_int>();
+        let res = unsafe {
+            libc::sysctlbyname(
+                c"hw.ncpu".as_ptr(),
+                &mut ncpu as *mut c_int as *mut libc::c_void,
+                &mut size,
+                std::ptr::null_mut(),
+                0,
+            )
+        };
+        if res != 0 {
+            return 1;
+        }
+        ncpu.max(1) as usize
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    fn cpu_count(&self) -> usize {
+        // The frameworks never load off macOS, any sensible value does
+        std::thread::available_parallelism().map_or(1, |n| n.get())
+    }
+
+    unsafe fn kpep_config_create(&self, db: *mut kpep_db, cfg_ptr: *mut *mut kpep_config) -> c_int {
+        functions::kpep_config_create(db, cfg_ptr)
+    }
+
+    unsafe fn kpep_config_free(&self, cfg: *mut kpep_config) {
+        functions::kpep_config_free(cfg)
+    }
+
+    unsafe fn kpep_config_add_event(
+        &self,
+        cfg: *mut kpep_config,
+        ev_ptr: *mut *mut kpep_event,
+        flag: c_uint,
+        err: *mut c_uint,
+    ) -> c_int {
+        functions::kpep_config_add_event(cfg, ev_ptr, flag, err)
+    }
+
+    unsafe fn kpep_config_force_counters(&self, cfg: *mut kpep_config) -> c_int {
+        functions::kpep_config_force_counters(cfg)
+    }
+
+    unsafe fn kpep_config_kpc(
+        &self,
+        cfg: *mut kpep_config,
+        buf: *mut kpc_config_t,
+        buf_size: size_t,
+    ) -> c_int {
+        functions::kpep_config_kpc(cfg, buf, buf_size)
+    }
+
+    unsafe fn kpep_config_kpc_count(&self, cfg: *mut kpep_config, count_ptr: *mut size_t) -> c_int {
+        functions::kpep_config_kpc_count(cfg, count_ptr)
+    }
+
+    unsafe fn kpep_config_kpc_classes(
+        &self,
+        cfg: *mut kpep_config,
+        classes_ptr: *mut c_uint,
+    ) -> c_int {
+        functions::kpep_config_kpc_classes(cfg, classes_ptr)
+    }
+
+    unsafe fn kpep_config_kpc_map(
+        &self,
+        cfg: *mut kpep_config,
+        buf: *mut size_t,
+        buf_size: size_t,
+    ) -> c_int {
+        functions::kpep_config_kpc_map(cfg, buf, buf_size)
+    }
+
+    unsafe fn kpep_db_create(&self, name: *const c_char, db_ptr: *mut *mut kpep_db) -> c_int {
+        functions::kpep_db_create(name, db_ptr)
+    }
+
+    unsafe fn kpep_db_free(&self, db: *mut kpep_db) {
+        functions::kpep_db_free(db)
+    }
+
+    unsafe fn kpep_db_events_count(&self, db: *mut kpep_db, count: *mut size_t) -> c_int {
+        functions::kpep_db_events_count(db, count)
+    }
+
+    unsafe fn kpep_db_events(
+        &self,
+        db: *mut kpep_db,
+        buf: *mut *mut kpep_event,
+        buf_size: size_t,
+    ) -> c_int {
+        functions::kpep_db_events(db, buf, buf_size)
+    }
+
+    unsafe fn kpep_db_event(
+        &self,
+        db: *mut kpep_db,
+        name: *const c_char,
+        ev_ptr: *mut *mut kpep_event,
+    ) -> c_int {
+        functions::kpep_db_event(db, name, ev_ptr)
+    }
+}
+
+#[cfg(all(test, feature = "dynamic", not(target_os = "macos")))]
+mod tests {
+    use super::*;
+    use crate::check_kpc_permission_with;
+    use crate::kperf::KProbesDatabase;
+
+    #[test]
+    fn test_unavailable_frameworks() {
+        assert!(KProbesDatabase::load_database_with(KperfBackend).is_err());
+        assert!(check_kpc_permission_with(&KperfBackend).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+#[cfg(any(target_os = "macos", feature = "dynamic"))]
+pub(crate) mod kperf;
+#[cfg(target_os = "linux")]
+mod perf_event;
+mod simulated;
+
+#[cfg(any(target_os = "macos", feature = "dynamic"))]
+pub use self::kperf::KperfBackend;
+#[cfg(target_os = "linux")]
+pub use self::perf_event::PerfEventBackend;
+pub use self::simulated::{SimulatedBackend, SimulatedDatabase, SimulatedEvent};
+
+/// Backend used by the constructors that don't take one explicitly.
+#[cfg(target_os = "macos")]
+pub type DefaultBackend = KperfBackend;
+/// Backend used by the constructors that don't take one explicitly.
+#[cfg(target_os = "linux")]
+pub type DefaultBackend = PerfEventBackend;
+
+use crate::error::KperfError;
+use kperf_sys::structs::{kpc_config_t, kpep_config, kpep_db, kpep_event};
+use libc::{c_char, c_int, c_uint, c_ulonglong, size_t};
+
+/// Every kpc/kpep operation used by the safe layer.
+///
+/// Each method has the same signature and return code convention as the `kperf_sys::functions`
+/// function of the same name, so the real frameworks can be swapped for another implementation
+/// (for example [`SimulatedBackend`] in unit tests on Linux).
+///
+/// # Safety
+/// Callers must uphold the same pointer contracts as the matching `kperf_sys` function.
+/// Implementations may assume the pointers they receive were produced by themselves.
+#[allow(clippy::missing_safety_doc)]
+pub trait CounterBackend: Clone {
+    /// Whether the functions of this backend can be called at all, checked before loading the
+    /// event database so a missing library is reported rather than a failing call.
+    fn check_available(&self) -> Result<(), KperfError> {
+        Ok(())
+    }
+
+    /// Identifies the counting state the backend changes, the same for every backend sharing
+    /// it. The kernel's is machine-wide, so every instance of the frameworks shares it.
+    fn state_id(&self) -> usize {
+        0
+    }
+
+    unsafe fn kpc_get_counting(&self) -> c_uint;
+    unsafe fn kpc_set_counting(&self, classes: c_uint) -> c_int;
+    unsafe fn kpc_get_thread_counting(&self) -> c_uint;
+    unsafe fn kpc_set_thread_counting(&self, classes: c_uint) -> c_int;
+    unsafe fn kpc_set_config(&self, classes: c_uint, config: *mut kpc_config_t) -> c_int;
+    unsafe fn kpc_get_counter_count(&self, classes: c_uint) -> c_uint;
+    unsafe fn kpc_get_cpu_counters(
+        &self,
+        all_cpus: bool,
+        classes: c_uint,
+        curcpu: *mut c_int,
+        buf: *mut c_ulonglong,
+    ) -> c_int;
+    unsafe fn kpc_get_thread_counters(
+        &self,
+        tid: c_uint,
+        buf_count: c_uint,
+        buf: *mut c_ulonglong,
+    ) -> c_int;
+    unsafe fn kpc_force_all_ctrs_set(&self, val: c_int) -> c_int;
+    unsafe fn kpc_force_all_ctrs_get(&self, val_out: *mut c_int) -> c_int;
+    unsafe fn kperf_reset(&self) -> c_int;
+    unsafe fn kperf_action_count_set(&self, count: c_uint) -> c_int;
+    unsafe fn kperf_action_samplers_set(&self, actionid: c_uint, sample: c_uint) -> c_int;
+    unsafe fn kperf_timer_count_set(&self, count: c_uint) -> c_int;
+    /// Set the period of timer `timerid`, which kperf-sys names `actionid`.
+    unsafe fn kperf_timer_period_set(&self, timerid: c_uint, tick: c_ulonglong) -> c_int;
+    /// Make timer `timerid` fire action `actionid`: the timer comes first, whatever kperf-sys
+    /// names the arguments.
+    unsafe fn kperf_timer_action_set(&self, timerid: c_uint, actionid: c_uint) -> c_int;
+    unsafe fn kperf_timer_pet_set(&self, timerid: c_uint) -> c_int;
+    unsafe fn kperf_timer_pet_get(&self, timerid: *mut c_uint) -> c_int;
+    /// The `kperf.lightweight_pet` sysctl, kperf.framework has no function for it.
+    unsafe fn kperf_lightweight_pet_set(&self, enabled: c_uint) -> c_int;
+    unsafe fn kperf_lightweight_pet_get(&self, enabled: *mut c_uint) -> c_int;
+    unsafe fn kperf_sample_set(&self, enabled: c_uint) -> c_int;
+    unsafe fn kperf_sample_get(&self, enabled: *mut c_uint) -> c_int;
+
+    /// Number of CPUs `kpc_get_cpu_counters` reports, `hw.ncpu` on macOS.
+    fn cpu_count(&self) -> usize;
+
+    unsafe fn kpep_config_create(&self, db: *mut kpep_db, cfg_ptr: *mut *mut kpep_config) -> c_int;
+    unsafe fn kpep_config_free(&self, cfg: *mut kpep_config);
+    unsafe fn kpep_config_add_event(
+        &self,
+        cfg: *mut kpep_config,
+        ev_ptr: *mut *mut kpep_event,
+        flag: c_uint,
+        err: *mut c_uint,
+    ) -> c_int;
+    unsafe fn kpep_config_force_counters(&self, cfg: *mut kpep_config) -> c_int;
+    unsafe fn kpep_config_kpc(
+        &self,
+        cfg: *mut kpep_config,
+        buf: *mut kpc_config_t,
+        buf_size: size_t,
+    ) -> c_int;
+    unsafe fn kpep_config_kpc_count(&self, cfg: *mut kpep_config, count_ptr: *mut size_t) -> c_int;
+    unsafe fn kpep_config_kpc_classes(
+        &self,
+        cfg: *mut kpep_config,
+        classes_ptr: *mut c_uint,
+    ) -> c_int;
+    unsafe fn kpep_config_kpc_map(
+        &self,
+        cfg: *mut kpep_config,
+        buf: *mut size_t,
+        buf_size: size_t,
+    ) -> c_int;
+
+    unsafe fn kpep_db_create(&self, name: *const c_char, db_ptr: *mut *mut kpep_db) -> c_int;
+    unsafe fn kpep_db_free(&self, db: *mut kpep_db);
+    unsafe fn kpep_db_events_count(&self, db: *mut kpep_db, count: *mut size_t) -> c_int;
+    unsafe fn kpep_db_events(
+        &self,
+        db: *mut kpep_db,
+        buf: *mut *mut kpep_event,
+        buf_size: size_t,
+    ) -> c_int;
+    unsafe fn kpep_db_event(
+        &self,
+        db: *mut kpep_db,
+        name: *const c_char,
+        ev_ptr: *mut *mut kpep_event,
+    ) -> c_int;
+}
+
+/// Fail the way the kpc and kperf functions do: set `errno` and return -1.
+pub(crate) fn fail_with_errno(errno: c_int) -> c_int {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    unsafe {
+        *libc::__errno_location() = errno;
+    }
+    #[cfg(any(target_vendor = "apple", target_os = "freebsd"))]
+    unsafe {
+        *libc::__error() = errno;
+    }
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_vendor = "apple",
+        target_os = "freebsd"
+    )))]
+    let _ = errno;
+    -1
+}
@@ -0,0 +1,867 @@
+use crate::backend::{fail_with_errno, CounterBackend};
+use kperf_sys::constants::kpep_config_error_code::{
+    KPEP_CONFIG_ERROR_BUFFER_TOO_SMALL, KPEP_CONFIG_ERROR_CONFLICTING_EVENTS,
+    KPEP_CONFIG_ERROR_EVENT_NOT_FOUND, KPEP_CONFIG_ERROR_INVALID_ARGUMENT,
+};
+use kperf_sys::constants::KPC_CLASS_CONFIGURABLE_MASK;
+use kperf_sys::structs::{kpc_config_t, kpep_config, kpep_db, kpep_event};
+use libc::{c_char, c_int, c_uint, c_ulong, c_ulonglong, size_t, EIO, ENOSYS};
+use std::cell::RefCell;
+use std::ffi::CStr;
+use std::io;
+use std::mem::{size_of, zeroed};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::ptr::null;
+use std::rc::Rc;
+
+const PERF_TYPE_HARDWARE: u32 = 0;
+const PERF_TYPE_SOFTWARE: u32 = 1;
+
+const PERF_COUNT_HW_CPU_CYCLES: u64 = 0;
+const PERF_COUNT_HW_INSTRUCTIONS: u64 = 1;
+const PERF_COUNT_HW_BRANCH_INSTRUCTIONS: u64 = 4;
+const PERF_COUNT_HW_BRANCH_MISSES: u64 = 5;
+const PERF_COUNT_SW_TASK_CLOCK: u64 = 1;
+const PERF_COUNT_SW_PAGE_FAULTS: u64 = 2;
+const PERF_COUNT_SW_CONTEXT_SWITCHES: u64 = 3;
+
+const PERF_ATTR_FLAG_DISABLED: u64 = 1 << 0;
+const PERF_ATTR_FLAG_EXCLUDE_KERNEL: u64 = 1 << 5;
+const PERF_ATTR_FLAG_EXCLUDE_HV: u64 = 1 << 6;
+
+const PERF_EVENT_IOC_ENABLE: c_ulong = 0x2400;
+const PERF_EVENT_IOC_DISABLE: c_ulong = 0x2401;
+const PERF_EVENT_IOC_RESET: c_ulong = 0x2403;
+
+/// Marks a register value written by `kpep_config_kpc`, so unused slots are never opened.
+const REGISTER_VALID: kpc_config_t = 1 << 63;
+
+/// `perf_event_attr` truncated to `PERF_ATTR_SIZE_VER0`, which every kernel accepts.
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    type_: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+}
+
+struct PerfEventDesc {
+    name: &'static CStr,
+    alias: &'static CStr,
+    description: &'static CStr,
+    type_: u32,
+    config: u64,
+}
+
+const PERF_EVENTS: [PerfEventDesc; 7] = [
+    PerfEventDesc {
+        name: c"cpu-cycles",
+        alias: c"Cycles",
+        description: c"Total cycles.",
+        type_: PERF_TYPE_HARDWARE,
+        config: PERF_COUNT_HW_CPU_CYCLES,
+    },
+    PerfEventDesc {
+        name: c"instructions",
+        alias: c"Instructions",
+        description: c"Retired instructions.",
+        type_: PERF_TYPE_HARDWARE,
+        config: PERF_COUNT_HW_INSTRUCTIONS,
+    },
+    PerfEventDesc {
+        name: c"branch-instructions",
+        alias: c"Branches",
+        description: c"Retired branch instructions.",
+        type_: PERF_TYPE_HARDWARE,
+        config: PERF_COUNT_HW_BRANCH_INSTRUCTIONS,
+    },
+    PerfEventDesc {
+        name: c"branch-misses",
+        alias: c"BranchMisses",
+        description: c"Mispredicted branch instructions.",
+        type_: PERF_TYPE_HARDWARE,
+        config: PERF_COUNT_HW_BRANCH_MISSES,
+    },
+    PerfEventDesc {
+        name: c"task-clock",
+        alias: c"TaskClock",
+        description: c"Nanoseconds the task was running on a CPU.",
+        type_: PERF_TYPE_SOFTWARE,
+        config: PERF_COUNT_SW_TASK_CLOCK,
+    },
+    PerfEventDesc {
+        name: c"context-switches",
+        alias: c"ContextSwitches",
+        description: c"Context switches of the task.",
+        type_: PERF_TYPE_SOFTWARE,
+        config: PERF_COUNT_SW_CONTEXT_SWITCHES,
+    },
+    PerfEventDesc {
+        name: c"page-faults",
+        alias: c"PageFaults",
+        description: c"Page faults taken by the task.",
+        type_: PERF_TYPE_SOFTWARE,
+        config: PERF_COUNT_SW_PAGE_FAULTS,
+    },
+];
+
+/// Configurable counters of the backend, one per event of a config, so a config can't hold more
+/// events than that.
+const COUNTER_COUNT: usize = PERF_EVENTS.len();
+
+#[cfg(target_arch = "aarch64")]
+const PERF_EVENT_ARCH: c_uint = kperf_sys::constants::KPEP_ARCH_ARM64;
+#[cfg(not(target_arch = "aarch64"))]
+const PERF_EVENT_ARCH: c_uint = kperf_sys::constants::KPEP_ARCH_X86_64;
+
+/// Backend emulating kpc/kpep on top of Linux `perf_event_open`.
+///
+/// Every event gets its own configurable counter, opened for the calling thread.
+/// Per-CPU counters are only opened for every CPU the first time they are read, as the kernel
+/// allows them with `perf_event_paranoid` <= 0 or `CAP_PERFMON`, and run while kpc counting is on.
+/// Thread counters never hold PMU slots or file descriptors on the other CPUs.
+/// Hardware events the kernel refuses to open (as in most VMs) are left out of the database,
+/// while the software events (`task-clock`, `context-switches`, `page-faults`) stay available,
+/// and counters allowing it count cycles as `task-clock` instead, see
+/// [`Event::software_substitute`].
+///
+/// [`Event::software_substitute`]: crate::event::Event::software_substitute
+#[derive(Clone, Default)]
+pub struct PerfEventBackend {
+    state: Rc<RefCell<PerfEventState>>,
+}
+
+#[derive(Default)]
+struct PerfEventState {
+    databases: Vec<PerfEventDatabase>,
+    configs: Vec<PerfEventConfig>,
+    counters: Vec<OwnedFd>,
+    /// Index in `PERF_EVENTS` and kpep flag of every event of the config set last.
+    registers: Vec<(usize, c_uint)>,
+    /// Counters of every CPU, empty for offline CPUs, `None` until the per-CPU counters are read.
+    cpu_counters: Option<Vec<Vec<OwnedFd>>>,
+    counting: c_uint,
+    thread_counting: c_uint,
+}
+
+struct PerfEventDatabase {
+    db: Box<kpep_db>,
+    events: Vec<kpep_event>,
+}
+
+struct PerfEventConfig {
+    config: Box<kpep_config>,
+    /// Index in `PERF_EVENTS` and kpep flag of every added event, in counter order.
+    events: Vec<(usize, c_uint)>,
+}
+
+impl PerfEventState {
+    fn database(&self, db: *mut kpep_db) -> Option<&PerfEventDatabase> {
+        self.databases
+            .iter()
+            .find(|database| std::ptr::eq(&*database.db, db))
+    }
+
+    fn database_mut(&mut self, db: *mut kpep_db) -> Option<&mut PerfEventDatabase> {
+        self.databases
+            .iter_mut()
+            .find(|database| std::ptr::eq(&*database.db, db))
+    }
+
+    fn config(&mut self, cfg: *mut kpep_config) -> Option<&mut PerfEventConfig> {
+        self.configs
+            .iter_mut()
+            .find(|config| std::ptr::eq(&*config.config, cfg))
+    }
+
+    /// Open the counters of every CPU if they aren't yet, enabled if kpc counting is on.
+    fn open_cpu_counters(&mut self) -> c_int {
+        if self.cpu_counters.is_some() {
+            return 0;
+        }
+        let mut cpu_counters = Vec::new();
+        'cpus: for cpu in 0..cpu_count() {
+            let mut counters = Vec::new();
+            for &(index, flag) in &self.registers {
+                match perf_event_open_on(&PERF_EVENTS[index], flag, -1, cpu as c_int) {
+                    Ok(fd) => counters.push(fd),
+                    // Offline CPU
+                    Err(err) if err.raw_os_error() == Some(libc::ENODEV) => {
+                        cpu_counters.push(Vec::new());
+                        continue 'cpus;
+                    }
+                    Err(err) => return fail_with(err),
+                }
+            }
+            cpu_counters.push(counters);
+        }
+        self.cpu_counters = Some(cpu_counters);
+        if self.counting != 0 {
+            return self.ioctl_all_cpus(PERF_EVENT_IOC_ENABLE);
+        }
+        0
+    }
+
+    fn ioctl_all(&self, request: c_ulong) -> c_int {
+        ioctl_counters(&self.counters, request)
+    }
+
+    fn ioctl_all_cpus(&self, request: c_ulong) -> c_int {
+        for counters in self.cpu_counters.iter().flatten() {
+            let res = ioctl_counters(counters, request);
+            if res != 0 {
+                return res;
+            }
+        }
+        0
+    }
+}
+
+fn ioctl_counters(counters: &[OwnedFd], request: c_ulong) -> c_int {
+    for counter in counters {
+        if unsafe { libc::ioctl(counter.as_raw_fd(), request, 0) } != 0 {
+            return fail_with(io::Error::last_os_error());
+        }
+    }
+    0
+}
+
+fn read_counters(counters: &[OwnedFd], buf: &mut [c_ulonglong]) -> c_int {
+    buf.fill(0);
+    for (value, counter) in buf.iter_mut().zip(counters) {
+        let res = unsafe {
+            libc::read(
+                counter.as_raw_fd(),
+                value as *mut c_ulonglong as *mut libc::c_void,
+                size_of::<c_ulonglong>(),
+            )
+        };
+        if res < 0 {
+            return fail_with(io::Error::last_os_error());
+        }
+        if res != size_of::<c_ulonglong>() as isize {
+            return fail_with_errno(EIO);
+        }
+    }
+    0
+}
+
+fn cpu_count() -> usize {
+    let count = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) };
+    count.max(1) as usize
+}
+
+/// Fail with the errno of `err`, as the kpc functions fail.
+fn fail_with(err: io::Error) -> c_int {
+    fail_with_errno(err.raw_os_error().unwrap_or(EIO))
+}
+
+fn perf_event_open(desc: &PerfEventDesc, flag: c_uint) -> io::Result<OwnedFd> {
+    perf_event_open_on(desc, flag, 0, -1)
+}
+
+/// Open a disabled counter for `pid` (0 for the calling thread, -1 for any) on `cpu` (-1 for any).
+fn perf_event_open_on(
+    desc: &PerfEventDesc,
+    flag: c_uint,
+    pid: libc::pid_t,
+    cpu: c_int,
+) -> io::Result<OwnedFd> {
+    let mut attr = PerfEventAttr {
+        type_: desc.type_,
+        size: size_of::<PerfEventAttr>() as u32,
+        config: desc.config,
+        flags: PERF_ATTR_FLAG_DISABLED | PERF_ATTR_FLAG_EXCLUDE_HV,
+        ..Default::default()
+    };
+    if flag == 1 {
+        attr.flags |= PERF_ATTR_FLAG_EXCLUDE_KERNEL;
+    }
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_perf_event_open,
+            &attr as *const PerfEventAttr,
+            pid,
+            cpu,
+            -1 as c_int,
+            0 as c_ulong,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as c_int) })
+}
+
+fn perf_event_index(ev: *const kpep_event) -> Option<usize> {
+    let name = unsafe { CStr::from_ptr((*ev).name) };
+    PERF_EVENTS.iter().position(|desc| desc.name == name)
+}
+
+impl CounterBackend for PerfEventBackend {
+    fn state_id(&self) -> usize {
+        Rc::as_ptr(&self.state) as *const () as usize
+    }
+
+    unsafe fn kpc_get_counting(&self) -> c_uint {
+        self.state.borrow().counting
+    }
+
+    unsafe fn kpc_set_counting(&self, classes: c_uint) -> c_int {
+        let request = if classes != 0 {
+            PERF_EVENT_IOC_ENABLE
+        } else {
+            PERF_EVENT_IOC_DISABLE
+        };
+        let mut state = self.state.borrow_mut();
+        let res = state.ioctl_all_cpus(request);
+        if res == 0 {
+            state.counting = classes;
+        }
+        res
+    }
+
+    unsafe fn kpc_get_thread_counting(&self) -> c_uint {
+        self.state.borrow().thread_counting
+    }
+
+    unsafe fn kpc_set_thread_counting(&self, classes: c_uint) -> c_int {
+        let request = if classes != 0 {
+            PERF_EVENT_IOC_ENABLE
+        } else {
+            PERF_EVENT_IOC_DISABLE
+        };
+        let mut state = self.state.borrow_mut();
+        let res = state.ioctl_all(request);
+        if res == 0 {
+            state.thread_counting = classes;
+        }
+        res
+    }
+
+    unsafe fn kpc_set_config(&self, classes: c_uint, config: *mut kpc_config_t) -> c_int {
+        // As kpc, read a register for every counter of the classes
+        let count = self.kpc_get_counter_count(classes) as usize;
+        let registers = std::slice::from_raw_parts(config, count);
+        let registers: Vec<_> = registers
+            .iter()
+            .take_while(|&&register| register & REGISTER_VALID != 0)
+            .map(|&register| {
+                (
+                    (register & 0xffff_ffff) as usize,
+                    ((register >> 32) & 1) as c_uint,
+                )
+            })
+            .collect();
+        let mut counters = Vec::new();
+        for &(index, flag) in &registers {
+            match perf_event_open(&PERF_EVENTS[index], flag) {
+                Ok(fd) => counters.push(fd),
+                Err(err) => return fail_with(err),
+            }
+        }
+
+        let mut state = self.state.borrow_mut();
+        state.counters = counters;
+        state.registers = registers;
+        // Reopened with the new events when next read
+        state.cpu_counters = None;
+        0
+    }
+
+    unsafe fn kpc_get_counter_count(&self, classes: c_uint) -> c_uint {
+        if classes & KPC_CLASS_CONFIGURABLE_MASK != 0 {
+            COUNTER_COUNT as c_uint
+        } else {
+            0
+        }
+    }
+
+    unsafe fn kpc_get_cpu_counters(
+        &self,
+        all_cpus: bool,
+        classes: c_uint,
+        curcpu: *mut c_int,
+        buf: *mut c_ulonglong,
+    ) -> c_int {
+        let current_cpu = libc::sched_getcpu();
+        if !curcpu.is_null() {
+            *curcpu = current_cpu;
+        }
+        let mut state = self.state.borrow_mut();
+        let res = state.open_cpu_counters();
+        if res != 0 {
+            return res;
+        }
+        let counter_count = self.kpc_get_counter_count(classes) as usize;
+        let cpus = if all_cpus {
+            0..cpu_count()
+        } else {
+            current_cpu.max(0) as usize..current_cpu.max(0) as usize + 1
+        };
+        for (block, cpu) in cpus.enumerate() {
+            let buf = std::slice::from_raw_parts_mut(buf.add(block * counter_count), counter_count);
+            let counters = state
+                .cpu_counters
+                .iter()
+                .flatten()
+                .nth(cpu)
+                .map_or(&[][..], Vec::as_slice);
+            let res = read_counters(counters, buf);
+            if res != 0 {
+                return res;
+            }
+        }
+        0
+    }
+
+    unsafe fn kpc_get_thread_counters(
+        &self,
+        _tid: c_uint,
+        buf_count: c_uint,
+        buf: *mut c_ulonglong,
+    ) -> c_int {
+        let buf = std::slice::from_raw_parts_mut(buf, buf_count as usize);
+        read_counters(&self.state.borrow().counters, buf)
+    }
+
+    unsafe fn kpc_force_all_ctrs_set(&self, _val: c_int) -> c_int {
+        // perf_event shares nothing with the Power Manager, there is nothing to acquire.
+        0
+    }
+
+    unsafe fn kpc_force_all_ctrs_get(&self, val_out: *mut c_int) -> c_int {
+        *val_out = 0;
+        0
+    }
+
+    unsafe fn kperf_reset(&self) -> c_int {
+        let state = self.state.borrow();
+        match state.ioctl_all(PERF_EVENT_IOC_RESET) {
+            0 => state.ioctl_all_cpus(PERF_EVENT_IOC_RESET),
+            res => res,
+        }
+    }
+
+    fn cpu_count(&self) -> usize {
+        cpu_count()
+    }
+
+    // Sampling is kperf only
+
+    unsafe fn kperf_action_count_set(&self, _count: c_uint) -> c_int {
+        fail_with_errno(ENOSYS)
+    }
+
+    unsafe fn kperf_action_samplers_set(&self, _actionid: c_uint, _sample: c_uint) -> c_int {
+        fail_with_errno(ENOSYS)
+    }
+
+    unsafe fn kperf_timer_count_set(&self, _count: c_uint) -> c_int {
+        fail_with_errno(ENOSYS)
+    }
+
+    unsafe fn kperf_timer_period_set(&self, _timerid: c_uint, _tick: c_ulonglong) -> c_int {
+        fail_with_errno(ENOSYS)
+    }
+
+    unsafe fn kperf_timer_action_set(&self, _timerid: c_uint, _actionid: c_uint) -> c_int {
+        fail_with_errno(ENOSYS)
+    }
+
+    unsafe fn kperf_timer_pet_set(&self, _timerid: c_uint) -> c_int {
+        fail_with_errno(ENOSYS)
+    }
+
+    unsafe fn kperf_timer_pet_get(&self, _timerid: *mut c_uint) -> c_int {
+        fail_with_errno(ENOSYS)
+    }
+
+    unsafe fn kperf_lightweight_pet_set(&self, _enabled: c_uint) -> c_int {
+        fail_with_errno(ENOSYS)
+    }
+
+    unsafe fn kperf_lightweight_pet_get(&self, _enabled: *mut c_uint) -> c_int {
+        fail_with_errno(ENOSYS)
+    }
+
+    unsafe fn kperf_sample_set(&self, _enabled: c_uint) -> c_int {
+        fail_with_errno(ENOSYS)
+    }
+
+    unsafe fn kperf_sample_get(&self, _enabled: *mut c_uint) -> c_int {
+        fail_with_errno(ENOSYS)
+    }
+
+    unsafe fn kpep_config_create(&self, db: *mut kpep_db, cfg_ptr: *mut *mut kpep_config) -> c_int {
+        let mut config: Box<kpep_config> = Box::new(zeroed());
+        config.db = db;
+        config.classes = KPC_CLASS_CONFIGURABLE_MASK;
+        *cfg_ptr = &mut *config;
+        self.state.borrow_mut().configs.push(PerfEventConfig {
+            config,
+            events: Vec::new(),
+        });
+        0
+    }
+
+    unsafe fn kpep_config_free(&self, cfg: *mut kpep_config) {
+        self.state
+            .borrow_mut()
+            .configs
+            .retain(|config| !std::ptr::eq(&*config.config, cfg));
+    }
+
+    unsafe fn kpep_config_add_event(
+        &self,
+        cfg: *mut kpep_config,
+        ev_ptr: *mut *mut kpep_event,
+        flag: c_uint,
+        err: *mut c_uint,
+    ) -> c_int {
+        let Some(index) = perf_event_index(*ev_ptr) else {
+            return KPEP_CONFIG_ERROR_EVENT_NOT_FOUND as c_int;
+        };
+        let mut state = self.state.borrow_mut();
+        let Some(config) = state.config(cfg) else {
+            return KPEP_CONFIG_ERROR_INVALID_ARGUMENT as c_int;
+        };
+        if config.events.len() == COUNTER_COUNT {
+            // Every counter is taken, by all of the added events
+            if !err.is_null() {
+                *err = (0..COUNTER_COUNT).fold(0, |bitmap, i| bitmap | 1 << i);
+            }
+            return KPEP_CONFIG_ERROR_CONFLICTING_EVENTS as c_int;
+        }
+        config.events.push((index, flag));
+        config.config.event_count = config.events.len();
+        config.config.counter_count = config.events.len();
+        0
+    }
+
+    unsafe fn kpep_config_force_counters(&self, _cfg: *mut kpep_config) -> c_int {
+        0
+    }
+
+    unsafe fn kpep_config_kpc(
+        &self,
+        cfg: *mut kpep_config,
+        buf: *mut kpc_config_t,
+        buf_size: size_t,
+    ) -> c_int {
+        let mut state = self.state.borrow_mut();
+        let Some(config) = state.config(cfg) else {
+            return KPEP_CONFIG_ERROR_INVALID_ARGUMENT as c_int;
+        };
+        if buf_size < config.events.len() * size_of::<kpc_config_t>() {
+            return KPEP_CONFIG_ERROR_BUFFER_TOO_SMALL as c_int;
+        }
+        for (i, &(index, flag)) in config.events.iter().enumerate() {
+            *buf.add(i) = REGISTER_VALID | (flag as kpc_config_t) << 32 | index as kpc_config_t;
+        }
+        0
+    }
+
+    unsafe fn kpep_config_kpc_count(&self, cfg: *mut kpep_config, count_ptr: *mut size_t) -> c_int {
+        let mut state = self.state.borrow_mut();
+        let Some(config) = state.config(cfg) else {
+            return KPEP_CONFIG_ERROR_INVALID_ARGUMENT as c_int;
+        };
+        *count_ptr = config.events.len();
+        0
+    }
+
+    unsafe fn kpep_config_kpc_classes(
+        &self,
+        _cfg: *mut kpep_config,
+        classes_ptr: *mut c_uint,
+    ) -> c_int {
+        *classes_ptr = KPC_CLASS_CONFIGURABLE_MASK;
+        0
+    }
+
+    unsafe fn kpep_config_kpc_map(
+        &self,
+        cfg: *mut kpep_config,
+        buf: *mut size_t,
+        buf_size: size_t,
+    ) -> c_int {
+        let mut state = self.state.borrow_mut();
+        let Some(config) = state.config(cfg) else {
+            return KPEP_CONFIG_ERROR_INVALID_ARGUMENT as c_int;
+        };
+        if buf_size < config.events.len() * size_of::<size_t>() {
+            return KPEP_CONFIG_ERROR_BUFFER_TOO_SMALL as c_int;
+        }
+        for i in 0..config.events.len() {
+            *buf.add(i) = i;
+        }
+        0
+    }
+
+    unsafe fn kpep_db_create(&self, _name: *const c_char, db_ptr: *mut *mut kpep_db) -> c_int {
+        let mut events: Vec<kpep_event> = PERF_EVENTS
+            .iter()
+            .filter(|desc| desc.type_ != PERF_TYPE_HARDWARE || perf_event_open(desc, 1).is_ok())
+            .map(|desc| kpep_event {
+                name: desc.name.as_ptr(),
+                description: desc.description.as_ptr(),
+                errata: null(),
+                alias: desc.alias.as_ptr(),
+                fallback: null(),
+                mask: 0,
+                number: desc.config as u8,
+                umask: 0,
+                reserved: 0,
+                is_fixed: 0,
+            })
+            .collect();
+
+        let mut db: Box<kpep_db> = Box::new(zeroed());
+        db.name = c"perf_event".as_ptr();
+        db.marketing_name = c"Linux perf_event".as_ptr();
+        db.event_arr = events.as_mut_ptr();
+        db.event_count = events.len();
+        db.fixed_counter_count = 0;
+        db.config_counter_count = COUNTER_COUNT;
+        db.archtecture = PERF_EVENT_ARCH;
+        db.config_counter_bits = 64;
+        *db_ptr = &mut *db;
+        self.state
+            .borrow_mut()
+            .databases
+            .push(PerfEventDatabase { db, events });
+        0
+    }
+
+    unsafe fn kpep_db_free(&self, db: *mut kpep_db) {
+        self.state
+            .borrow_mut()
+            .databases
+            .retain(|database| !std::ptr::eq(&*database.db, db));
+    }
+
+    unsafe fn kpep_db_events_count(&self, db: *mut kpep_db, count: *mut size_t) -> c_int {
+        let state = self.state.borrow();
+        match state.database(db) {
+            Some(database) => {
+                *count = database.events.len();
+                0
+            }
+            None => KPEP_CONFIG_ERROR_INVALID_ARGUMENT as c_int,
+        }
+    }
+
+    unsafe fn kpep_db_events(
+        &self,
+        db: *mut kpep_db,
+        buf: *mut *mut kpep_event,
+        buf_size: size_t,
+    ) -> c_int {
+        let mut state = self.state.borrow_mut();
+        let Some(database) = state.database_mut(db) else {
+            return KPEP_CONFIG_ERROR_INVALID_ARGUMENT as c_int;
+        };
+        if buf_size < database.events.len() * size_of::<*mut kpep_event>() {
+            return KPEP_CONFIG_ERROR_BUFFER_TOO_SMALL as c_int;
+        }
+        for (i, event) in database.events.iter_mut().enumerate() {
+            *buf.add(i) = event;
+        }
+        0
+    }
+
+    unsafe fn kpep_db_event(
+        &self,
+        db: *mut kpep_db,
+        name: *const c_char,
+        ev_ptr: *mut *mut kpep_event,
+    ) -> c_int {
+        let name = CStr::from_ptr(name);
+        let mut state = self.state.borrow_mut();
+        let Some(database) = state.database_mut(db) else {
+            return KPEP_CONFIG_ERROR_INVALID_ARGUMENT as c_int;
+        };
+        match database
+            .events
+            .iter_mut()
+            .find(|ev| CStr::from_ptr(ev.name) == name)
+        {
+            Some(ev) => {
+                *ev_ptr = ev;
+                0
+            }
+            None => KPEP_CONFIG_ERROR_EVENT_NOT_FOUND as c_int,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::KperfError;
+    use crate::event::Event;
+    use crate::kperf::{KProbesConfig, KProbesDatabase};
+    use crate::{PerfCounterBuilder, Track};
+    use std::fs::File;
+
+    /// Whether the kernel lets the tests open `task-clock` counters, reporting the test as
+    /// skipped if not.
+    fn perf_event_available(test: &str, pid: libc::pid_t, cpu: c_int) -> bool {
+        match perf_event_open_on(&PERF_EVENTS[4], 1, pid, cpu) {
+            Ok(_) => true,
+            Err(err) => {
+                eprintln!("skipped {}: perf_event_open failed: {}", test, err);
+                false
+            }
+        }
+    }
+
+    #[test]
+    fn test_database_lists_software_events() {
+        let db = KProbesDatabase::load_database_with(PerfEventBackend::default()).unwrap();
+        assert_eq!(db.get_db_name().as_deref(), Some("perf_event"));
+        assert_eq!(db.get_fixed_counter_count(), 0);
+        assert!(db.get_configurable_counter_count() >= 3);
+    }
+
+    #[test]
+    fn test_failures_set_errno() {
+        // Neither an ioctl nor a read of a perf event works on /dev/null
+        let counters = [OwnedFd::from(File::open("/dev/null").unwrap())];
+        assert_eq!(ioctl_counters(&counters, PERF_EVENT_IOC_RESET), -1);
+        assert_eq!(
+            io::Error::last_os_error().raw_os_error(),
+            Some(libc::ENOTTY)
+        );
+        assert_eq!(read_counters(&counters, &mut [0; 1]), -1);
+        assert_eq!(io::Error::last_os_error().raw_os_error(), Some(EIO));
+
+        let backend = PerfEventBackend::default();
+        backend.state.borrow_mut().counters = counters.into();
+        assert_eq!(unsafe { backend.kperf_reset() }, -1);
+        assert_eq!(unsafe { backend.kpc_set_thread_counting(1) }, -1);
+        assert_eq!(
+            io::Error::last_os_error().raw_os_error(),
+            Some(libc::ENOTTY)
+        );
+    }
+
+    #[test]
+    fn test_config_holds_a_counter_per_event() {
+        let mut db = KProbesDatabase::load_database_with(PerfEventBackend::default()).unwrap();
+        let mut config = KProbesConfig::from_database(&mut db).unwrap();
+        for _ in 0..COUNTER_COUNT {
+            config.add_event(&db, Event::TaskClock).unwrap();
+        }
+        match config.add_event(&db, Event::Named("task-clock".to_string())) {
+            Err(KperfError::ConflictingEvents { event, conflicts }) => {
+                assert_eq!(event, "task-clock");
+                assert_eq!(conflicts.len(), COUNTER_COUNT);
+            }
+            res => panic!("Expected ConflictingEvents, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_task_clock_counts_while_started() {
+        if !perf_event_available("test_task_clock_counts_while_started", 0, -1) {
+            return;
+        }
+        let mut counter = PerfCounterBuilder::try_with_backend(PerfEventBackend::default())
+            .unwrap()
+            .track_event(Event::TaskClock)
+            .build_counter()
+            .unwrap();
+        counter.start().unwrap();
+        let mut x = 0u64;
+        for i in 0..100_000 {
+            x = std::hint::black_box(x.wrapping_add(i));
+        }
+        counter.stop().unwrap();
+        assert!(counter.read().unwrap()[Event::TaskClock] > 0);
+    }
+
+    #[test]
+    fn test_cycles_count_on_hardware_or_as_task_clock() {
+        if !perf_event_available("test_cycles_count_on_hardware_or_as_task_clock", 0, -1) {
+            return;
+        }
+        let hardware = perf_event_open(&PERF_EVENTS[0], 1).is_ok();
+        let mut counter = PerfCounterBuilder::try_with_backend(PerfEventBackend::default())
+            .unwrap()
+            .allow_software_fallback()
+            .track_event(Event::Cycles)
+            .build_counter()
+            .unwrap();
+        let (_, snapshot) = counter
+            .measure(|| {
+                let mut x = 0u64;
+                for i in 0..100_000 {
+                    x = std::hint::black_box(x.wrapping_add(i));
+                }
+            })
+            .unwrap();
+        assert!(snapshot[Event::Cycles] > 0);
+        let substitute = (!hardware).then_some(&Event::TaskClock);
+        assert_eq!(snapshot.substitute(&Event::Cycles), substitute);
+    }
+
+    #[test]
+    fn test_cpu_counters_only_open_when_read() {
+        let test = "test_cpu_counters_only_open_when_read";
+        if !perf_event_available(test, 0, -1) {
+            return;
+        }
+        let backend = PerfEventBackend::default();
+        let mut counter = PerfCounterBuilder::try_with_backend(backend.clone())
+            .unwrap()
+            .track_event(Event::TaskClock)
+            .build_counter()
+            .unwrap();
+        counter.start().unwrap();
+        counter.stop().unwrap();
+        assert!(backend.state.borrow().cpu_counters.is_none());
+        drop(counter);
+
+        if !perf_event_available(test, -1, 0) {
+            return;
+        }
+        let mut counter = PerfCounterBuilder::try_with_backend(backend.clone())
+            .unwrap()
+            .track(Track::AllCpus)
+            .track_event(Event::TaskClock)
+            .build_counter()
+            .unwrap();
+        assert!(backend.state.borrow().cpu_counters.is_none());
+        counter.start().unwrap();
+        assert!(backend.state.borrow().cpu_counters.is_some());
+        counter.stop().unwrap();
+
+        // Stopped counters don't advance
+        let cpus = backend.cpu_count();
+        let read = || {
+            let mut buf = vec![0; cpus * PERF_EVENTS.len()];
+            let res = unsafe {
+                backend.kpc_get_cpu_counters(
+                    true,
+                    KPC_CLASS_CONFIGURABLE_MASK,
+                    std::ptr::null_mut(),
+                    buf.as_mut_ptr(),
+                )
+            };
+            assert_eq!(res, 0);
+            buf
+        };
+        let before = read();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert_eq!(read(), before);
+    }
+}
@@ -0,0 +1,1034 @@
+use crate::backend::{fail_with_errno, CounterBackend};
+use kperf_sys::constants::kpep_config_error_code::{
+    KPEP_CONFIG_ERROR_BUFFER_TOO_SMALL, KPEP_CONFIG_ERROR_CONFLICTING_EVENTS,
+    KPEP_CONFIG_ERROR_DB_NOT_FOUND, KPEP_CONFIG_ERROR_EVENT_NOT_FOUND,
+    KPEP_CONFIG_ERROR_INVALID_ARGUMENT,
+};
+use kperf_sys::constants::{
+    KPC_CLASS_CONFIGURABLE_MASK, KPC_CLASS_FIXED_MASK, KPEP_ARCH_ARM64, KPERF_ACTION_MAX,
+    KPERF_TIMER_MAX,
+};
+use kperf_sys::structs::{kpc_config_t, kpep_config, kpep_db, kpep_event};
+use libc::{c_char, c_int, c_uint, c_ulonglong, size_t, EBUSY, EINVAL, EPERM};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{CStr, CString};
+use std::mem::{size_of, zeroed};
+use std::ptr::null;
+use std::rc::Rc;
+
+/// One event of a [`SimulatedDatabase`].
+#[derive(Debug, Clone)]
+pub struct SimulatedEvent {
+    pub name: String,
+    pub alias: Option<String>,
+    pub description: String,
+    /// Event used in place of this one when its fixed counter is busy.
+    pub fallback: Option<String>,
+    /// Fixed events always count on their own fixed counter, in database order.
+    pub is_fixed: bool,
+    pub mask: c_uint,
+    pub number: u8,
+    pub umask: u8,
+}
+
+impl SimulatedEvent {
+    pub fn fixed(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            alias: None,
+            description: String::new(),
+            fallback: None,
+            is_fixed: true,
+            mask: 0,
+            number: 0,
+            umask: 0,
+        }
+    }
+
+    pub fn configurable(name: &str) -> Self {
+        Self {
+            is_fixed: false,
+            ..Self::fixed(name)
+        }
+    }
+
+    pub fn with_alias(mut self, alias: &str) -> Self {
+        self.alias = Some(alias.to_string());
+        self
+    }
+
+    pub fn with_description(mut self, description: &str) -> Self {
+        self.description = description.to_string();
+        self
+    }
+
+    pub fn with_fallback(mut self, fallback: &str) -> Self {
+        self.fallback = Some(fallback.to_string());
+        self
+    }
+
+    /// Set the raw PMU encoding reported in the `kpep_event`.
+    pub fn with_encoding(mut self, number: u8, umask: u8, mask: c_uint) -> Self {
+        self.number = number;
+        self.umask = umask;
+        self.mask = mask;
+        self
+    }
+}
+
+/// Fake `kpep_db` served by a [`SimulatedBackend`].
+#[derive(Debug, Clone)]
+pub struct SimulatedDatabase {
+    pub name: String,
+    pub marketing_name: String,
+    pub fixed_counter_count: usize,
+    pub config_counter_count: usize,
+    pub fixed_counter_bits: c_uint,
+    pub config_counter_bits: c_uint,
+    pub events: Vec<SimulatedEvent>,
+}
+
+impl SimulatedDatabase {
+    /// A database shaped like the one of an Apple M2 performance core.
+    pub fn apple_m2() -> Self {
+        Self {
+            name: "a15".to_string(),
+            marketing_name: "Apple A15".to_string(),
+            fixed_counter_count: 2,
+            config_counter_count: 8,
+            fixed_counter_bits: 48,
+            config_counter_bits: 48,
+            events: vec![
+                SimulatedEvent::fixed("FIXED_CYCLES")
+                    .with_alias("Cycles")
+                    .with_description("No. of cycles")
+                    .with_encoding(0x02, 0, 0x01),
+                SimulatedEvent::fixed("FIXED_INSTRUCTIONS")
+                    .with_alias("Instructions")
+                    .with_description("No. of retired instructions")
+                    .with_fallback("INST_ALL")
+                    .with_encoding(0x8c, 0, 0x02),
+                SimulatedEvent::configurable("INST_BRANCH")
+                    .with_description("Retired branch instructions")
+                    .with_encoding(0x8d, 0, 0xfc),
+                SimulatedEvent::configurable("BRANCH_MISPRED_NONSPEC")
+                    .with_description("Retired branches that mispredicted")
+                    .with_encoding(0xcb, 0, 0xfc),
+                SimulatedEvent::configurable("INST_ALL")
+                    .with_description("All retired instructions")
+                    .with_encoding(0x8c, 0, 0xfc),
+                SimulatedEvent::configurable("L1D_CACHE_MISS_LD")
+                    .with_description("Loads that missed the L1 data cache")
+                    .with_encoding(0xa3, 0, 0xfc),
+                SimulatedEvent::configurable("L1D_CACHE_MISS_ST")
+                    .with_description("Stores that missed the L1 data cache")
+                    .with_encoding(0xa2, 0, 0xfc),
+                SimulatedEvent::configurable("L1I_CACHE_MISS_DEMAND")
+                    .with_description("Demand fetches that missed the L1 instruction cache")
+                    .with_encoding(0xdb, 0, 0xfc),
+            ],
+        }
+    }
+}
+
+impl Default for SimulatedDatabase {
+    fn default() -> Self {
+        Self::apple_m2()
+    }
+}
+
+const SIMULATED_CPU_COUNT: usize = 4;
+
+/// PET timer id kperf uses when PET is off.
+const PET_TIMER_NONE: c_uint = 999;
+
+type CounterSource = Box<dyn FnMut(&str) -> u64>;
+type CpuCounterSource = Box<dyn FnMut(usize, &str) -> u64>;
+
+/// Deterministic stand-in for the kpc/kpep frameworks.
+///
+/// Events are scheduled on the counters of a [`SimulatedDatabase`] with the same limits as
+/// kpep, and the privileged calls set `errno` to `EPERM` and return -1 unless the backend runs
+/// "as root".
+/// Every call to `kpc_get_thread_counters` advances each running counter by the value the
+/// counter source (or the script) gives for its event, wrapping at the counter width.
+/// Per-CPU counters advance the same way on `kpc_get_cpu_counters`, from the CPU counter source.
+#[derive(Clone, Default)]
+pub struct SimulatedBackend {
+    state: Rc<RefCell<SimulatedState>>,
+}
+
+struct SimulatedState {
+    database: SimulatedDatabase,
+    root: bool,
+    force_all_ctrs: bool,
+    counting: c_uint,
+    thread_counting: c_uint,
+    /// Database event index programmed on every configurable counter.
+    config_registers: Vec<Option<usize>>,
+    counters: Vec<c_ulonglong>,
+    source: Option<CounterSource>,
+    current_cpu: usize,
+    cpu_counters: Vec<Vec<c_ulonglong>>,
+    cpu_source: Option<CpuCounterSource>,
+    script: HashMap<String, VecDeque<u64>>,
+    sampling: c_uint,
+    /// Samplers of every kperf action, from action id 1.
+    action_samplers: Vec<c_uint>,
+    /// Period and action of every kperf timer.
+    timers: Vec<(c_ulonglong, c_uint)>,
+    /// Timer doing PET, none if it isn't one of `timers`.
+    pet_timer: c_uint,
+    lightweight_pet: c_uint,
+    databases: Vec<SimulatedKpepDatabase>,
+    configs: Vec<SimulatedConfig>,
+}
+
+struct SimulatedKpepDatabase {
+    db: Box<kpep_db>,
+    events: Vec<kpep_event>,
+    // Only kept alive for the pointers stored in `db` and `events`
+    _fixed_events: Vec<*mut kpep_event>,
+    _strings: Vec<CString>,
+}
+
+struct SimulatedConfig {
+    config: Box<kpep_config>,
+    /// Database event index and counter index of every added event.
+    events: Vec<(usize, usize)>,
+    flags: Vec<c_uint>,
+}
+
+impl Default for SimulatedState {
+    fn default() -> Self {
+        Self::new(SimulatedDatabase::default())
+    }
+}
+
+impl SimulatedState {
+    fn new(database: SimulatedDatabase) -> Self {
+        Self {
+            config_registers: vec![None; database.config_counter_count],
+            counters: vec![0; database.fixed_counter_count + database.config_counter_count],
+            current_cpu: 0,
+            cpu_counters: vec![
+                vec![0; database.fixed_counter_count + database.config_counter_count];
+                SIMULATED_CPU_COUNT
+            ],
+            database,
+            root: true,
+            force_all_ctrs: false,
+            counting: 0,
+            thread_counting: 0,
+            source: None,
+            cpu_source: None,
+            script: HashMap::new(),
+            sampling: 0,
+            action_samplers: Vec::new(),
+            timers: Vec::new(),
+            pet_timer: PET_TIMER_NONE,
+            lightweight_pet: 0,
+            databases: Vec::new(),
+            configs: Vec::new(),
+        }
+    }
+
+    fn database(&self, db: *mut kpep_db) -> Option<&SimulatedKpepDatabase> {
+        self.databases
+            .iter()
+            .find(|database| std::ptr::eq(&*database.db, db))
+    }
+
+    fn database_mut(&mut self, db: *mut kpep_db) -> Option<&mut SimulatedKpepDatabase> {
+        self.databases
+            .iter_mut()
+            .find(|database| std::ptr::eq(&*database.db, db))
+    }
+
+    fn config(&mut self, cfg: *mut kpep_config) -> Option<&mut SimulatedConfig> {
+        self.configs
+            .iter_mut()
+            .find(|config| std::ptr::eq(&*config.config, cfg))
+    }
+
+    fn event_index(&self, ev: *const kpep_event) -> Option<usize> {
+        self.databases.iter().find_map(|database| {
+            database
+                .events
+                .iter()
+                .position(|event| std::ptr::eq(event, ev))
+        })
+    }
+
+    fn fixed_counter(&self, event: usize) -> usize {
+        self.database.events[..event]
+            .iter()
+            .filter(|event| event.is_fixed)
+            .count()
+    }
+
+    fn next_increment(&mut self, event: usize) -> u64 {
+        let name = &self.database.events[event].name;
+        match &mut self.source {
+            Some(source) => source(name),
+            None => self
+                .script
+                .get_mut(name)
+                .and_then(|values| values.pop_front())
+                .unwrap_or(0),
+        }
+    }
+
+    /// Counter index, event index and width of every counter running in `classes`.
+    fn running_counters(&self, classes: c_uint) -> Vec<(usize, usize, c_uint)> {
+        let mut running = Vec::new();
+        if classes & KPC_CLASS_FIXED_MASK != 0 {
+            let fixed_events = (0..self.database.events.len())
+                .filter(|&event| self.database.events[event].is_fixed)
+                .take(self.database.fixed_counter_count);
+            for (counter, event) in fixed_events.enumerate() {
+                running.push((counter, event, self.database.fixed_counter_bits));
+            }
+        }
+        if classes & KPC_CLASS_CONFIGURABLE_MASK != 0 {
+            for (register, event) in self.config_registers.iter().enumerate() {
+                if let Some(event) = *event {
+                    let counter = self.database.fixed_counter_count + register;
+                    running.push((counter, event, self.database.config_counter_bits));
+                }
+            }
+        }
+        running
+    }
+
+    fn advance_running_counters(&mut self) {
+        for (counter, event, bits) in self.running_counters(self.counting & self.thread_counting) {
+            let increment = self.next_increment(event);
+            self.counters[counter] = wrap(self.counters[counter].wrapping_add(increment), bits);
+        }
+    }
+
+    fn advance_cpu_counters(&mut self) {
+        let running = self.running_counters(self.counting);
+        let Some(source) = &mut self.cpu_source else {
+            return;
+        };
+        for (cpu, counters) in self.cpu_counters.iter_mut().enumerate() {
+            for &(counter, event, bits) in &running {
+                let increment = source(cpu, &self.database.events[event].name);
+                counters[counter] = wrap(counters[counter].wrapping_add(increment), bits);
+            }
+        }
+    }
+}
+
+fn wrap(value: c_ulonglong, bits: c_uint) -> c_ulonglong {
+    if bits >= 64 {
+        value
+    } else {
+        value & ((1 << bits) - 1)
+    }
+}
+
+impl SimulatedBackend {
+    pub fn new(database: SimulatedDatabase) -> Self {
+        Self {
+            state: Rc::new(RefCell::new(SimulatedState::new(database))),
+        }
+    }
+
+    /// Pretend the process does (or doesn't) have super-user privileges.
+    pub fn set_root(&self, root: bool) {
+        self.state.borrow_mut().root = root;
+    }
+
+    /// Use `source` to know how much a running counter advances on every read.
+    /// It is given the event name, and takes precedence over any script.
+    pub fn set_counter_source<F: FnMut(&str) -> u64 + 'static>(&self, source: F) {
+        self.state.borrow_mut().source = Some(Box::new(source));
+    }
+
+    /// Number of CPUs reported by `kpc_get_cpu_counters`, 4 by default.
+    pub fn set_cpu_count(&self, cpu_count: usize) {
+        let mut state = self.state.borrow_mut();
+        let counter_count = state.counters.len();
+        state.cpu_counters.resize(cpu_count, vec![0; counter_count]);
+        state.current_cpu = state.current_cpu.min(cpu_count.saturating_sub(1));
+    }
+
+    /// Pretend the calling thread runs on `cpu`.
+    pub fn set_current_cpu(&self, cpu: usize) {
+        self.state.borrow_mut().current_cpu = cpu;
+    }
+
+    /// Use `source` to know how much a running per-CPU counter advances on every read.
+    /// It is given the CPU and the event name, per-CPU counters don't advance without it.
+    pub fn set_cpu_counter_source<F: FnMut(usize, &str) -> u64 + 'static>(&self, source: F) {
+        self.state.borrow_mut().cpu_source = Some(Box::new(source));
+    }
+
+    /// Queue the amounts `event` advances by on its next reads, 0 once they are exhausted.
+    pub fn script_event(&self, event: &str, increments: &[u64]) {
+        self.state
+            .borrow_mut()
+            .script
+            .entry(event.to_string())
+            .or_default()
+            .extend(increments);
+    }
+
+    pub fn counting(&self) -> c_uint {
+        self.state.borrow().counting
+    }
+
+    pub fn thread_counting(&self) -> c_uint {
+        self.state.borrow().thread_counting
+    }
+
+    pub fn force_all_ctrs(&self) -> bool {
+        self.state.borrow().force_all_ctrs
+    }
+
+    /// Name and kpep flag of every event added to a config not freed yet.
+    pub fn added_events(&self) -> Vec<(String, c_uint)> {
+        let state = self.state.borrow();
+        state
+            .configs
+            .iter()
+            .flat_map(|config| config.events.iter().zip(&config.flags))
+            .map(|(&(event, _), &flag)| (state.database.events[event].name.clone(), flag))
+            .collect()
+    }
+
+    pub fn sampling(&self) -> bool {
+        self.state.borrow().sampling != 0
+    }
+
+    /// Samplers of every kperf action, from action id 1.
+    pub fn action_samplers(&self) -> Vec<c_uint> {
+        self.state.borrow().action_samplers.clone()
+    }
+
+    /// Timer doing PET, if any.
+    pub fn pet_timer(&self) -> Option<c_uint> {
+        let state = self.state.borrow();
+        ((state.pet_timer as usize) < state.timers.len()).then_some(state.pet_timer)
+    }
+
+    pub fn lightweight_pet(&self) -> bool {
+        self.state.borrow().lightweight_pet != 0
+    }
+
+    /// Period in ticks and action id of every kperf timer.
+    pub fn timers(&self) -> Vec<(c_ulonglong, c_uint)> {
+        self.state.borrow().timers.clone()
+    }
+
+    /// Number of kpep databases and configs created and not freed yet.
+    pub fn live_kpep_objects(&self) -> usize {
+        let state = self.state.borrow();
+        state.databases.len() + state.configs.len()
+    }
+
+    /// Raw value of every counter, fixed counters first.
+    pub fn counter_values(&self) -> Vec<c_ulonglong> {
+        self.state.borrow().counters.clone()
+    }
+}
+
+impl CounterBackend for SimulatedBackend {
+    fn state_id(&self) -> usize {
+        Rc::as_ptr(&self.state) as *const () as usize
+    }
+
+    unsafe fn kpc_get_counting(&self) -> c_uint {
+        self.state.borrow().counting
+    }
+
+    unsafe fn kpc_set_counting(&self, classes: c_uint) -> c_int {
+        let mut state = self.state.borrow_mut();
+        if !state.root {
+            return fail_with_errno(EPERM);
+        }
+        state.counting = classes;
+        0
+    }
+
+    unsafe fn kpc_get_thread_counting(&self) -> c_uint {
+        self.state.borrow().thread_counting
+    }
+
+    unsafe fn kpc_set_thread_counting(&self, classes: c_uint) -> c_int {
+        let mut state = self.state.borrow_mut();
+        if !state.root {
+            return fail_with_errno(EPERM);
+        }
+        state.thread_counting = classes;
+        0
+    }
+
+    unsafe fn kpc_set_config(&self, classes: c_uint, config: *mut kpc_config_t) -> c_int {
+        let mut state = self.state.borrow_mut();
+        if !state.root {
+            return fail_with_errno(EPERM);
+        }
+        if classes & KPC_CLASS_CONFIGURABLE_MASK != 0 {
+            // The Power Manager owns the configurable counters until they are forced
+            if !state.force_all_ctrs {
+                return fail_with_errno(EPERM);
+            }
+            let count = state.database.config_counter_count;
+            let registers = std::slice::from_raw_parts(config, count);
+            for (slot, &register) in state.config_registers.iter_mut().zip(registers) {
+                let event = (register & 0xffff_ffff) as usize;
+                *slot = event.checked_sub(1);
+            }
+        }
+        0
+    }
+
+    unsafe fn kpc_get_counter_count(&self, classes: c_uint) -> c_uint {
+        let state = self.state.borrow();
+        let mut count = 0;
+        if classes & KPC_CLASS_FIXED_MASK != 0 {
+            count += state.database.fixed_counter_count;
+        }
+        if classes & KPC_CLASS_CONFIGURABLE_MASK != 0 {
+            count += state.database.config_counter_count;
+        }
+        count as c_uint
+    }
+
+    unsafe fn kpc_get_cpu_counters(
+        &self,
+        all_cpus: bool,
+        classes: c_uint,
+        curcpu: *mut c_int,
+        buf: *mut c_ulonglong,
+    ) -> c_int {
+        let counter_count = self.kpc_get_counter_count(classes) as usize;
+        let mut state = self.state.borrow_mut();
+        if !curcpu.is_null() {
+            *curcpu = state.current_cpu as c_int;
+        }
+        state.advance_cpu_counters();
+        let cpus = if all_cpus {
+            0..state.cpu_counters.len()
+        } else {
+            state.current_cpu..state.current_cpu + 1
+        };
+        for (block, cpu) in cpus.enumerate() {
+            let buf = std::slice::from_raw_parts_mut(buf.add(block * counter_count), counter_count);
+            buf.fill(0);
+            for (value, counter) in buf.iter_mut().zip(&state.cpu_counters[cpu]) {
+                *value = *counter;
+            }
+        }
+        0
+    }
+
+    unsafe fn kpc_get_thread_counters(
+        &self,
+        _tid: c_uint,
+        buf_count: c_uint,
+        buf: *mut c_ulonglong,
+    ) -> c_int {
+        let mut state = self.state.borrow_mut();
+        state.advance_running_counters();
+        let buf = std::slice::from_raw_parts_mut(buf, buf_count as usize);
+        buf.fill(0);
+        for (value, counter) in buf.iter_mut().zip(&state.counters) {
+            *value = *counter;
+        }
+        0
+    }
+
+    unsafe fn kpc_force_all_ctrs_set(&self, val: c_int) -> c_int {
+        let mut state = self.state.borrow_mut();
+        if !state.root {
+            return fail_with_errno(EPERM);
+        }
+        state.force_all_ctrs = val != 0;
+        0
+    }
+
+    unsafe fn kpc_force_all_ctrs_get(&self, val_out: *mut c_int) -> c_int {
+        let state = self.state.borrow();
+        if !state.root {
+            return fail_with_errno(EPERM);
+        }
+        *val_out = state.force_all_ctrs as c_int;
+        0
+    }
+
+    unsafe fn kperf_reset(&self) -> c_int {
+        let mut state = self.state.borrow_mut();
+        if !state.root {
+            return fail_with_errno(EPERM);
+        }
+        state.sampling = 0;
+        state.action_samplers.clear();
+        state.timers.clear();
+        state.pet_timer = PET_TIMER_NONE;
+        state.lightweight_pet = 0;
+        0
+    }
+
+    unsafe fn kperf_action_count_set(&self, count: c_uint) -> c_int {
+        let mut state = self.state.borrow_mut();
+        if !state.root {
+            return fail_with_errno(EPERM);
+        }
+        if count > KPERF_ACTION_MAX {
+            return fail_with_errno(EINVAL);
+        }
+        state.action_samplers.resize(count as usize, 0);
+        0
+    }
+
+    unsafe fn kperf_action_samplers_set(&self, actionid: c_uint, sample: c_uint) -> c_int {
+        let mut state = self.state.borrow_mut();
+        if !state.root {
+            return fail_with_errno(EPERM);
+        }
+        // Action 0 means no action
+        if actionid == 0 || actionid as usize > state.action_samplers.len() {
+            return fail_with_errno(EINVAL);
+        }
+        state.action_samplers[actionid as usize - 1] = sample;
+        0
+    }
+
+    unsafe fn kperf_timer_count_set(&self, count: c_uint) -> c_int {
+        let mut state = self.state.borrow_mut();
+        if !state.root {
+            return fail_with_errno(EPERM);
+        }
+        if count > KPERF_TIMER_MAX {
+            return fail_with_errno(EINVAL);
+        }
+        state.timers.resize(count as usize, (0, 0));
+        0
+    }
+
+    unsafe fn kperf_timer_period_set(&self, timerid: c_uint, tick: c_ulonglong) -> c_int {
+        let mut state = self.state.borrow_mut();
+        if !state.root {
+            return fail_with_errno(EPERM);
+        }
+        match state.timers.get_mut(timerid as usize) {
+            Some((period, _)) => {
+                *period = tick;
+                0
+            }
+            None => fail_with_errno(EINVAL),
+        }
+    }
+
+    unsafe fn kperf_timer_action_set(&self, timerid: c_uint, actionid: c_uint) -> c_int {
+        let mut state = self.state.borrow_mut();
+        if !state.root {
+            return fail_with_errno(EPERM);
+        }
+        if actionid as usize > state.action_samplers.len() {
+            return fail_with_errno(EINVAL);
+        }
+        match state.timers.get_mut(timerid as usize) {
+            Some((_, action)) => {
+                *action = actionid;
+                0
+            }
+            None => fail_with_errno(EINVAL),
+        }
+    }
+
+    unsafe fn kperf_timer_pet_set(&self, timerid: c_uint) -> c_int {
+        let mut state = self.state.borrow_mut();
+        if !state.root {
+            return fail_with_errno(EPERM);
+        }
+        if state.sampling != 0 {
+            return fail_with_errno(EBUSY);
+        }
+        // Any id past the timers turns PET off
+        state.pet_timer = timerid;
+        0
+    }
+
+    unsafe fn kperf_timer_pet_get(&self, timerid: *mut c_uint) -> c_int {
+        *timerid = self.state.borrow().pet_timer;
+        0
+    }
+
+    unsafe fn kperf_lightweight_pet_set(&self, enabled: c_uint) -> c_int {
+        let mut state = self.state.borrow_mut();
+        if !state.root {
+            return fail_with_errno(EPERM);
+        }
+        if state.sampling != 0 {
+            return fail_with_errno(EBUSY);
+        }
+        state.lightweight_pet = enabled;
+        0
+    }
+
+    unsafe fn kperf_lightweight_pet_get(&self, enabled: *mut c_uint) -> c_int {
+        *enabled = self.state.borrow().lightweight_pet;
+        0
+    }
+
+    unsafe fn kperf_sample_set(&self, enabled: c_uint) -> c_int {
+        let mut state = self.state.borrow_mut();
+        if !state.root {
+            return fail_with_errno(EPERM);
+        }
+        state.sampling = enabled;
+        0
+    }
+
+    unsafe fn kperf_sample_get(&self, enabled: *mut c_uint) -> c_int {
+        *enabled = self.state.borrow().sampling;
+        0
+    }
+
+    fn cpu_count(&self) -> usize {
+        self.state.borrow().cpu_counters.len()
+    }
+
+    unsafe fn kpep_config_create(&self, db: *mut kpep_db, cfg_ptr: *mut *mut kpep_config) -> c_int {
+        let mut config: Box<kpep_config> = Box::new(zeroed());
+        config.db = db;
+        *cfg_ptr = &mut *config;
+        self.state.borrow_mut().configs.push(SimulatedConfig {
+            config,
+            events: Vec::new(),
+            flags: Vec::new(),
+        });
+        0
+    }
+
+    unsafe fn kpep_config_free(&self, cfg: *mut kpep_config) {
+        self.state
+            .borrow_mut()
+            .configs
+            .retain(|config| !std::ptr::eq(&*config.config, cfg));
+    }
+
+    unsafe fn kpep_config_add_event(
+        &self,
+        cfg: *mut kpep_config,
+        ev_ptr: *mut *mut kpep_event,
+        flag: c_uint,
+        err: *mut c_uint,
+    ) -> c_int {
+        let mut state = self.state.borrow_mut();
+        let Some(event) = state.event_index(*ev_ptr) else {
+            return KPEP_CONFIG_ERROR_INVALID_ARGUMENT as c_int;
+        };
+        let is_fixed = state.database.events[event].is_fixed;
+        let fixed_count = state.database.fixed_counter_count;
+        let config_count = state.database.config_counter_count;
+        let fixed_counter = state.fixed_counter(event);
+        let Some(config) = state.config(cfg) else {
+            return KPEP_CONFIG_ERROR_INVALID_ARGUMENT as c_int;
+        };
+
+        // Bitmap of the already added events competing for the same counters
+        let conflicts = |config: &SimulatedConfig, fixed: bool| -> c_uint {
+            config
+                .events
+                .iter()
+                .enumerate()
+                .filter(|(_, &(_, counter))| (counter < fixed_count) == fixed)
+                .fold(0, |bitmap, (i, _)| bitmap | 1 << i)
+        };
+        let counter = if is_fixed && fixed_counter < fixed_count {
+            if config.events.iter().any(|&(_, c)| c == fixed_counter) {
+                if !err.is_null() {
+                    *err = conflicts(config, true);
+                }
+                return KPEP_CONFIG_ERROR_CONFLICTING_EVENTS as c_int;
+            }
+            fixed_counter
+        } else {
+            let used: Vec<usize> = config.events.iter().map(|&(_, c)| c).collect();
+            match (fixed_count..fixed_count + config_count).find(|c| !used.contains(c)) {
+                Some(counter) => counter,
+                None => {
+                    if !err.is_null() {
+                        *err = conflicts(config, false);
+                    }
+                    return KPEP_CONFIG_ERROR_CONFLICTING_EVENTS as c_int;
+                }
+            }
+        };
+        config.events.push((event, counter));
+        config.flags.push(flag);
+        config.config.event_count = config.events.len();
+        0
+    }
+
+    unsafe fn kpep_config_force_counters(&self, cfg: *mut kpep_config) -> c_int {
+        match self.state.borrow_mut().config(cfg) {
+            Some(_) => 0,
+            None => KPEP_CONFIG_ERROR_INVALID_ARGUMENT as c_int,
+        }
+    }
+
+    unsafe fn kpep_config_kpc(
+        &self,
+        cfg: *mut kpep_config,
+        buf: *mut kpc_config_t,
+        buf_size: size_t,
+    ) -> c_int {
+        let mut state = self.state.borrow_mut();
+        let fixed_count = state.database.fixed_counter_count;
+        let config_count = state.database.config_counter_count;
+        let Some(config) = state.config(cfg) else {
+            return KPEP_CONFIG_ERROR_INVALID_ARGUMENT as c_int;
+        };
+        if buf_size < config_count * size_of::<kpc_config_t>() {
+            return KPEP_CONFIG_ERROR_BUFFER_TOO_SMALL as c_int;
+        }
+        let registers = std::slice::from_raw_parts_mut(buf, config_count);
+        registers.fill(0);
+        for (&(event, counter), &flag) in config.events.iter().zip(&config.flags) {
+            if counter >= fixed_count {
+                registers[counter - fixed_count] =
+                    (flag as kpc_config_t) << 32 | (event + 1) as kpc_config_t;
+            }
+        }
+        0
+    }
+
+    unsafe fn kpep_config_kpc_count(&self, cfg: *mut kpep_config, count_ptr: *mut size_t) -> c_int {
+        let mut state = self.state.borrow_mut();
+        let config_count = state.database.config_counter_count;
+        let fixed_count = state.database.fixed_counter_count;
+        let Some(config) = state.config(cfg) else {
+            return KPEP_CONFIG_ERROR_INVALID_ARGUMENT as c_int;
+        };
+        let configurable = config
+            .events
+            .iter()
+            .any(|&(_, counter)| counter >= fixed_count);
+        *count_ptr = if configurable { config_count } else { 0 };
+        0
+    }
+
+    unsafe fn kpep_config_kpc_classes(
+        &self,
+        cfg: *mut kpep_config,
+        classes_ptr: *mut c_uint,
+    ) -> c_int {
+        let mut state = self.state.borrow_mut();
+        let fixed_count = state.database.fixed_counter_count;
+        let Some(config) = state.config(cfg) else {
+            return KPEP_CONFIG_ERROR_INVALID_ARGUMENT as c_int;
+        };
+        let mut classes = KPC_CLASS_FIXED_MASK;
+        if config
+            .events
+            .iter()
+            .any(|&(_, counter)| counter >= fixed_count)
+        {
+            classes |= KPC_CLASS_CONFIGURABLE_MASK;
+        }
+        config.config.classes = classes;
+        *classes_ptr = classes;
+        0
+    }
+
+    unsafe fn kpep_config_kpc_map(
+        &self,
+        cfg: *mut kpep_config,
+        buf: *mut size_t,
+        buf_size: size_t,
+    ) -> c_int {
+        let mut state = self.state.borrow_mut();
+        let Some(config) = state.config(cfg) else {
+            return KPEP_CONFIG_ERROR_INVALID_ARGUMENT as c_int;
+        };
+        if buf_size < config.events.len() * size_of::<size_t>() {
+            return KPEP_CONFIG_ERROR_BUFFER_TOO_SMALL as c_int;
+        }
+        for (i, &(_, counter)) in config.events.iter().enumerate() {
+            *buf.add(i) = counter;
+        }
+        0
+    }
+
+    unsafe fn kpep_db_create(&self, name: *const c_char, db_ptr: *mut *mut kpep_db) -> c_int {
+        let mut state = self.state.borrow_mut();
+        let database = state.database.clone();
+        if !name.is_null() && CStr::from_ptr(name).to_bytes() != database.name.as_bytes() {
+            return KPEP_CONFIG_ERROR_DB_NOT_FOUND as c_int;
+        }
+
+        let mut strings = Vec::new();
+        let mut intern = |value: &str| {
+            let value = CString::new(value).unwrap();
+            let ptr = value.as_ptr();
+            strings.push(value);
+            ptr
+        };
+        let mut events: Vec<kpep_event> = database
+            .events
+            .iter()
+            .map(|event| kpep_event {
+                name: intern(&event.name),
+                description: intern(&event.description),
+                errata: null(),
+                alias: event.alias.as_deref().map_or(null(), &mut intern),
+                fallback: event.fallback.as_deref().map_or(null(), &mut intern),
+                mask: event.mask,
+                number: event.number,
+                umask: event.umask,
+                reserved: 0,
+                is_fixed: event.is_fixed as u8,
+            })
+            .collect();
+        let mut fixed_events: Vec<*mut kpep_event> = events
+            .iter_mut()
+            .filter(|event| event.is_fixed != 0)
+            .map(|event| event as *mut kpep_event)
+            .collect();
+
+        let mut db: Box<kpep_db> = Box::new(zeroed());
+        db.name = intern(&database.name);
+        db.cpu_id = intern(&database.name);
+        db.marketing_name = intern(&database.marketing_name);
+        db.event_arr = events.as_mut_ptr();
+        db.fixed_event_arr = fixed_events.as_mut_ptr();
+        db.event_count = events.len();
+        db.alias_count = database.events.iter().filter(|e| e.alias.is_some()).count();
+        db.fixed_counter_count = database.fixed_counter_count;
+        db.config_counter_count = database.config_counter_count;
+        db.archtecture = KPEP_ARCH_ARM64;
+        db.fixed_counter_bits = database.fixed_counter_bits;
+        db.config_counter_bits = database.config_counter_bits;
+        *db_ptr = &mut *db;
+        state.databases.push(SimulatedKpepDatabase {
+            db,
+            events,
+            _fixed_events: fixed_events,
+            _strings: strings,
+        });
+        0
+    }
+
+    unsafe fn kpep_db_free(&self, db: *mut kpep_db) {
+        self.state
+            .borrow_mut()
+            .databases
+            .retain(|database| !std::ptr::eq(&*database.db, db));
+    }
+
+    unsafe fn kpep_db_events_count(&self, db: *mut kpep_db, count: *mut size_t) -> c_int {
+        let state = self.state.borrow();
+        match state.database(db) {
+            Some(database) => {
+                *count = database.events.len();
+                0
+            }
+            None => KPEP_CONFIG_ERROR_INVALID_ARGUMENT as c_int,
+        }
+    }
+
+    unsafe fn kpep_db_events(
+        &self,
+        db: *mut kpep_db,
+        buf: *mut *mut kpep_event,
+        buf_size: size_t,
+    ) -> c_int {
+        let mut state = self.state.borrow_mut();
+        let Some(database) = state.database_mut(db) else {
+            return KPEP_CONFIG_ERROR_INVALID_ARGUMENT as c_int;
+        };
+        if buf_size < database.events.len() * size_of::<*mut kpep_event>() {
+            return KPEP_CONFIG_ERROR_BUFFER_TOO_SMALL as c_int;
+        }
+        for (i, event) in database.events.iter_mut().enumerate() {
+            *buf.add(i) = event;
+        }
+        0
+    }
+
+    unsafe fn kpep_db_event(
+        &self,
+        db: *mut kpep_db,
+        name: *const c_char,
+        ev_ptr: *mut *mut kpep_event,
+    ) -> c_int {
+        let name = CStr::from_ptr(name);
+        let mut state = self.state.borrow_mut();
+        let Some(database) = state.database_mut(db) else {
+            return KPEP_CONFIG_ERROR_INVALID_ARGUMENT as c_int;
+        };
+        match database
+            .events
+            .iter_mut()
+            .find(|ev| CStr::from_ptr(ev.name) == name)
+        {
+            Some(ev) => {
+                *ev_ptr = ev;
+                0
+            }
+            None => KPEP_CONFIG_ERROR_EVENT_NOT_FOUND as c_int,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::KperfError;
+    use crate::event::Event;
+    use crate::{check_kpc_permission_with, PerfCounterBuilder};
+
+    #[test]
+    fn test_configurable_counters_are_limited() {
+        let database = SimulatedDatabase {
+            config_counter_count: 1,
+            ..SimulatedDatabase::apple_m2()
+        };
+        let backend = SimulatedBackend::new(database);
+        let mut db = crate::kperf::KProbesDatabase::load_database_with(backend).unwrap();
+        let mut config = crate::kperf::KProbesConfig::from_database(&mut db).unwrap();
+        config.add_event(&db, Event::Cycles).unwrap();
+        config.add_event(&db, Event::Instructions).unwrap();
+        config.add_event(&db, Event::Branches).unwrap();
+        assert!(config.add_event(&db, Event::BranchMisses).is_err());
+    }
+
+    #[test]
+    fn test_not_root() {
+        let backend = SimulatedBackend::default();
+        backend.set_root(false);
+        assert!(matches!(
+            check_kpc_permission_with(&backend),
+            Err(KperfError::PermissionDenied)
+        ));
+        let result = PerfCounterBuilder::try_with_backend(backend)
+            .unwrap()
+            .track_event(Event::Cycles)
+            .build_counter();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_scripted_configurable_counter() {
+        let backend = SimulatedBackend::default();
+        backend.script_event("BRANCH_MISPRED_NONSPEC", &[5, 7, 11]);
+        let mut counter = PerfCounterBuilder::try_with_backend(backend.clone())
+            .unwrap()
+            .track_event(Event::BranchMisses)
+            .build_counter()
+            .unwrap();
+        assert!(backend.force_all_ctrs());
+        counter.start().unwrap();
+        assert_eq!(counter.read().unwrap()[Event::BranchMisses], 7);
+        assert_eq!(counter.read().unwrap()[Event::BranchMisses], 18);
+    }
+}
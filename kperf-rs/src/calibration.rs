@@ -0,0 +1,144 @@
+use crate::event::Event;
+use std::fmt;
+use std::fmt::Formatter;
+
+/// What reading the counters adds to the value of one event, measured on empty regions.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Overhead {
+    pub mean: f64,
+    /// Sample variance of the measurements, 0 for less than two of them.
+    pub variance: f64,
+}
+
+impl Overhead {
+    fn from_samples(samples: &[u64]) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+        let count = samples.len() as f64;
+        let mean = samples.iter().map(|&sample| sample as f64).sum::<f64>() / count;
+        let variance = if samples.len() < 2 {
+            0.
+        } else {
+            samples
+                .iter()
+                .map(|&sample| (sample as f64 - mean).powi(2))
+                .sum::<f64>()
+                / (count - 1.)
+        };
+        Self { mean, variance }
+    }
+
+    pub fn std_dev(&self) -> f64 {
+        self.variance.sqrt()
+    }
+
+    /// Amount subtracted from the values of the event.
+    pub fn correction(&self) -> u64 {
+        self.mean.round() as u64
+    }
+
+    /// Overhead of `count` independent measurements, each adding this one.
+    pub fn times(&self, count: u64) -> Self {
+        Self {
+            mean: self.mean * count as f64,
+            variance: self.variance * count as f64,
+        }
+    }
+}
+
+impl fmt::Display for Overhead {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.1} ± {:.1}", self.mean, self.std_dev())
+    }
+}
+
+/// Measurement overhead of every tracked event, see [`crate::PerfCounter::calibrate`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Calibration {
+    overheads: Vec<(Event, Overhead)>,
+    repetitions: usize,
+}
+
+impl Calibration {
+    /// Calibration from the values `samples` measured for `events` on each repetition.
+    pub(crate) fn from_samples(events: &[Event], samples: &[Vec<u64>]) -> Self {
+        let overheads = events
+            .iter()
+            .enumerate()
+            .map(|(idx, event)| {
+                let values: Vec<u64> = samples.iter().map(|sample| sample[idx]).collect();
+                (event.clone(), Overhead::from_samples(&values))
+            })
+            .collect();
+        Self {
+            overheads,
+            repetitions: samples.len(),
+        }
+    }
+
+    pub fn get(&self, event: &Event) -> Option<Overhead> {
+        self.overheads
+            .iter()
+            .find(|(calibrated, _)| calibrated == event)
+            .map(|(_, overhead)| *overhead)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Event, Overhead)> {
+        self.overheads
+            .iter()
+            .map(|(event, overhead)| (event, *overhead))
+    }
+
+    /// Number of empty regions measured.
+    pub fn repetitions(&self) -> usize {
+        self.repetitions
+    }
+
+    /// Overheads in the order of the calibrated events.
+    pub(crate) fn overheads(&self) -> Vec<Overhead> {
+        self.overheads
+            .iter()
+            .map(|(_, overhead)| *overhead)
+            .collect()
+    }
+}
+
+impl fmt::Display for Calibration {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (event, overhead) in &self.overheads {
+            writeln!(f, "{}: {}", event, overhead)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overhead_statistics() {
+        let calibration = Calibration::from_samples(
+            &[Event::Cycles, Event::Instructions],
+            &[vec![90, 10], vec![100, 10], vec![111, 10]],
+        );
+        assert_eq!(calibration.repetitions(), 3);
+        let cycles = calibration.get(&Event::Cycles).unwrap();
+        assert!((cycles.mean - 100.333).abs() < 1e-3);
+        assert!((cycles.variance - 110.333).abs() < 1e-3);
+        assert_eq!(cycles.correction(), 100);
+        assert_eq!(
+            calibration.get(&Event::Instructions),
+            Some(Overhead {
+                mean: 10.,
+                variance: 0.
+            })
+        );
+        assert_eq!(calibration.get(&Event::Branches), None);
+        assert_eq!(
+            calibration.to_string(),
+            "Cycles: 100.3 ± 10.5\nInstructions: 10.0 ± 0.0\n"
+        );
+    }
+}
@@ -1,11 +1,290 @@
-#[derive(Debug, Copy, Clone)]
+use kperf_sys::constants::kpep_config_error_code;
+use kperf_sys::constants::kpep_config_error_code::*;
+#[cfg(feature = "dynamic")]
+use kperf_sys::dynamic::LoadError;
+use libc::{c_int, c_uint};
+use std::error::Error;
+use std::fmt;
+use std::fmt::Formatter;
+use std::io;
+
+/// Error codes returned by the kpep functions, see `kperf_sys::constants::kpep_config_error_code`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum KpepError {
-    UnknownError,
+    InvalidArgument,
+    OutOfMemory,
+    Io,
+    BufferTooSmall,
+    CurSystemUnknown,
+    DbPathInvalid,
+    DbNotFound,
+    DbArchUnsupported,
+    DbVersionUnsupported,
+    DbCorrupt,
+    EventNotFound,
+    ConflictingEvents,
+    CountersNotForced,
+    EventUnavailable,
+    /// The call failed with the contained `errno`.
+    Errno(c_int),
+    /// A code kperf-sys doesn't know about.
+    UnknownError(c_int),
+}
+
+impl KpepError {
+    /// Error matching the non-zero return `code` of a kpep function.
+    ///
+    /// Must be called right after the failing call, as [`KpepError::Errno`] captures `errno`.
+    pub fn from_code(code: c_int) -> Self {
+        match code as kpep_config_error_code::Type {
+            KPEP_CONFIG_ERROR_INVALID_ARGUMENT => KpepError::InvalidArgument,
+            KPEP_CONFIG_ERROR_OUT_OF_MEMORY => KpepError::OutOfMemory,
+            KPEP_CONFIG_ERROR_IO => KpepError::Io,
+            KPEP_CONFIG_ERROR_BUFFER_TOO_SMALL => KpepError::BufferTooSmall,
+            KPEP_CONFIG_ERROR_CUR_SYSTEM_UNKNOWN => KpepError::CurSystemUnknown,
+            KPEP_CONFIG_ERROR_DB_PATH_INVALID => KpepError::DbPathInvalid,
+            KPEP_CONFIG_ERROR_DB_NOT_FOUND => KpepError::DbNotFound,
+            KPEP_CONFIG_ERROR_DB_ARCH_UNSUPPORTED => KpepError::DbArchUnsupported,
+            KPEP_CONFIG_ERROR_DB_VERSION_UNSUPPORTED => KpepError::DbVersionUnsupported,
+            KPEP_CONFIG_ERROR_DB_CORRUPT => KpepError::DbCorrupt,
+            KPEP_CONFIG_ERROR_EVENT_NOT_FOUND => KpepError::EventNotFound,
+            KPEP_CONFIG_ERROR_CONFLICTING_EVENTS => KpepError::ConflictingEvents,
+            KPEP_CONFIG_ERROR_COUNTERS_NOT_FORCED => KpepError::CountersNotForced,
+            KPEP_CONFIG_ERROR_EVENT_UNAVAILABLE => KpepError::EventUnavailable,
+            KPEP_CONFIG_ERROR_ERRNO => {
+                KpepError::Errno(io::Error::last_os_error().raw_os_error().unwrap_or(0))
+            }
+            _ => KpepError::UnknownError(code),
+        }
+    }
+
+    /// `Ok` if `code` is `KPEP_CONFIG_ERROR_NONE`, the matching error otherwise.
+    pub fn check(code: c_int) -> Result<(), KpepError> {
+        if code == KPEP_CONFIG_ERROR_NONE as c_int {
+            Ok(())
+        } else {
+            Err(Self::from_code(code))
+        }
+    }
+
+    /// The `kpep_config_error_code` of this error.
+    pub fn code(&self) -> c_int {
+        let code = match self {
+            KpepError::InvalidArgument => KPEP_CONFIG_ERROR_INVALID_ARGUMENT,
+            KpepError::OutOfMemory => KPEP_CONFIG_ERROR_OUT_OF_MEMORY,
+            KpepError::Io => KPEP_CONFIG_ERROR_IO,
+            KpepError::BufferTooSmall => KPEP_CONFIG_ERROR_BUFFER_TOO_SMALL,
+            KpepError::CurSystemUnknown => KPEP_CONFIG_ERROR_CUR_SYSTEM_UNKNOWN,
+            KpepError::DbPathInvalid => KPEP_CONFIG_ERROR_DB_PATH_INVALID,
+            KpepError::DbNotFound => KPEP_CONFIG_ERROR_DB_NOT_FOUND,
+            KpepError::DbArchUnsupported => KPEP_CONFIG_ERROR_DB_ARCH_UNSUPPORTED,
+            KpepError::DbVersionUnsupported => KPEP_CONFIG_ERROR_DB_VERSION_UNSUPPORTED,
+            KpepError::DbCorrupt => KPEP_CONFIG_ERROR_DB_CORRUPT,
+            KpepError::EventNotFound => KPEP_CONFIG_ERROR_EVENT_NOT_FOUND,
+            KpepError::ConflictingEvents => KPEP_CONFIG_ERROR_CONFLICTING_EVENTS,
+            KpepError::CountersNotForced => KPEP_CONFIG_ERROR_COUNTERS_NOT_FORCED,
+            KpepError::EventUnavailable => KPEP_CONFIG_ERROR_EVENT_UNAVAILABLE,
+            KpepError::Errno(_) => KPEP_CONFIG_ERROR_ERRNO,
+            KpepError::UnknownError(code) => return *code,
+        };
+        code as c_int
+    }
+}
+
+impl fmt::Display for KpepError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            KpepError::InvalidArgument => write!(f, "invalid argument"),
+            KpepError::OutOfMemory => write!(f, "out of memory"),
+            KpepError::Io => write!(f, "I/O error"),
+            KpepError::BufferTooSmall => write!(f, "buffer too small"),
+            KpepError::CurSystemUnknown => write!(f, "current system unknown"),
+            KpepError::DbPathInvalid => write!(f, "database path invalid"),
+            KpepError::DbNotFound => write!(f, "database not found"),
+            KpepError::DbArchUnsupported => write!(f, "database architecture unsupported"),
+            KpepError::DbVersionUnsupported => write!(f, "database version unsupported"),
+            KpepError::DbCorrupt => write!(f, "database corrupt"),
+            KpepError::EventNotFound => write!(f, "event not found"),
+            KpepError::ConflictingEvents => write!(f, "conflicting events"),
+            KpepError::CountersNotForced => write!(f, "all counters must be forced"),
+            KpepError::EventUnavailable => write!(f, "event unavailable"),
+            KpepError::Errno(errno) => write!(f, "{}", io::Error::from_raw_os_error(*errno)),
+            KpepError::UnknownError(code) => write!(f, "unknown error (code: {})", code),
+        }
+    }
 }
 
+impl Error for KpepError {}
+
 #[derive(Debug, Clone)]
 pub enum KperfError {
     UnknownError(String),
     PermissionDenied,
     PerfCounterBuildError(String),
+    /// No event of the CPU's database matches `event`, by name or alias.
+    EventNotFound {
+        event: String,
+        database: Option<String>,
+    },
+    /// `event` can't be counted together with the `conflicts` already added to the config.
+    ConflictingEvents {
+        event: String,
+        conflicts: Vec<String>,
+    },
+    /// A kpep function failed.
+    Kpep(KpepError),
+    /// A counter read `value`, which doesn't fit in its `bits`, so no delta can be trusted.
+    CounterOutOfRange {
+        value: u64,
+        bits: c_uint,
+    },
+    /// The actions or timers of a sampling session can't be programmed.
+    InvalidSampling(String),
+    /// Trace data that can't be decoded.
+    InvalidTrace(String),
+    /// The kperf frameworks couldn't be loaded at runtime.
+    #[cfg(feature = "dynamic")]
+    Load(LoadError),
+}
+
+impl fmt::Display for KperfError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            KperfError::UnknownError(message) => write!(f, "{}", message),
+            KperfError::PermissionDenied => {
+                write!(f, "permission denied, xnu/kpc requires root privileges")
+            }
+            KperfError::PerfCounterBuildError(message) => {
+                write!(f, "failed to build counter: {}", message)
+            }
+            KperfError::EventNotFound {
+                event,
+                database: Some(database),
+            } => write!(f, "event {} not found in database {}", event, database),
+            KperfError::EventNotFound {
+                event,
+                database: None,
+            } => write!(f, "event {} not found", event),
+            KperfError::ConflictingEvents { event, conflicts } => write!(
+                f,
+                "event {} conflicts with already added events: {}",
+                event,
+                conflicts.join(", ")
+            ),
+            KperfError::Kpep(error) => write!(f, "kpep error: {}", error),
+            KperfError::CounterOutOfRange { value, bits } => {
+                write!(f, "counter value {} doesn't fit in {} bits", value, bits)
+            }
+            KperfError::InvalidSampling(message) => {
+                write!(f, "invalid sampling session: {}", message)
+            }
+            KperfError::InvalidTrace(message) => write!(f, "invalid trace: {}", message),
+            #[cfg(feature = "dynamic")]
+            KperfError::Load(error) => write!(f, "failed to load kperf: {}", error),
+        }
+    }
+}
+
+impl Error for KperfError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            KperfError::Kpep(error) => Some(error),
+            #[cfg(feature = "dynamic")]
+            KperfError::Load(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "dynamic")]
+impl From<LoadError> for KperfError {
+    fn from(error: LoadError) -> Self {
+        KperfError::Load(error)
+    }
+}
+
+impl From<KpepError> for KperfError {
+    fn from(error: KpepError) -> Self {
+        KperfError::Kpep(error)
+    }
+}
+
+impl TryFrom<KperfError> for KpepError {
+    type Error = KperfError;
+
+    /// The underlying kpep error, or `error` itself if it didn't come from kpep.
+    fn try_from(error: KperfError) -> Result<Self, KperfError> {
+        match error {
+            KperfError::Kpep(error) => Ok(error),
+            error => Err(error),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetricError {
+    /// `formula` isn't a valid expression, `position` is the byte offset of the problem.
+    Parse {
+        formula: String,
+        position: usize,
+        message: String,
+    },
+    /// `metric` uses `event`, which isn't tracked.
+    UntrackedEvent { metric: String, event: String },
+}
+
+impl fmt::Display for MetricError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            MetricError::Parse {
+                formula,
+                position,
+                message,
+            } => write!(
+                f,
+                "invalid formula {:?} at {}: {}",
+                formula, position, message
+            ),
+            MetricError::UntrackedEvent { metric, event } => {
+                write!(
+                    f,
+                    "metric {} uses event {}, which isn't tracked",
+                    metric, event
+                )
+            }
+        }
+    }
+}
+
+impl Error for MetricError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_every_code_round_trips() {
+        for code in 1..KPEP_CONFIG_ERROR_MAX as c_int {
+            let error = KpepError::from_code(code);
+            assert!(!matches!(error, KpepError::UnknownError(_)));
+            assert_eq!(error.code(), code);
+        }
+        assert_eq!(KpepError::check(0), Ok(()));
+        assert_eq!(
+            KpepError::from_code(KPEP_CONFIG_ERROR_MAX as c_int),
+            KpepError::UnknownError(KPEP_CONFIG_ERROR_MAX as c_int)
+        );
+    }
+
+    #[test]
+    fn test_conversions() {
+        let error = KperfError::from(KpepError::ConflictingEvents);
+        assert_eq!(error.to_string(), "kpep error: conflicting events");
+        assert!(error.source().is_some());
+        assert_eq!(
+            KpepError::try_from(error).ok(),
+            Some(KpepError::ConflictingEvents)
+        );
+        assert!(KpepError::try_from(KperfError::PermissionDenied).is_err());
+    }
 }
@@ -1,17 +1,25 @@
+use crate::backend::CounterBackend;
 use crate::kperf::KProbesDatabase;
-use kperf_sys::functions::kpep_db_event;
 use kperf_sys::structs::kpep_event;
-use std::ffi::CString;
+use libc::{c_char, c_uint};
+use std::convert::Infallible;
+use std::ffi::{CStr, CString};
 use std::fmt;
 use std::fmt::Formatter;
 use std::ptr::null_mut;
+use std::str::FromStr;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Event {
     Cycles,
     Instructions,
     Branches,
     BranchMisses,
+    TaskClock,
+    ContextSwitches,
+    PageFaults,
+    /// Any event of the current CPU's database, by name (e.g. "L1D_CACHE_MISS_LD") or alias.
+    Named(String),
 }
 
 impl fmt::Display for Event {
@@ -21,23 +29,151 @@ impl fmt::Display for Event {
             Event::Instructions => write!(f, "Instructions"),
             Event::Branches => write!(f, "Branches"),
             Event::BranchMisses => write!(f, "BranchMisses"),
+            Event::TaskClock => write!(f, "TaskClock"),
+            Event::ContextSwitches => write!(f, "ContextSwitches"),
+            Event::PageFaults => write!(f, "PageFaults"),
+            Event::Named(name) => write!(f, "{}", name),
         }
     }
 }
 
-pub fn get_event_names(event_type: Event) -> Vec<CString> {
+impl Event {
+    /// Software event counted in place of this one when the CPU's database doesn't have it, as
+    /// in VMs hiding the PMU, see [`PerfCounterBuilder::allow_software_fallback`]. Only cycles
+    /// have one: the time the task ran, in nanoseconds.
+    ///
+    /// [`PerfCounterBuilder::allow_software_fallback`]: crate::PerfCounterBuilder::allow_software_fallback
+    pub fn software_substitute(&self) -> Option<Event> {
+        match self {
+            Event::Cycles => Some(Event::TaskClock),
+            _ => None,
+        }
+    }
+}
+
+/// Privilege levels an event is counted in.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub enum CountingMode {
+    /// User space and kernel.
+    #[default]
+    All,
+    /// User space only.
+    UserOnly,
+}
+
+impl CountingMode {
+    /// `flag` argument of `kpep_config_add_event`.
+    pub fn kpep_flag(&self) -> c_uint {
+        match self {
+            CountingMode::All => 0,
+            CountingMode::UserOnly => 1,
+        }
+    }
+}
+
+impl fmt::Display for CountingMode {
+    /// perf style modifier, ":uk" or ":u".
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CountingMode::All => write!(f, ":uk"),
+            CountingMode::UserOnly => write!(f, ":u"),
+        }
+    }
+}
+
+impl FromStr for Event {
+    type Err = Infallible;
+
+    /// Inverse of `Display`: the name of a variant, or any other event name as [`Event::Named`].
+    fn from_str(name: &str) -> Result<Self, Infallible> {
+        Ok(match name {
+            "Cycles" => Event::Cycles,
+            "Instructions" => Event::Instructions,
+            "Branches" => Event::Branches,
+            "BranchMisses" => Event::BranchMisses,
+            "TaskClock" => Event::TaskClock,
+            "ContextSwitches" => Event::ContextSwitches,
+            "PageFaults" => Event::PageFaults,
+            name => Event::Named(name.to_string()),
+        })
+    }
+}
+
+/// Description of one event of a [`KProbesDatabase`], as listed by [`KProbesDatabase::events`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EventInfo {
+    /// Unique name of the event, such as "INST_RETIRED.ANY".
+    pub name: String,
+    /// Alias name, such as "Instructions" or "Cycles".
+    pub alias: Option<String>,
+    pub description: Option<String>,
+    /// Event used in place of this one when its fixed counter is busy.
+    pub fallback: Option<String>,
+    pub is_fixed: bool,
+    pub mask: c_uint,
+    pub number: u8,
+    pub umask: u8,
+}
+
+impl EventInfo {
+    /// # Safety
+    /// `event` must point to a valid `kpep_event` whose strings are NUL terminated or null.
+    pub(crate) unsafe fn from_raw(event: *const kpep_event) -> Self {
+        let event = &*event;
+        Self {
+            name: to_string(event.name).unwrap_or_default(),
+            alias: to_string(event.alias),
+            description: to_string(event.description),
+            fallback: to_string(event.fallback),
+            is_fixed: event.is_fixed != 0,
+            mask: event.mask,
+            number: event.number,
+            umask: event.umask,
+        }
+    }
+
+    /// The [`Event`] to track to count this event.
+    pub fn event(&self) -> Event {
+        Event::Named(self.name.clone())
+    }
+}
+
+impl fmt::Display for EventInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(alias) = &self.alias {
+            write!(f, " ({})", alias)?;
+        }
+        if let Some(description) = &self.description {
+            write!(f, ": {}", description)?;
+        }
+        Ok(())
+    }
+}
+
+unsafe fn to_string(ptr: *const c_char) -> Option<String> {
+    if ptr.is_null() {
+        return None;
+    }
+    let value = CStr::from_ptr(ptr).to_string_lossy();
+    (!value.is_empty()).then(|| value.into_owned())
+}
+
+pub fn get_event_names(event_type: &Event) -> Vec<CString> {
     match event_type {
         Event::Cycles => {
             vec![
                 CString::new("FIXED_CYCLES").unwrap(), // Apple A7-A15
                 CString::new("CPU_CLK_UNHALTED.THREAD").unwrap(), // Intel Core 1th-10th
                 CString::new("CPU_CLK_UNHALTED.CORE").unwrap(), // Intel Yonah, Merom
+                CString::new("cpu-cycles").unwrap(),   // Linux perf_event
             ]
         }
         Event::Instructions => {
             vec![
                 CString::new("FIXED_INSTRUCTIONS").unwrap(), // Apple A7-A15
                 CString::new("INST_RETIRED.ANY").unwrap(),   // Intel Yonah, Merom, Core 1th-10th
+                CString::new("instructions").unwrap(),       // Linux perf_event
             ]
         }
         Event::Branches => {
@@ -45,6 +181,7 @@ pub fn get_event_names(event_type: Event) -> Vec<CString> {
                 CString::new("INST_BRANCH").unwrap(), // Apple A7-A15
                 CString::new("BR_INST_RETIRED.ALL_BRANCHES").unwrap(), // Intel Core 1th-10th
                 CString::new("INST_RETIRED.ANY").unwrap(), // Intel Yonah, Merom
+                CString::new("branch-instructions").unwrap(), // Linux perf_event
             ]
         }
         Event::BranchMisses => {
@@ -53,20 +190,45 @@ pub fn get_event_names(event_type: Event) -> Vec<CString> {
                 CString::new("BRANCH_MISPREDICT").unwrap(),      // Apple A7-A14
                 CString::new("BR_MISP_RETIRED.ALL_BRANCHES").unwrap(), // Intel Core 2th-10th
                 CString::new("BR_INST_RETIRED.MISPRED").unwrap(), // Intel Yonah, Merom
+                CString::new("branch-misses").unwrap(),          // Linux perf_event
             ]
         }
+        // Software events, only provided by the Linux perf_event backend
+        Event::TaskClock => vec![CString::new("task-clock").unwrap()],
+        Event::ContextSwitches => vec![CString::new("context-switches").unwrap()],
+        Event::PageFaults => vec![CString::new("page-faults").unwrap()],
+        Event::Named(name) => CString::new(name.as_str()).into_iter().collect(),
     }
 }
 
-pub fn get_event(event_type: Event, db: &KProbesDatabase) -> Option<*mut kpep_event> {
+pub fn get_event<B: CounterBackend>(
+    event_type: &Event,
+    db: &KProbesDatabase<B>,
+) -> Option<*mut kpep_event> {
     let names = get_event_names(event_type);
-    for name in names {
+    for name in &names {
         unsafe {
             let mut ev: *mut kpep_event = null_mut();
-            if kpep_db_event(db.database, name.as_ptr(), &mut ev) == 0 {
+            if db
+                .backend()
+                .kpep_db_event(db.database, name.as_ptr(), &mut ev)
+                == 0
+            {
                 return Some(ev);
             }
         }
     }
-    return None;
+
+    // Fall back on the aliases, such as "Cycles" for "FIXED_CYCLES"
+    let events = db.event_pointers().ok()?;
+    for name in &names {
+        for &ev in &events {
+            unsafe {
+                if !(*ev).alias.is_null() && CStr::from_ptr((*ev).alias) == name.as_c_str() {
+                    return Some(ev);
+                }
+            }
+        }
+    }
+    None
 }
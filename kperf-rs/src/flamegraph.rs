@@ -0,0 +1,570 @@
+use crate::kdebug::{Callstack, Sample};
+use crate::kperf::counter_delta;
+use crate::symbolicate::Symbolicator;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fmt::{Formatter, Write};
+use std::os::raw::c_uint;
+
+/// How much a sample weighs in [`FoldedStacks`].
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Weight {
+    /// Every sample counts as one.
+    #[default]
+    Samples,
+    /// What the thread counter at this index of [`Sample::thread_counters`], this many bits wide,
+    /// counted since the previous sample of the same thread. The first sample of a thread weighs
+    /// nothing.
+    ///
+    /// The width, which [`crate::kperf::KProbesDatabase::counter_bits`] tells, lets a sample
+    /// taken after the counter wrapped around weigh what it counted.
+    ThreadCounter(usize, c_uint),
+    /// What the CPU counter at this index of [`Sample::cpu_counters`], this many bits wide,
+    /// counted since the previous sample on the same CPU. The first sample of a CPU weighs
+    /// nothing.
+    CpuCounter(usize, c_uint),
+}
+
+/// Weights of the distinct stacks of a profile, in the folded format of Brendan Gregg's
+/// FlameGraph tools: one `root;caller;callee weight` line per stack.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FoldedStacks {
+    /// Frames of each stack, root first.
+    stacks: BTreeMap<Vec<String>, u64>,
+}
+
+impl FoldedStacks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `weight` to the stack of `frames`, root first.
+    pub fn add<S: AsRef<str>>(&mut self, frames: &[S], weight: u64) {
+        if frames.is_empty() || weight == 0 {
+            return;
+        }
+        let frames = frames
+            .iter()
+            .map(|frame| sanitize(frame.as_ref()))
+            .collect();
+        *self.stacks.entry(frames).or_default() += weight;
+    }
+
+    /// Fold the user and kernel stacks of `samples`, the kernel frames on top of the user ones.
+    ///
+    /// Frames are named by `symbolicator`, inlined functions included, and by their address when
+    /// it doesn't know them. Samples without stacks are skipped.
+    pub fn from_samples(
+        samples: &[Sample],
+        weight: Weight,
+        symbolicator: Option<&Symbolicator>,
+    ) -> Self {
+        let mut folded = Self::new();
+        let mut previous: HashMap<u64, u64> = HashMap::new();
+        for sample in samples {
+            let weight = match weight {
+                Weight::Samples => 1,
+                Weight::ThreadCounter(idx, bits) => {
+                    let thread = sample
+                        .thread_info
+                        .map_or(sample.thread, |thread_info| thread_info.tid);
+                    since_previous(&mut previous, thread, sample.thread_counters.get(idx), bits)
+                }
+                Weight::CpuCounter(idx, bits) => since_previous(
+                    &mut previous,
+                    sample.cpu as u64,
+                    sample.cpu_counters.get(idx),
+                    bits,
+                ),
+            };
+            let mut frames = Vec::new();
+            for stack in [&sample.user_stack, &sample.kernel_stack]
+                .into_iter()
+                .flatten()
+            {
+                frames.extend(stack_frames(stack, symbolicator));
+            }
+            folded.add(&frames, weight);
+        }
+        folded
+    }
+
+    /// Stacks, root first, and their weights, sorted by frames.
+    pub fn iter(&self) -> impl Iterator<Item = (&[String], u64)> {
+        self.stacks
+            .iter()
+            .map(|(frames, weight)| (frames.as_slice(), *weight))
+    }
+
+    pub fn len(&self) -> usize {
+        self.stacks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stacks.is_empty()
+    }
+
+    /// Sum of the weights of every stack.
+    pub fn total(&self) -> u64 {
+        self.stacks.values().sum()
+    }
+}
+
+impl fmt::Display for FoldedStacks {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (frames, weight) in &self.stacks {
+            writeln!(f, "{} {}", frames.join(";"), weight)?;
+        }
+        Ok(())
+    }
+}
+
+/// What the `bits` wide `counter` counted since the previous value of `key`, which it replaces.
+///
+/// A value that doesn't fit in `bits` weighs nothing, it only gives the next one a baseline.
+fn since_previous(
+    previous: &mut HashMap<u64, u64>,
+    key: u64,
+    counter: Option<&u64>,
+    bits: c_uint,
+) -> u64 {
+    let Some(&counter) = counter else {
+        return 0;
+    };
+    previous
+        .insert(key, counter)
+        .and_then(|previous| counter_delta(previous, counter, bits).ok())
+        .map_or(0, |(delta, _)| delta)
+}
+
+/// Names of the frames of `stack`, root first.
+fn stack_frames(stack: &Callstack, symbolicator: Option<&Symbolicator>) -> Vec<String> {
+    let Some(symbolicator) = symbolicator else {
+        return stack
+            .frames
+            .iter()
+            .rev()
+            .map(|&address| hex(address))
+            .collect();
+    };
+    let mut frames = Vec::new();
+    for address in symbolicator.symbolicate_stack(stack).iter().rev() {
+        if address.frames.is_empty() {
+            frames.push(hex(address.address));
+        }
+        // Inlined functions come first, so the function they are inlined in ends up below them
+        for frame in address.frames.iter().rev() {
+            frames.push(
+                frame
+                    .function
+                    .clone()
+                    .unwrap_or_else(|| hex(address.address)),
+            );
+        }
+    }
+    frames
+}
+
+fn hex(address: u64) -> String {
+    format!("{:#x}", address)
+}
+
+/// Frame name that can't break a folded line.
+fn sanitize(frame: &str) -> String {
+    frame.replace(';', ":").replace(['\n', '\r'], " ")
+}
+
+/// Renders [`FoldedStacks`] to an SVG flame graph: the stacks grow upward from the root, and
+/// the width of every frame is proportional to the weight of the stacks going through it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlameGraph {
+    pub title: String,
+    /// What the weights count, e.g. "samples" or "cycles".
+    pub unit: String,
+    /// Width of the image, in pixels.
+    pub width: u32,
+    pub frame_height: u32,
+    pub font_size: u32,
+    /// Frames narrower than this many pixels aren't drawn.
+    pub min_width: f64,
+}
+
+impl Default for FlameGraph {
+    fn default() -> Self {
+        Self {
+            title: "Flame Graph".to_string(),
+            unit: "samples".to_string(),
+            width: 1200,
+            frame_height: 16,
+            font_size: 12,
+            min_width: 0.1,
+        }
+    }
+}
+
+/// Frame of the flame graph, with the weight of every stack going through it.
+#[derive(Default)]
+struct Node {
+    weight: u64,
+    children: BTreeMap<String, Node>,
+}
+
+impl Node {
+    fn depth(&self) -> usize {
+        self.children
+            .values()
+            .map(|child| child.depth() + 1)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+const PADDING: f64 = 10.;
+/// Room for the title above the frames.
+const TITLE_HEIGHT: f64 = 40.;
+/// Width of a character, relative to the font size.
+const CHAR_WIDTH: f64 = 0.59;
+
+impl FlameGraph {
+    pub fn render(&self, stacks: &FoldedStacks) -> String {
+        let mut root = Node::default();
+        for (frames, weight) in stacks.iter() {
+            root.weight += weight;
+            let mut node = &mut root;
+            for frame in frames {
+                node = node.children.entry(frame.clone()).or_default();
+                node.weight += weight;
+            }
+        }
+        let frame_height = self.frame_height as f64;
+        let height = TITLE_HEIGHT + (root.depth() + 1) as f64 * frame_height + PADDING;
+        let mut svg = String::new();
+        // Writing to a String can't fail
+        let _ = self.write_svg(&mut svg, &root, height);
+        svg
+    }
+
+    fn write_svg(&self, svg: &mut String, root: &Node, height: f64) -> fmt::Result {
+        writeln!(svg, r#"<?xml version="1.0" standalone="no"?>"#)?;
+        writeln!(
+            svg,
+            r#"<svg version="1.1" width="{}" height="{}" viewBox="0 0 {} {}" xmlns="http://www.w3.org/2000/svg">"#,
+            self.width, height, self.width, height
+        )?;
+        writeln!(
+            svg,
+            r#"<rect x="0" y="0" width="100%" height="100%" fill="rgb(250,250,250)"/>"#
+        )?;
+        writeln!(
+            svg,
+            r#"<text x="{}" y="{}" font-family="Verdana" font-size="{}" text-anchor="middle">{}</text>"#,
+            self.width as f64 / 2.,
+            TITLE_HEIGHT / 2.,
+            self.font_size + 5,
+            escape(&self.title)
+        )?;
+        if root.weight == 0 {
+            writeln!(
+                svg,
+                r#"<text x="{}" y="{}" font-family="Verdana" font-size="{}" text-anchor="middle">No stacks</text>"#,
+                self.width as f64 / 2.,
+                height / 2.,
+                self.font_size
+            )?;
+        } else {
+            let scale = (self.width as f64 - 2. * PADDING) / root.weight as f64;
+            self.write_frame(svg, "all", root, root.weight, PADDING, 0, height, scale)?;
+        }
+        writeln!(svg, "</svg>")
+    }
+
+    /// Write the frame of `node` at `x` pixels and `depth` frames above the bottom, then the
+    /// frames of its children on top of it.
+    #[allow(clippy::too_many_arguments)]
+    fn write_frame(
+        &self,
+        svg: &mut String,
+        name: &str,
+        node: &Node,
+        total: u64,
+        x: f64,
+        depth: usize,
+        height: f64,
+        scale: f64,
+    ) -> fmt::Result {
+        let width = node.weight as f64 * scale;
+        if width < self.min_width {
+            return Ok(());
+        }
+        let frame_height = self.frame_height as f64;
+        let y = height - PADDING - (depth + 1) as f64 * frame_height;
+        let (r, g, b) = color(name);
+        writeln!(svg, "<g>")?;
+        writeln!(
+            svg,
+            "<title>{} ({} {}, {:.2}%)</title>",
+            escape(name),
+            node.weight,
+            escape(&self.unit),
+            node.weight as f64 * 100. / total as f64
+        )?;
+        writeln!(
+            svg,
+            r#"<rect x="{:.2}" y="{:.2}" width="{:.2}" height="{:.2}" fill="rgb({},{},{})" rx="2" ry="2"/>"#,
+            x,
+            y,
+            width,
+            frame_height - 1.,
+            r,
+            g,
+            b
+        )?;
+        let label = fit_label(name, width, self.font_size as f64);
+        if !label.is_empty() {
+            writeln!(
+                svg,
+                r#"<text x="{:.2}" y="{:.2}" font-family="Verdana" font-size="{}">{}</text>"#,
+                x + 3.,
+                y + frame_height - 4.,
+                self.font_size,
+                escape(&label)
+            )?;
+        }
+        writeln!(svg, "</g>")?;
+        let mut child_x = x;
+        for (child_name, child) in &node.children {
+            self.write_frame(
+                svg,
+                child_name,
+                child,
+                total,
+                child_x,
+                depth + 1,
+                height,
+                scale,
+            )?;
+            child_x += child.weight as f64 * scale;
+        }
+        Ok(())
+    }
+}
+
+/// `name`, cut with ".." to fit in `width` pixels, empty if not even 3 characters fit.
+fn fit_label(name: &str, width: f64, font_size: f64) -> String {
+    let chars = ((width - 6.) / (font_size * CHAR_WIDTH)).max(0.) as usize;
+    if chars < 3 {
+        return String::new();
+    }
+    if name.chars().count() <= chars {
+        return name.to_string();
+    }
+    let mut label: String = name.chars().take(chars - 2).collect();
+    label.push_str("..");
+    label
+}
+
+/// Warm color of a frame, the same for every frame of the function.
+fn color(name: &str) -> (u8, u8, u8) {
+    // FNV-1a, stable across runs unlike the std hasher
+    let hash = name.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3)
+    });
+    let fraction = |shift: u32| ((hash >> shift) & 0xff) as f64 / 255.;
+    (
+        (205. + 50. * fraction(0)) as u8,
+        (230. * fraction(8)) as u8,
+        (55. * fraction(16)) as u8,
+    )
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kdebug::ThreadInfo;
+    use crate::sampling::{ActionId, Samplers};
+
+    /// Sample of `thread` in the user stack of `frames`, innermost first, having counted
+    /// `cycles` so far.
+    fn sample(thread: u64, frames: &[u64], cycles: u64) -> Sample {
+        Sample {
+            timestamp: 0,
+            cpu: 0,
+            thread: 0x1000 + thread,
+            action: ActionId(1),
+            samplers: Samplers::USER_STACK | Samplers::PMC_THREAD,
+            thread_info: Some(ThreadInfo {
+                pid: 1,
+                tid: thread,
+                dispatch_queue: 0,
+                run_mode: 1,
+            }),
+            scheduling: None,
+            kernel_stack: None,
+            user_stack: Some(Callstack {
+                flags: 0,
+                frames: frames.to_vec(),
+            }),
+            cpu_counters: Vec::new(),
+            thread_counters: vec![0, cycles],
+        }
+    }
+
+    fn samples() -> Vec<Sample> {
+        vec![
+            sample(1, &[0x30, 0x20, 0x10], 100),
+            sample(2, &[0x40, 0x10], 1000),
+            sample(1, &[0x30, 0x20, 0x10], 150),
+            sample(1, &[0x20, 0x10], 400),
+            sample(2, &[0x40, 0x10], 1500),
+        ]
+    }
+
+    #[test]
+    fn test_fold_samples() {
+        let folded = FoldedStacks::from_samples(&samples(), Weight::Samples, None);
+        assert_eq!(folded.total(), 5);
+        assert_eq!(
+            folded.to_string(),
+            "0x10;0x20 1\n0x10;0x20;0x30 2\n0x10;0x40 2\n"
+        );
+
+        // The first samples of each thread only give the counters a baseline
+        let cycles = FoldedStacks::from_samples(&samples(), Weight::ThreadCounter(1, 48), None);
+        assert_eq!(
+            cycles.to_string(),
+            "0x10;0x20 250\n0x10;0x20;0x30 50\n0x10;0x40 500\n"
+        );
+        let none = FoldedStacks::from_samples(&samples(), Weight::CpuCounter(0, 48), None);
+        assert!(none.is_empty());
+    }
+
+    #[test]
+    fn test_counter_wraparound() {
+        let samples = [
+            sample(1, &[0x20, 0x10], (1 << 48) - 100),
+            sample(1, &[0x30, 0x10], 50),
+            // Out of the range of the counter, only gives a new baseline
+            sample(1, &[0x40, 0x10], 1 << 48),
+            sample(1, &[0x50, 0x10], 60),
+        ];
+        let cycles = FoldedStacks::from_samples(&samples, Weight::ThreadCounter(1, 48), None);
+        assert_eq!(
+            cycles.to_string(),
+            "0x10;0x30 150
+"
+        );
+        // A wider counter didn't wrap, it went back
+        let cycles = FoldedStacks::from_samples(&samples[..2], Weight::ThreadCounter(1, 64), None);
+        assert_eq!(cycles.total(), u64::MAX - (1 << 48) + 151);
+    }
+
+    #[test]
+    fn test_thread_without_info() {
+        let folded = "0x10;0x20 250\n0x10;0x20;0x30 50\n0x10;0x40 500\n";
+        // The thread id of the sampled thread is preferred to the thread of the records
+        let mut same_thread = samples();
+        for sample in &mut same_thread {
+            sample.thread = 1;
+        }
+        let cycles = FoldedStacks::from_samples(&same_thread, Weight::ThreadCounter(1, 48), None);
+        assert_eq!(cycles.to_string(), folded);
+
+        // Without thread info, samples are told apart by the thread of their records
+        let mut samples = samples();
+        for sample in &mut samples {
+            sample.thread_info = None;
+        }
+        let cycles = FoldedStacks::from_samples(&samples, Weight::ThreadCounter(1, 48), None);
+        assert_eq!(cycles.to_string(), folded);
+        for sample in &mut samples {
+            sample.thread = 1;
+        }
+        let cycles = FoldedStacks::from_samples(&samples, Weight::ThreadCounter(1, 48), None);
+        assert_ne!(cycles.to_string(), folded);
+    }
+
+    #[test]
+    fn test_kernel_frames_on_top() {
+        let mut sample = sample(1, &[0x20, 0x10], 0);
+        sample.kernel_stack = Some(Callstack {
+            flags: 0,
+            frames: vec![0xffff_0002, 0xffff_0001],
+        });
+        let folded = FoldedStacks::from_samples(&[sample], Weight::Samples, None);
+        assert_eq!(folded.to_string(), "0x10;0x20;0xffff0001;0xffff0002 1\n");
+    }
+
+    #[test]
+    fn test_symbolicated_frames() {
+        let mut symbolicator = Symbolicator::new();
+        symbolicator
+            .add_image("fixtures/symbols/fixture.elf", 0x40_0000)
+            .unwrap();
+        // leaf, called by helper inlined in compute, called by _start, see fixture.rs
+        let samples = [sample(1, &[0x40_1033, 0x40_1013, 0x40_102b, 0x10], 0)];
+        let folded = FoldedStacks::from_samples(&samples, Weight::Samples, Some(&symbolicator));
+        assert_eq!(
+            folded.to_string(),
+            "0x10;_start;fixture::compute;fixture::helper;leaf 1\n"
+        );
+    }
+
+    #[test]
+    fn test_add() {
+        let mut folded = FoldedStacks::new();
+        folded.add(&["main", "parse;lex\nnext"], 3);
+        folded.add(&["main", "parse;lex\nnext"], 2);
+        folded.add(&["main"], 0);
+        folded.add::<&str>(&[], 7);
+        assert_eq!(folded.len(), 1);
+        assert_eq!(folded.to_string(), "main;parse:lex next 5\n");
+    }
+
+    #[test]
+    fn test_render_svg() {
+        let mut folded = FoldedStacks::new();
+        folded.add(&["main", "compute"], 3);
+        folded.add(&["main", "Vec<T>::push"], 1);
+        folded.add(&["main", "compute", "a_function_with_a_long_name"], 1);
+        let graph = FlameGraph {
+            title: "cycles & more".to_string(),
+            unit: "cycles".to_string(),
+            width: 420,
+            ..FlameGraph::default()
+        };
+        let svg = graph.render(&folded);
+        assert!(svg.contains(r#"width="420" height="114""#));
+        assert!(svg.contains(">cycles &amp; more</text>"));
+        assert!(svg.contains("<title>all (5 cycles, 100.00%)</title>"));
+        assert!(svg.contains("<title>compute (4 cycles, 80.00%)</title>"));
+        assert!(svg.contains("<title>Vec&lt;T&gt;::push (1 cycles, 20.00%)</title>"));
+        assert_eq!(svg.matches("<rect").count(), 6);
+        // The deepest frame is too narrow for its whole name
+        assert!(svg.contains(">a_functi..</text>"));
+        // Children are drawn left to right, alphabetically
+        assert!(svg.contains(r#"<rect x="10.00" y="56.00" width="80.00""#));
+        assert!(svg.contains(r#"<rect x="90.00" y="56.00" width="320.00""#));
+        assert!(svg.ends_with("</svg>\n"));
+
+        let narrow = FlameGraph {
+            min_width: 100.,
+            ..graph.clone()
+        };
+        assert_eq!(narrow.render(&folded).matches("<rect").count(), 4);
+        assert!(graph.render(&FoldedStacks::new()).contains("No stacks"));
+    }
+}
@@ -0,0 +1,546 @@
+use crate::error::KperfError;
+use crate::sampling::{ActionId, Samplers};
+use kperf_sys::constants::{
+    CALLSTACK_64BIT, CALLSTACK_KERNEL, CALLSTACK_TRUNCATED, CALLSTACK_VALID, DBG_FUNC_END,
+    DBG_FUNC_MASK, DBG_FUNC_START, DBG_PERF, PERF_CALLSTACK, PERF_CS_KDATA, PERF_CS_KHDR,
+    PERF_CS_UDATA, PERF_CS_UHDR, PERF_GENERIC, PERF_GEN_EVENT, PERF_KPC, PERF_KPC_CONFIG,
+    PERF_KPC_DATA, PERF_KPC_DATA_THREAD, PERF_THREADINFO, PERF_TI_DATA, PERF_TI_SCHEDDATA,
+    PERF_TI_SCHEDDATA_2,
+};
+use kperf_sys::structs::kd_buf;
+use std::collections::HashMap;
+
+/// Size of a `kd_buf` record in the trace buffer of a 64 bit kernel.
+pub const KD_BUF_SIZE: usize = 64;
+
+/// Class, subclass, code and function of a kdebug record.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct DebugId(pub u32);
+
+impl DebugId {
+    pub const fn new(class: u32, subclass: u32, code: u32, func: u32) -> Self {
+        Self(
+            (class & 0xff) << 24
+                | (subclass & 0xff) << 16
+                | (code & 0x3fff) << 2
+                | (func & DBG_FUNC_MASK),
+        )
+    }
+
+    pub const fn class(&self) -> u32 {
+        self.0 >> 24
+    }
+
+    pub const fn subclass(&self) -> u32 {
+        (self.0 >> 16) & 0xff
+    }
+
+    pub const fn code(&self) -> u32 {
+        (self.0 >> 2) & 0x3fff
+    }
+
+    /// `DBG_FUNC_START`, `DBG_FUNC_END` or `DBG_FUNC_NONE`.
+    pub const fn func(&self) -> u32 {
+        self.0 & DBG_FUNC_MASK
+    }
+
+    /// Whether the record was emitted by kperf.
+    pub const fn is_kperf(&self) -> bool {
+        self.class() == DBG_PERF
+    }
+
+    /// Whether this is the `code` of `subclass` of kperf, whatever its function.
+    const fn is(&self, subclass: u32, code: u32) -> bool {
+        self.is_kperf() && self.subclass() == subclass && self.code() == code
+    }
+}
+
+/// Record of the kdebug trace buffer, see [`kd_buf`] and `kd_buf` in xnu's
+/// `bsd/sys/kdebug_private.h`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct KdRecord {
+    /// Time of the record, in ticks of the system timebase.
+    pub timestamp: u64,
+    pub args: [u64; 4],
+    /// Thread that emitted the record.
+    pub thread: u64,
+    pub debugid: DebugId,
+    pub cpu: u32,
+}
+
+impl KdRecord {
+    /// Record of a little endian `kd_buf` from a 64 bit kernel.
+    pub fn from_bytes(bytes: &[u8; KD_BUF_SIZE]) -> Self {
+        let word =
+            |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        let half =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        Self {
+            timestamp: word(0),
+            args: [word(8), word(16), word(24), word(32)],
+            thread: word(40),
+            debugid: DebugId(half(48)),
+            cpu: half(52),
+        }
+    }
+
+    /// Inverse of [`KdRecord::from_bytes`], with the unused trailing word zeroed.
+    pub fn to_bytes(&self) -> [u8; KD_BUF_SIZE] {
+        let mut bytes = [0; KD_BUF_SIZE];
+        let words = [
+            self.timestamp,
+            self.args[0],
+            self.args[1],
+            self.args[2],
+            self.args[3],
+        ];
+        for (idx, word) in words.into_iter().chain([self.thread]).enumerate() {
+            bytes[idx * 8..idx * 8 + 8].copy_from_slice(&word.to_le_bytes());
+        }
+        bytes[48..52].copy_from_slice(&self.debugid.0.to_le_bytes());
+        bytes[52..56].copy_from_slice(&self.cpu.to_le_bytes());
+        bytes
+    }
+
+    /// Records of a buffer of `kd_buf`, which must be a whole number of them.
+    pub fn parse_all(bytes: &[u8]) -> Result<Vec<Self>, KperfError> {
+        if !bytes.len().is_multiple_of(KD_BUF_SIZE) {
+            return Err(KperfError::InvalidTrace(format!(
+                "{} bytes isn't a whole number of {} byte records",
+                bytes.len(),
+                KD_BUF_SIZE
+            )));
+        }
+        Ok(bytes
+            .chunks_exact(KD_BUF_SIZE)
+            .map(|chunk| Self::from_bytes(chunk.try_into().unwrap()))
+            .collect())
+    }
+}
+
+impl From<kd_buf> for KdRecord {
+    fn from(buf: kd_buf) -> Self {
+        Self {
+            timestamp: buf.timestamp,
+            args: [
+                buf.arg1 as u64,
+                buf.arg2 as u64,
+                buf.arg3 as u64,
+                buf.arg4 as u64,
+            ],
+            thread: buf.arg5 as u64,
+            debugid: DebugId(buf.debugid),
+            cpu: buf.cpuid,
+        }
+    }
+}
+
+/// What `KPERF_SAMPLER_TH_INFO` records of the sampled thread.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ThreadInfo {
+    pub pid: i32,
+    pub tid: u64,
+    /// Address of the dispatch queue the thread serves, 0 if none.
+    pub dispatch_queue: u64,
+    /// `KPERF_TI_*` run state flags, bit 0 is set if the thread was running.
+    pub run_mode: u32,
+}
+
+impl ThreadInfo {
+    pub fn is_running(&self) -> bool {
+        self.run_mode & 1 != 0
+    }
+}
+
+/// What `KPERF_SAMPLER_TH_SCHEDULING` records of the sampled thread.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ThreadScheduling {
+    /// Time spent in user space, in ticks.
+    pub user_time: u64,
+    /// Time spent in the kernel, in ticks.
+    pub system_time: u64,
+    pub base_priority: u16,
+    pub sched_priority: u16,
+    /// `TH_*` scheduler state flags.
+    pub state: u8,
+    pub effective_qos: u8,
+    pub requested_qos: u8,
+    pub requested_qos_override: u8,
+}
+
+impl ThreadScheduling {
+    /// Scheduling info of the arguments of a `PERF_TI_SCHEDDATA` record.
+    fn from_args(args: [u64; 4]) -> Self {
+        let packed = args[2];
+        Self {
+            user_time: args[0],
+            system_time: args[1],
+            base_priority: (packed >> 48) as u16,
+            sched_priority: (packed >> 32) as u16,
+            state: (packed >> 24) as u8,
+            effective_qos: ((packed >> 6) & 0x7) as u8,
+            requested_qos: ((packed >> 3) & 0x7) as u8,
+            requested_qos_override: (packed & 0x7) as u8,
+        }
+    }
+}
+
+/// Callstack of the sampled thread, innermost frame first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Callstack {
+    /// `CALLSTACK_*` flags.
+    pub flags: u32,
+    pub frames: Vec<u64>,
+}
+
+impl Callstack {
+    /// Whether kperf could walk the stack, the frames are meaningless otherwise.
+    pub fn is_valid(&self) -> bool {
+        self.flags & CALLSTACK_VALID != 0
+    }
+
+    /// Whether the stack was deeper than kperf could record.
+    pub fn is_truncated(&self) -> bool {
+        self.flags & CALLSTACK_TRUNCATED != 0
+    }
+
+    pub fn is_kernel(&self) -> bool {
+        self.flags & CALLSTACK_KERNEL != 0
+    }
+
+    pub fn is_64bit(&self) -> bool {
+        self.flags & CALLSTACK_64BIT != 0
+    }
+}
+
+/// Everything kperf recorded when an action fired on a thread.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sample {
+    /// Time the sample started, in ticks of the system timebase.
+    pub timestamp: u64,
+    pub cpu: u32,
+    /// Thread that took the sample. With PET, it samples other threads, see `thread_info`.
+    pub thread: u64,
+    pub action: ActionId,
+    /// What the action recorded.
+    pub samplers: Samplers,
+    pub thread_info: Option<ThreadInfo>,
+    pub scheduling: Option<ThreadScheduling>,
+    pub kernel_stack: Option<Callstack>,
+    pub user_stack: Option<Callstack>,
+    /// Counters of the CPU, in the order of the counters configured.
+    pub cpu_counters: Vec<u64>,
+    /// Counters of the sampled thread, in the order of the counters configured.
+    pub thread_counters: Vec<u64>,
+}
+
+impl Sample {
+    fn new(record: &KdRecord) -> Self {
+        Self {
+            timestamp: record.timestamp,
+            cpu: record.cpu,
+            thread: record.thread,
+            samplers: Samplers::from_bits_truncate(record.args[0] as u32),
+            action: ActionId(record.args[1] as u32),
+            thread_info: None,
+            scheduling: None,
+            kernel_stack: None,
+            user_stack: None,
+            cpu_counters: Vec::new(),
+            thread_counters: Vec::new(),
+        }
+    }
+}
+
+/// Sample being rebuilt from the records of a CPU.
+struct PendingSample {
+    sample: Sample,
+    /// Frames left to read into the kernel and user stacks.
+    kernel_frames: usize,
+    user_frames: usize,
+    /// Number of counters of the `PERF_KPC_CONFIG` record, the data records pad them to 4.
+    counter_count: Option<usize>,
+}
+
+/// Rebuilds [`Sample`]s from the records kperf emits into the kdebug buffer.
+///
+/// kperf emits the records of a sample on a single CPU, between the start and end of a
+/// `PERF_GEN_EVENT` (`kperf_sample_internal` in xnu's `osfmk/kperf/action.c`), so samples of
+/// different CPUs may interleave. Records that aren't kperf's are ignored, and so are samples
+/// the trace doesn't hold the start or end of. User stacks deferred to the return to user
+/// space are emitted after the end of their sample, and are not decoded.
+#[derive(Default)]
+pub struct SampleDecoder {
+    pending: HashMap<u32, PendingSample>,
+}
+
+impl SampleDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decode the next `record`, returning the sample it completes, if any.
+    pub fn push(&mut self, record: &KdRecord) -> Option<Sample> {
+        let id = record.debugid;
+        if !id.is_kperf() {
+            return None;
+        }
+        if id.is(PERF_GENERIC, PERF_GEN_EVENT) {
+            return match id.func() {
+                DBG_FUNC_START => {
+                    // A sample started while another was pending lost its end, drop it
+                    self.pending.insert(
+                        record.cpu,
+                        PendingSample {
+                            sample: Sample::new(record),
+                            kernel_frames: 0,
+                            user_frames: 0,
+                            counter_count: None,
+                        },
+                    );
+                    None
+                }
+                DBG_FUNC_END => {
+                    let mut pending = self.pending.remove(&record.cpu)?;
+                    if let Some(count) = pending.counter_count {
+                        pending.sample.cpu_counters.truncate(count);
+                        pending.sample.thread_counters.truncate(count);
+                    }
+                    Some(pending.sample)
+                }
+                _ => None,
+            };
+        }
+        let pending = self.pending.get_mut(&record.cpu)?;
+        let sample = &mut pending.sample;
+        match (id.subclass(), id.code()) {
+            (PERF_THREADINFO, PERF_TI_DATA) => {
+                sample.thread_info = Some(ThreadInfo {
+                    pid: record.args[0] as i32,
+                    tid: record.args[1],
+                    dispatch_queue: record.args[2],
+                    run_mode: record.args[3] as u32,
+                });
+            }
+            (PERF_THREADINFO, PERF_TI_SCHEDDATA | PERF_TI_SCHEDDATA_2) => {
+                sample.scheduling = Some(ThreadScheduling::from_args(record.args));
+            }
+            (PERF_CALLSTACK, PERF_CS_KHDR) => {
+                sample.kernel_stack = Some(Callstack {
+                    flags: record.args[0] as u32,
+                    frames: Vec::new(),
+                });
+                pending.kernel_frames = record.args[1] as usize;
+            }
+            (PERF_CALLSTACK, PERF_CS_UHDR) => {
+                sample.user_stack = Some(Callstack {
+                    flags: record.args[0] as u32,
+                    frames: Vec::new(),
+                });
+                pending.user_frames = record.args[1] as usize;
+            }
+            (PERF_CALLSTACK, PERF_CS_KDATA) => {
+                if let Some(stack) = &mut sample.kernel_stack {
+                    read_frames(stack, &mut pending.kernel_frames, record.args);
+                }
+            }
+            (PERF_CALLSTACK, PERF_CS_UDATA) => {
+                if let Some(stack) = &mut sample.user_stack {
+                    read_frames(stack, &mut pending.user_frames, record.args);
+                }
+            }
+            // kperf_kpc_config_log in xnu's osfmk/kperf/kperf_kpc.c logs the running classes and
+            // the number of counters before the counters, 4 per data record
+            (PERF_KPC, PERF_KPC_CONFIG) => pending.counter_count = Some(record.args[1] as usize),
+            (PERF_KPC, PERF_KPC_DATA) => sample.cpu_counters.extend(record.args),
+            (PERF_KPC, PERF_KPC_DATA_THREAD) => sample.thread_counters.extend(record.args),
+            _ => {}
+        }
+        None
+    }
+
+    /// Samples of `records`, in the order they ended.
+    pub fn decode<'a>(records: impl IntoIterator<Item = &'a KdRecord>) -> Vec<Sample> {
+        let mut decoder = Self::new();
+        records
+            .into_iter()
+            .filter_map(|record| decoder.push(record))
+            .collect()
+    }
+}
+
+/// Add the frames of a `PERF_CS_*DATA` record to `stack`, while some are `left` to read.
+fn read_frames(stack: &mut Callstack, left: &mut usize, frames: [u64; 4]) {
+    let count = (*left).min(frames.len());
+    stack.frames.extend_from_slice(&frames[..count]);
+    *left -= count;
+}
+
+/// Samples of a buffer of little endian `kd_buf` from a 64 bit kernel.
+pub fn decode_samples(bytes: &[u8]) -> Result<Vec<Sample>, KperfError> {
+    Ok(SampleDecoder::decode(&KdRecord::parse_all(bytes)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // See fixtures/kdebug/generate.py for how the fixtures are written
+    /// Two CPUs sampling at once, records interleaved: CPU 0 samples thread info, kernel and
+    /// user stacks and 6 CPU counters, CPU 1 thread info, scheduling and 2 thread counters.
+    const INTERLEAVED: &[u8] = include_bytes!("../fixtures/kdebug/interleaved.kdbuf");
+    /// A sample cut by the start of the trace, a complete one, and one cut by its end,
+    /// among records of other classes.
+    const TRUNCATED: &[u8] = include_bytes!("../fixtures/kdebug/truncated.kdbuf");
+
+    #[test]
+    fn test_debug_id() {
+        let id = DebugId::new(DBG_PERF, PERF_CALLSTACK, PERF_CS_UHDR, DBG_FUNC_START);
+        assert_eq!(id, DebugId(0x2502_0019));
+        assert_eq!(
+            (id.class(), id.subclass(), id.code(), id.func()),
+            (DBG_PERF, PERF_CALLSTACK, PERF_CS_UHDR, DBG_FUNC_START)
+        );
+        assert!(id.is_kperf());
+        assert!(!DebugId::new(1, PERF_CALLSTACK, PERF_CS_UHDR, 0).is_kperf());
+    }
+
+    #[test]
+    fn test_parse_records() {
+        let records = KdRecord::parse_all(INTERLEAVED).unwrap();
+        assert_eq!(records.len(), INTERLEAVED.len() / KD_BUF_SIZE);
+        // The info level record of kperf_kpc_cpu_sample, logged before the sample
+        assert_eq!(
+            records[1],
+            KdRecord {
+                timestamp: 995,
+                args: [0b11, 6, 0, 0],
+                thread: 0x101,
+                debugid: DebugId(0x2506_002a),
+                cpu: 0,
+            }
+        );
+        assert_eq!(
+            records[2],
+            KdRecord {
+                timestamp: 1000,
+                args: [
+                    (Samplers::THREAD_INFO
+                        | Samplers::USER_STACK
+                        | Samplers::KERNEL_STACK
+                        | Samplers::PMC_CPU)
+                        .bits() as u64,
+                    1,
+                    0,
+                    0
+                ],
+                thread: 0x101,
+                debugid: DebugId::new(DBG_PERF, PERF_GENERIC, PERF_GEN_EVENT, DBG_FUNC_START),
+                cpu: 0,
+            }
+        );
+        assert_eq!(records[3].cpu, 1);
+        assert_eq!(
+            records[6].debugid,
+            DebugId::new(DBG_PERF, PERF_THREADINFO, PERF_TI_SCHEDDATA_2, 0)
+        );
+        assert!(matches!(
+            KdRecord::parse_all(&INTERLEAVED[..KD_BUF_SIZE + 1]),
+            Err(KperfError::InvalidTrace(_))
+        ));
+    }
+
+    #[test]
+    fn test_record_bytes() {
+        for (record, bytes) in KdRecord::parse_all(TRUNCATED)
+            .unwrap()
+            .iter()
+            .zip(TRUNCATED.chunks_exact(KD_BUF_SIZE))
+        {
+            assert_eq!(record.to_bytes(), bytes);
+        }
+    }
+
+    #[test]
+    fn test_decode_interleaved() {
+        let samples = decode_samples(INTERLEAVED).unwrap();
+        assert_eq!(samples.len(), 2);
+
+        let first = &samples[0];
+        assert_eq!((first.timestamp, first.cpu, first.thread), (1000, 0, 0x101));
+        assert_eq!(first.action, ActionId(1));
+        assert!(first
+            .samplers
+            .contains(Samplers::USER_STACK | Samplers::PMC_CPU));
+        assert_eq!(
+            first.thread_info,
+            Some(ThreadInfo {
+                pid: 42,
+                tid: 0x101,
+                dispatch_queue: 0,
+                run_mode: 1,
+            })
+        );
+        assert!(first.thread_info.unwrap().is_running());
+        let user = first.user_stack.as_ref().unwrap();
+        assert!(user.is_valid() && user.is_64bit() && !user.is_kernel());
+        assert_eq!(
+            user.frames,
+            [
+                0x1000_0010,
+                0x1000_0020,
+                0x1000_0030,
+                0x1000_0040,
+                0x1000_0050
+            ]
+        );
+        let kernel = first.kernel_stack.as_ref().unwrap();
+        assert!(kernel.is_kernel() && kernel.is_truncated());
+        assert_eq!(
+            kernel.frames,
+            [0xffff_ff80_0000_1000, 0xffff_ff80_0000_2000]
+        );
+        assert_eq!(first.cpu_counters, [100, 200, 300, 400, 500, 600]);
+        assert!(first.thread_counters.is_empty());
+        assert_eq!(first.scheduling, None);
+
+        let second = &samples[1];
+        assert_eq!(
+            (second.cpu, second.thread, second.action),
+            (1, 0x202, ActionId(2))
+        );
+        assert_eq!(second.thread_info.unwrap().pid, 7);
+        assert_eq!(
+            second.scheduling,
+            Some(ThreadScheduling {
+                user_time: 5000,
+                system_time: 700,
+                base_priority: 31,
+                sched_priority: 47,
+                state: 4,
+                effective_qos: 4,
+                requested_qos: 3,
+                requested_qos_override: 0,
+            })
+        );
+        assert_eq!(second.thread_counters, [11, 22]);
+        assert_eq!(second.user_stack, None);
+    }
+
+    #[test]
+    fn test_decode_truncated() {
+        let samples = decode_samples(TRUNCATED).unwrap();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].timestamp, 2000);
+        assert_eq!(samples[0].user_stack.as_ref().unwrap().frames, [0x4000]);
+
+        // Decoding record by record gives the same samples
+        let mut decoder = SampleDecoder::new();
+        let pushed: Vec<Sample> = KdRecord::parse_all(TRUNCATED)
+            .unwrap()
+            .iter()
+            .filter_map(|record| decoder.push(record))
+            .collect();
+        assert_eq!(pushed, samples);
+    }
+}
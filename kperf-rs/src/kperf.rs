@@ -1,135 +1,142 @@
-use crate::error::KpepError::UnknownError;
+#[cfg(target_os = "macos")]
+use crate::backend::kperf::functions::kperf_tick_frequency;
+use crate::backend::CounterBackend;
 use crate::error::{KpepError, KperfError};
 use crate::event::get_event;
-use crate::event::Event;
+use crate::event::{CountingMode, Event, EventInfo};
+use crate::sampling::check;
+#[cfg(target_os = "macos")]
+use crate::timebase::Timebase;
 use crate::KPC_MAX_COUNTERS;
-use kperf_sys::constants::KPC_CLASS_CONFIGURABLE_MASK;
-use kperf_sys::functions::{
-    kpc_set_config, kpc_set_counting, kpc_set_thread_counting, kpep_config_add_event,
-    kpep_config_create, kpep_config_force_counters, kpep_config_kpc, kpep_config_kpc_classes,
-    kpep_config_kpc_count, kpep_config_kpc_map, kperf_ns_to_ticks, kperf_reset,
-    kperf_tick_frequency, kperf_ticks_to_ns,
-};
-use kperf_sys::structs::{kpc_config_t, kpep_config, kpep_db};
-use libc::{c_uint, c_ulonglong, size_t};
+use kperf_sys::constants::kpep_config_error_code::KPEP_CONFIG_ERROR_CONFLICTING_EVENTS;
+use kperf_sys::constants::{KPC_CLASS_CONFIGURABLE_MASK, KPC_CLASS_FIXED_MASK};
+use kperf_sys::structs::{kpc_config_t, kpep_config, kpep_db, kpep_event};
+use libc::{c_int, c_uint, size_t};
 use std::ffi::{CStr, CString};
 use std::fmt;
 use std::fmt::Formatter;
 use std::mem::size_of;
 use std::ptr::{null, null_mut};
+use std::sync::{Mutex, MutexGuard, PoisonError};
 
 #[derive(Debug)]
-pub struct KProbesConfig {
+pub struct KProbesConfig<B: CounterBackend> {
+    backend: B,
     pub config: *mut kpep_config,
+    events: Vec<Event>,
+    counting_modes: Vec<CountingMode>,
     classes: c_uint,
     reg_count: size_t,
     counter_map: [size_t; KPC_MAX_COUNTERS],
     kpc_registers: [kpc_config_t; KPC_MAX_COUNTERS],
 }
 
-impl KProbesConfig {
-    pub fn from_database(database: &mut KProbesDatabase) -> Result<Self, KpepError> {
+impl<B: CounterBackend> KProbesConfig<B> {
+    pub fn from_database(database: &mut KProbesDatabase<B>) -> Result<Self, KpepError> {
+        let backend = database.backend().clone();
         let mut config = null_mut();
-        unsafe {
-            let res = kpep_config_create(database.database, &mut config);
-            if res != 0 {
-                // println!(
-                //     "Failed to create kpep config, error: {}",
-                //     res
-                // );
-                return Err(KpepError::UnknownError);
-            }
-        }
+        let res = unsafe { backend.kpep_config_create(database.database, &mut config) };
+        KpepError::check(res)?;
 
-        return Ok(Self {
+        Ok(Self {
+            backend,
             config,
+            events: Vec::new(),
+            counting_modes: Vec::new(),
             classes: 0,
             reg_count: 0,
             counter_map: [0; KPC_MAX_COUNTERS],
             kpc_registers: [0; KPC_MAX_COUNTERS],
-        });
+        })
     }
 
-    pub fn add_event(&mut self, db: &KProbesDatabase, event_type: Event) -> Result<(), KperfError> {
-        let mut event = get_event(event_type, db).ok_or(KperfError::UnknownError(format!(
-            "Couldn't find matching event for event type: {}",
-            event_type
-        )))?;
-        unsafe {
-            let res = kpep_config_add_event(self.config, &mut event, 0, null_mut());
-            if res != 0 {
-                return Err(KperfError::UnknownError(format!(
-                    "Error when adding event: {}, error code: {}",
-                    event_type, res
-                )));
-            }
+    pub fn add_event(
+        &mut self,
+        db: &KProbesDatabase<B>,
+        event_type: Event,
+    ) -> Result<(), KperfError> {
+        self.add_event_with_mode(db, event_type, CountingMode::All)
+    }
+
+    /// Add `event_type`, counted in the privilege levels of `mode`.
+    pub fn add_event_with_mode(
+        &mut self,
+        db: &KProbesDatabase<B>,
+        event_type: Event,
+        mode: CountingMode,
+    ) -> Result<(), KperfError> {
+        let mut event = get_event(&event_type, db).ok_or_else(|| KperfError::EventNotFound {
+            event: event_type.to_string(),
+            database: db.get_db_name(),
+        })?;
+        // Bitmap of the indices of the added events conflicting with this one
+        let mut err: c_uint = 0;
+        let res = unsafe {
+            self.backend
+                .kpep_config_add_event(self.config, &mut event, mode.kpep_flag(), &mut err)
+        };
+        if res == KPEP_CONFIG_ERROR_CONFLICTING_EVENTS as c_int {
+            return Err(KperfError::ConflictingEvents {
+                event: event_type.to_string(),
+                conflicts: self
+                    .events
+                    .iter()
+                    .enumerate()
+                    .filter(|&(i, _)| i < c_uint::BITS as usize && err & (1 << i) != 0)
+                    .map(|(_, event)| event.to_string())
+                    .collect(),
+            });
         }
+        KpepError::check(res)?;
+        self.events.push(event_type);
+        self.counting_modes.push(mode);
         Ok(())
     }
 
     pub fn force_counters(&mut self) -> Result<(), KperfError> {
-        let res = unsafe { kpep_config_force_counters(self.config) };
-        if res != 0 {
-            return Err(KperfError::UnknownError(format!(
-                "Failed to force counters, error: {}",
-                res
-            )));
-        }
+        let res = unsafe { self.backend.kpep_config_force_counters(self.config) };
+        KpepError::check(res)?;
         Ok(())
     }
 
+    /// Fill config variables classes, reg_count, counter_map, and kpc_registers
+    /// from kperf api.
     pub fn fill_config_variables(&mut self) -> Result<(), KperfError> {
-        /// Fill config variables classes, reg_count, counter_map, and kpc_registers
-        /// from kperf api.
-        let res = unsafe { kpep_config_kpc_classes(self.config, &mut self.classes) };
-        if res != 0 {
-            return Err(KperfError::PerfCounterBuildError(format!(
-                "Failed to get Kernel Performance Counters classes, error: {}",
-                res
-            )));
-        }
+        let res = unsafe {
+            self.backend
+                .kpep_config_kpc_classes(self.config, &mut self.classes)
+        };
+        KpepError::check(res)?;
 
-        let res = unsafe { kpep_config_kpc_count(self.config, &mut self.reg_count) };
-        if res != 0 {
-            return Err(KperfError::PerfCounterBuildError(format!(
-                "Failed to get Kernel Performance Counters register count, error: {}",
-                res
-            )));
-        }
+        let res = unsafe {
+            self.backend
+                .kpep_config_kpc_count(self.config, &mut self.reg_count)
+        };
+        KpepError::check(res)?;
 
         let res = unsafe {
-            kpep_config_kpc_map(
+            self.backend.kpep_config_kpc_map(
                 self.config,
                 self.counter_map.as_mut_ptr(),
                 size_of::<[size_t; KPC_MAX_COUNTERS]>(),
             )
         };
-        if res != 0 {
-            return Err(KperfError::PerfCounterBuildError(format!(
-                "Failed to get Kernel Performance Counters register mapping, error: {}",
-                res
-            )));
-        }
+        KpepError::check(res)?;
 
         let res = unsafe {
-            kpep_config_kpc(
+            self.backend.kpep_config_kpc(
                 self.config,
                 self.kpc_registers.as_mut_ptr(),
                 size_of::<[kpc_config_t; KPC_MAX_COUNTERS]>(),
             )
         };
-        if res != 0 {
-            return Err(KperfError::PerfCounterBuildError(format!(
-                "Failed to configure kpc, error: {}",
-                res
-            )));
-        }
+        KpepError::check(res)?;
 
         Ok(())
     }
 
     pub fn start_kpc_counting(&mut self) -> Result<(), KperfError> {
-        let res = unsafe { kpc_set_counting(self.classes) };
+        let res = unsafe { self.backend.kpc_set_counting(self.classes) };
         if res != 0 {
             return Err(KperfError::PerfCounterBuildError(format!(
                 "Failed to start kpc counting, error: {}",
@@ -138,8 +145,19 @@ impl KProbesConfig {
         }
         Ok(())
     }
+    pub fn stop_kpc_counting(&mut self) -> Result<(), KperfError> {
+        let res = unsafe { self.backend.kpc_set_counting(0) };
+        if res != 0 {
+            return Err(KperfError::UnknownError(format!(
+                "Failed to stop kpc counting, error: {}",
+                res
+            )));
+        }
+        Ok(())
+    }
+
     pub fn start_kpc_thread_counting(&mut self) -> Result<(), KperfError> {
-        let res = unsafe { kpc_set_thread_counting(self.classes) };
+        let res = unsafe { self.backend.kpc_set_thread_counting(self.classes) };
         if res != 0 {
             return Err(KperfError::UnknownError(format!(
                 "Failed to start kpc thread counting, error: {}",
@@ -150,7 +168,7 @@ impl KProbesConfig {
     }
 
     pub fn stop_kpc_thread_counting(&mut self) -> Result<(), KperfError> {
-        let res = unsafe { kpc_set_thread_counting(0) };
+        let res = unsafe { self.backend.kpc_set_thread_counting(0) };
         if res != 0 {
             return Err(KperfError::UnknownError(format!(
                 "Failed to stop kpc thread counting, error: {}",
@@ -161,7 +179,7 @@ impl KProbesConfig {
     }
 
     pub fn reset_counters(&mut self) -> Result<(), KperfError> {
-        let res = unsafe { kperf_reset() };
+        let res = unsafe { self.backend.kperf_reset() };
         if res != 0 {
             return Err(KperfError::UnknownError(format!(
                 "Failed to reset kpc thread counters, error: {}",
@@ -172,37 +190,58 @@ impl KProbesConfig {
     }
 
     pub fn set_kpc_config(&mut self) -> Result<(), KperfError> {
-        if (self.classes & KPC_CLASS_CONFIGURABLE_MASK) != 0 && self.reg_count != 0 {
-            let res = unsafe { kpc_set_config(self.classes, self.kpc_registers.as_mut_ptr()) };
-            if res != 0 {
-                return Err(KperfError::PerfCounterBuildError(format!(
-                    "Failed to set kpc config, error: {}",
-                    res
-                )));
-            }
-            return Ok(());
-        } else if (self.classes & KPC_CLASS_CONFIGURABLE_MASK) == 0 {
-            //println!("Warn: classes weren't configurable and register count was 0, should be OK");
+        // The fixed counters have nothing to configure
+        if (self.classes & KPC_CLASS_CONFIGURABLE_MASK) == 0 {
             return Ok(());
-            // return Err(KperfError::PerfCounterBuildError(format!("KProbes Register count is 0")));
-        } else if self.reg_count == 0 {
+        }
+        if self.reg_count == 0 {
+            return Err(KperfError::PerfCounterBuildError(
+                "KPC is configurable but reg_count is 0, probably shouldn't happen".to_string(),
+            ));
+        }
+        let res = unsafe {
+            self.backend
+                .kpc_set_config(self.classes, self.kpc_registers.as_mut_ptr())
+        };
+        if res != 0 {
             return Err(KperfError::PerfCounterBuildError(format!(
-                "KPC is configurable but reg_count is 0, probably shouldn't happen"
-            )));
-        } else {
-            return Err(KperfError::UnknownError(format!(
-                "Should never get to here"
+                "Failed to set kpc config, error: {}",
+                res
             )));
         }
+        Ok(())
+    }
+
+    /// Counter classes used by the added events, valid after `fill_config_variables`.
+    pub fn classes(&self) -> c_uint {
+        self.classes
     }
 
-    pub fn get_counter_index(&mut self) -> usize {
-        //TODO: see if this is OK for every event, should be
-        self.counter_map[0]
+    /// Events added to this config, in the order of `counter_map`.
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    /// Counting mode of every added event, in the order of `counter_map`.
+    pub fn counting_modes(&self) -> &[CountingMode] {
+        &self.counting_modes
+    }
+
+    /// Index in the kpc counters buffer of the `event_idx`th added event.
+    pub fn get_counter_index(&self, event_idx: usize) -> usize {
+        self.counter_map[event_idx]
+    }
+}
+
+impl<B: CounterBackend> Drop for KProbesConfig<B> {
+    fn drop(&mut self) {
+        if !self.config.is_null() {
+            unsafe { self.backend.kpep_config_free(self.config) };
+        }
     }
 }
 
-impl fmt::Display for KProbesConfig {
+impl<B: CounterBackend> fmt::Display for KProbesConfig<B> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(f,
                "KProbesConfig:\nclasses: {}\nreg_count: {}\ncounter_map: {:?}\nkpc_registers: {:?}\nkpep_config: {:?}\n",
@@ -216,29 +255,127 @@ impl fmt::Display for KProbesConfig {
 }
 
 #[derive(Debug)]
-pub struct KProbesDatabase {
+pub struct KProbesDatabase<B: CounterBackend> {
+    backend: B,
     pub database: *mut kpep_db, // TODO: make this non public
 }
 
-impl KProbesDatabase {
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+impl KProbesDatabase<crate::backend::DefaultBackend> {
     pub fn load_database() -> Result<Self, KpepError> {
+        Self::load_database_with(crate::backend::DefaultBackend::default())
+    }
+}
+
+impl<B: CounterBackend> KProbesDatabase<B> {
+    pub fn load_database_with(backend: B) -> Result<Self, KpepError> {
         let mut db: *mut kpep_db = null_mut();
-        unsafe {
-            let mut ret = kperf_sys::functions::kpep_db_create(null(), &mut db);
-            if ret != 0 {
-                return Err(KpepError::UnknownError);
+        let res = unsafe { backend.kpep_db_create(null(), &mut db) };
+        KpepError::check(res)?;
+
+        Ok(Self {
+            backend,
+            database: db,
+        })
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Pointers to every event of the database.
+    pub(crate) fn event_pointers(&self) -> Result<Vec<*mut kpep_event>, KperfError> {
+        let mut count: size_t = 0;
+        let res = unsafe { self.backend.kpep_db_events_count(self.database, &mut count) };
+        KpepError::check(res)?;
+
+        let mut events: Vec<*mut kpep_event> = vec![null_mut(); count];
+        let res = unsafe {
+            self.backend.kpep_db_events(
+                self.database,
+                events.as_mut_ptr(),
+                count * size_of::<*mut kpep_event>(),
+            )
+        };
+        KpepError::check(res)?;
+        Ok(events)
+    }
+
+    /// Every event supported by the database's CPU.
+    pub fn events(&self) -> Result<impl Iterator<Item = EventInfo> + '_, KperfError> {
+        let events = self.event_pointers()?;
+        Ok(events
+            .into_iter()
+            .map(|event| unsafe { EventInfo::from_raw(event) }))
+    }
+
+    /// Split `events` into groups that can each be counted at the same time, keeping their order.
+    ///
+    /// Every event goes in the first group it doesn't conflict with, or starts a new one.
+    pub fn group_events(&mut self, events: &[Event]) -> Result<Vec<Vec<Event>>, KperfError> {
+        let events: Vec<_> = events
+            .iter()
+            .map(|event| (event.clone(), CountingMode::All))
+            .collect();
+        let groups = self.group_configs(&events)?;
+        Ok(groups.iter().map(|group| group.events().to_vec()).collect())
+    }
+
+    /// Same as [`KProbesDatabase::group_events`], keeping the config of every group.
+    pub(crate) fn group_configs(
+        &mut self,
+        events: &[(Event, CountingMode)],
+    ) -> Result<Vec<KProbesConfig<B>>, KperfError> {
+        let mut groups: Vec<KProbesConfig<B>> = Vec::new();
+        'events: for (event, mode) in events {
+            for group in &mut groups {
+                match group.add_event_with_mode(self, event.clone(), *mode) {
+                    Ok(()) => continue 'events,
+                    Err(KperfError::ConflictingEvents { .. }) => {}
+                    Err(err) => return Err(err),
+                }
             }
+            let mut group = KProbesConfig::from_database(self)?;
+            group.force_counters()?;
+            group.add_event_with_mode(self, event.clone(), *mode)?;
+            groups.push(group);
         }
-
-        Ok(Self { database: db })
+        Ok(groups)
     }
 
     pub fn get_fixed_counter_count(&self) -> usize {
-        unsafe { (*self.database).fixed_counter_count as usize }
+        unsafe { (*self.database).fixed_counter_count }
     }
 
     pub fn get_configurable_counter_count(&self) -> usize {
-        unsafe { (*self.database).config_counter_count as usize }
+        unsafe { (*self.database).config_counter_count }
+    }
+
+    pub fn get_fixed_counter_bits(&self) -> c_uint {
+        unsafe { (*self.database).fixed_counter_bits }
+    }
+
+    pub fn get_configurable_counter_bits(&self) -> c_uint {
+        unsafe { (*self.database).config_counter_bits }
+    }
+
+    /// Width of each of the first `count` counters kpc reads for `classes`: the fixed counters
+    /// come first when `classes` has them, then the configurable ones.
+    pub fn counter_bits(&self, classes: c_uint, count: usize) -> Vec<c_uint> {
+        let fixed_count = if classes & KPC_CLASS_FIXED_MASK != 0 {
+            self.get_fixed_counter_count()
+        } else {
+            0
+        };
+        (0..count)
+            .map(|counter| {
+                if counter < fixed_count {
+                    self.get_fixed_counter_bits()
+                } else {
+                    self.get_configurable_counter_bits()
+                }
+            })
+            .collect()
     }
 
     pub fn get_db_name(&self) -> Option<String> {
@@ -247,27 +384,171 @@ impl KProbesDatabase {
                 return None;
             }
             let name = CString::from(CStr::from_ptr((*self.database).name)).into_string();
-            match name {
-                Ok(x) => return Some(x),
-                Err(x) => return None,
-            }
+            name.ok()
+        }
+    }
+}
+
+impl<B: CounterBackend> Drop for KProbesDatabase<B> {
+    fn drop(&mut self) {
+        if !self.database.is_null() {
+            unsafe { self.backend.kpep_db_free(self.database) };
         }
     }
 }
 
+/// Difference between the readings `start` and `end` of a `bits` wide counter, and whether the
+/// counter wrapped around in between.
+///
+/// Only one wraparound can be accounted for, a flagged delta is wrong if the counter wrapped more
+/// than once. A reading that doesn't fit in `bits` is an error.
+pub fn counter_delta(start: u64, end: u64, bits: c_uint) -> Result<(u64, bool), KperfError> {
+    let mask = if bits >= 64 {
+        u64::MAX
+    } else {
+        (1 << bits) - 1
+    };
+    for value in [start, end] {
+        if value > mask {
+            return Err(KperfError::CounterOutOfRange { value, bits });
+        }
+    }
+    Ok((end.wrapping_sub(start) & mask, end < start))
+}
+
+/// Counting classes and force_all_ctrs state saved when created, and restored when dropped.
+///
+/// Guards of backends sharing the same state (see [`CounterBackend::state_id`]) share the state
+/// saved by the first one, which is restored when the last one drops, so counters alive at the
+/// same time can be dropped in any order.
+/// Dropping also happens while unwinding, so a panic in the measured code doesn't leave the
+/// counters taken away from the Power Manager.
+#[derive(Debug)]
+pub struct KpcStateGuard<B: CounterBackend> {
+    backend: B,
+}
+
+/// State saved by the live guards of a backend state, and how many there are.
+struct SavedKpcState {
+    state_id: usize,
+    guards: usize,
+    counting: c_uint,
+    thread_counting: c_uint,
+    force_all_ctrs: c_int,
+}
+
+static SAVED_KPC_STATES: Mutex<Vec<SavedKpcState>> = Mutex::new(Vec::new());
+
+fn saved_kpc_states() -> MutexGuard<'static, Vec<SavedKpcState>> {
+    // The states stay consistent even if a guard panicked while holding the lock
+    SAVED_KPC_STATES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+impl<B: CounterBackend> KpcStateGuard<B> {
+    pub fn save(backend: &B) -> Result<Self, KperfError> {
+        let state_id = backend.state_id();
+        let mut states = saved_kpc_states();
+        if let Some(saved) = states.iter_mut().find(|saved| saved.state_id == state_id) {
+            saved.guards += 1;
+        } else {
+            let mut force_all_ctrs: c_int = 0;
+            let res = unsafe { backend.kpc_force_all_ctrs_get(&mut force_all_ctrs) };
+            check(res, "get the force_all_ctrs state")?;
+            states.push(SavedKpcState {
+                state_id,
+                guards: 1,
+                counting: unsafe { backend.kpc_get_counting() },
+                thread_counting: unsafe { backend.kpc_get_thread_counting() },
+                force_all_ctrs,
+            });
+        }
+        Ok(Self {
+            backend: backend.clone(),
+        })
+    }
+}
+
+impl<B: CounterBackend> Drop for KpcStateGuard<B> {
+    fn drop(&mut self) {
+        let state_id = self.backend.state_id();
+        let mut states = saved_kpc_states();
+        let Some(idx) = states.iter().position(|saved| saved.state_id == state_id) else {
+            return;
+        };
+        states[idx].guards -= 1;
+        if states[idx].guards > 0 {
+            return;
+        }
+        let saved = states.remove(idx);
+        // Nothing sensible to do if restoring fails, keep going with the other settings
+        unsafe {
+            self.backend.kpc_set_thread_counting(saved.thread_counting);
+            self.backend.kpc_set_counting(saved.counting);
+            self.backend.kpc_force_all_ctrs_set(saved.force_all_ctrs);
+        }
+    }
+}
+
+/// Frequency of kperf ticks, 0 without the kperf frameworks.
+#[cfg(target_os = "macos")]
 pub fn get_tick_frequency() -> u64 {
     unsafe { kperf_tick_frequency() as u64 }
 }
 
-const TICKS_TO_NANOSECONDS_MAGIC_NUMBER: u64 = 100; // Magic number, don't ask
-pub fn ticks_to_nanoseconds(cpu_ticks: u64) -> u64 {
-    unsafe {
-        kperf_ticks_to_ns(cpu_ticks as c_ulonglong) as u64 / TICKS_TO_NANOSECONDS_MAGIC_NUMBER
-    }
+/// Frequency of kperf ticks, 0 without the kperf frameworks.
+#[cfg(not(target_os = "macos"))]
+pub fn get_tick_frequency() -> u64 {
+    0
+}
+
+/// Nanoseconds lasted by kperf `ticks`, 0 if the timebase is unavailable, as it always is
+/// without the kperf frameworks.
+///
+/// Prefer getting a [`Timebase`](crate::timebase::Timebase) once and converting with it.
+pub fn ticks_to_nanoseconds(ticks: u64) -> u64 {
+    system_timebase().map_or(0, |timebase| timebase.ticks_to_nanoseconds(ticks))
 }
 
+/// kperf ticks in `nanoseconds`, 0 if the timebase is unavailable, as it always is without the
+/// kperf frameworks.
+///
+/// Prefer getting a [`Timebase`](crate::timebase::Timebase) once and converting with it.
+pub fn nanoseconds_to_ticks(nanoseconds: u64) -> u64 {
+    system_timebase().map_or(0, |timebase| timebase.nanoseconds_to_ticks(nanoseconds))
+}
+
+#[deprecated(note = "renamed to `nanoseconds_to_ticks`")]
 pub fn nanaseconds_to_ticks(nanoseconds: u64) -> u64 {
-    unsafe {
-        kperf_ns_to_ticks(nanoseconds * TICKS_TO_NANOSECONDS_MAGIC_NUMBER as c_ulonglong) as u64
+    nanoseconds_to_ticks(nanoseconds)
+}
+
+#[cfg(target_os = "macos")]
+fn system_timebase() -> Option<Timebase> {
+    Timebase::system().ok()
+}
+
+#[cfg(not(target_os = "macos"))]
+fn system_timebase() -> Option<crate::timebase::Timebase> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counter_delta() {
+        assert_eq!(counter_delta(10, 25, 48).unwrap(), (15, false));
+        assert_eq!(counter_delta(250, 4, 8).unwrap(), (10, true));
+        assert_eq!(counter_delta(u64::MAX, 1, 64).unwrap(), (2, true));
+        assert!(matches!(
+            counter_delta(10, 256, 8),
+            Err(KperfError::CounterOutOfRange {
+                value: 256,
+                bits: 8
+            })
+        ));
     }
 }
@@ -0,0 +1,381 @@
+use crate::error::KperfError;
+use crate::kdebug::{KdRecord, Sample, SampleDecoder};
+use crate::timebase::Timebase;
+use kperf_sys::constants::{
+    KDBG_CPUMAP_IS_IOP, KDBG_CPUMAP_VERSION1, KDBG_CPUMAP_VERSION2, RAW_VERSION1, RAW_VERSION3,
+    V3_CPU_MAP, V3_NULL_CHUNK, V3_RAW_EVENTS, V3_THREAD_MAP,
+};
+use std::fs;
+use std::path::Path;
+
+/// Size of the tag, sub tag and length every chunk starts with.
+const CHUNK_HEADER_SIZE: usize = 16;
+/// Size of the fields of the file header following its chunk header.
+const FILE_HEADER_SIZE: usize = 40;
+/// Size of a thread map entry: thread id, pid and a 20 byte command.
+const THREAD_MAP_ENTRY_SIZE: usize = 32;
+
+/// Fields of the header of a trace file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TraceHeader {
+    /// Timebase of the timestamps of the records, `None` if the file doesn't tell.
+    pub timebase: Option<Timebase>,
+    /// Time the trace started, in ticks of `timebase`.
+    pub timestamp: u64,
+    /// Wall clock time the trace started, since the Unix epoch.
+    pub walltime_secs: u64,
+    pub walltime_usecs: u32,
+    pub flags: u32,
+}
+
+/// CPU of the traced machine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuInfo {
+    pub id: u32,
+    /// `KDBG_CPUMAP_*` flags.
+    pub flags: u32,
+    pub name: String,
+}
+
+impl CpuInfo {
+    /// Whether this is an I/O processor rather than a CPU of the system.
+    pub fn is_iop(&self) -> bool {
+        self.flags & KDBG_CPUMAP_IS_IOP != 0
+    }
+}
+
+/// Thread alive when the trace was taken.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThreadMapEntry {
+    pub thread: u64,
+    pub pid: i32,
+    /// Name of the process, truncated to 19 characters by the kernel.
+    pub command: String,
+}
+
+/// Chunk the reader doesn't decode, e.g. a stackshot or the configuration of the trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub tag: u32,
+    pub sub_tag: u32,
+    pub data: Vec<u8>,
+}
+
+/// Trace file written by `ktrace record` or `ktrace artrace`, in the chunked version 3 format.
+///
+/// The file starts with a header chunk, followed by chunks mapping CPUs and threads, holding
+/// the kdebug records, or others like stackshots, which are kept as they are.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceFile {
+    header: TraceHeader,
+    cpus: Vec<CpuInfo>,
+    threads: Vec<ThreadMapEntry>,
+    records: Vec<KdRecord>,
+    chunks: Vec<Chunk>,
+}
+
+impl TraceFile {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, KperfError> {
+        let path = path.as_ref();
+        let bytes = fs::read(path).map_err(|err| {
+            KperfError::UnknownError(format!(
+                "Failed to read trace file {}, error: {}",
+                path.display(),
+                err
+            ))
+        })?;
+        Self::parse(&bytes)
+    }
+
+    /// Trace of the content of a trace file.
+    pub fn parse(bytes: &[u8]) -> Result<Self, KperfError> {
+        let mut chunks = ChunkReader { bytes, offset: 0 };
+        let (tag, _, data) = chunks
+            .next()
+            .ok_or_else(|| invalid("the file is empty".to_string()))??;
+        if tag == RAW_VERSION1 {
+            return Err(invalid(
+                "version 1 trace files aren't supported, record with ktrace".to_string(),
+            ));
+        }
+        if tag != RAW_VERSION3 {
+            return Err(invalid(format!("unknown file tag {:#x}", tag)));
+        }
+        let mut trace = Self {
+            header: parse_header(data)?,
+            cpus: Vec::new(),
+            threads: Vec::new(),
+            records: Vec::new(),
+            chunks: Vec::new(),
+        };
+        for chunk in chunks {
+            let (tag, sub_tag, data) = chunk?;
+            match tag {
+                V3_NULL_CHUNK => {}
+                V3_CPU_MAP => trace.cpus = parse_cpu_map(data)?,
+                V3_THREAD_MAP => trace.threads.extend(parse_thread_map(data)?),
+                V3_RAW_EVENTS => trace.records.extend(KdRecord::parse_all(data)?),
+                _ => trace.chunks.push(Chunk {
+                    tag,
+                    sub_tag,
+                    data: data.to_vec(),
+                }),
+            }
+        }
+        Ok(trace)
+    }
+
+    pub fn header(&self) -> &TraceHeader {
+        &self.header
+    }
+
+    pub fn timebase(&self) -> Option<Timebase> {
+        self.header.timebase
+    }
+
+    pub fn cpus(&self) -> &[CpuInfo] {
+        &self.cpus
+    }
+
+    pub fn threads(&self) -> &[ThreadMapEntry] {
+        &self.threads
+    }
+
+    /// Entry of the thread map of `thread`, if it was alive when the trace was taken.
+    pub fn thread(&self, thread: u64) -> Option<&ThreadMapEntry> {
+        self.threads.iter().find(|entry| entry.thread == thread)
+    }
+
+    /// Records of every event chunk, in file order.
+    pub fn records(&self) -> &[KdRecord] {
+        &self.records
+    }
+
+    /// Chunks the reader doesn't decode, in file order.
+    pub fn chunks(&self) -> &[Chunk] {
+        &self.chunks
+    }
+
+    /// kperf samples of the trace, as [`SampleDecoder`] rebuilds them from live records.
+    pub fn samples(&self) -> Vec<Sample> {
+        SampleDecoder::decode(&self.records)
+    }
+}
+
+/// Iterator over the tag, sub tag and data of the chunks of a file.
+struct ChunkReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for ChunkReader<'a> {
+    type Item = Result<(u32, u32, &'a [u8]), KperfError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = &self.bytes[self.offset..];
+        if rest.is_empty() {
+            return None;
+        }
+        let offset = self.offset;
+        // Stop iterating after an error, nothing after it can be trusted
+        self.offset = self.bytes.len();
+        if rest.len() < CHUNK_HEADER_SIZE {
+            return Some(Err(invalid(format!(
+                "truncated chunk header at offset {}",
+                offset
+            ))));
+        }
+        let tag = read_u32(rest, 0);
+        let sub_tag = read_u32(rest, 4);
+        let length = read_u64(rest, 8);
+        let Some(end) = usize::try_from(length)
+            .ok()
+            .and_then(|length| length.checked_add(CHUNK_HEADER_SIZE))
+            .filter(|&end| end <= rest.len())
+        else {
+            return Some(Err(invalid(format!(
+                "chunk {:#x} at offset {} runs past the end of the file",
+                tag, offset
+            ))));
+        };
+        self.offset = offset + end;
+        Some(Ok((tag, sub_tag, &rest[CHUNK_HEADER_SIZE..end])))
+    }
+}
+
+fn parse_header(data: &[u8]) -> Result<TraceHeader, KperfError> {
+    if data.len() < FILE_HEADER_SIZE {
+        return Err(invalid(format!("{} bytes long file header", data.len())));
+    }
+    Ok(TraceHeader {
+        timebase: Timebase::new(read_u32(data, 0) as u64, read_u32(data, 4) as u64),
+        timestamp: read_u64(data, 8),
+        walltime_secs: read_u64(data, 16),
+        walltime_usecs: read_u32(data, 24),
+        flags: read_u32(data, 36),
+    })
+}
+
+/// CPUs of a CPU map: a version and count, then the id, flags and name of each CPU.
+fn parse_cpu_map(data: &[u8]) -> Result<Vec<CpuInfo>, KperfError> {
+    if data.len() < 8 {
+        return Err(invalid("truncated CPU map".to_string()));
+    }
+    let entry_size = match read_u32(data, 0) {
+        KDBG_CPUMAP_VERSION1 => 16,
+        KDBG_CPUMAP_VERSION2 => 40,
+        version => return Err(invalid(format!("unknown CPU map version {:#x}", version))),
+    };
+    let count = read_u32(data, 4) as usize;
+    let entries = &data[8..];
+    if entries.len() / entry_size < count {
+        return Err(invalid(format!("CPU map too small for {} CPUs", count)));
+    }
+    Ok(entries
+        .chunks_exact(entry_size)
+        .take(count)
+        .map(|entry| CpuInfo {
+            id: read_u32(entry, 0),
+            flags: read_u32(entry, 4),
+            name: read_c_string(&entry[8..]),
+        })
+        .collect())
+}
+
+fn parse_thread_map(data: &[u8]) -> Result<Vec<ThreadMapEntry>, KperfError> {
+    if !data.len().is_multiple_of(THREAD_MAP_ENTRY_SIZE) {
+        return Err(invalid(format!(
+            "{} bytes isn't a whole number of thread map entries",
+            data.len()
+        )));
+    }
+    Ok(data
+        .chunks_exact(THREAD_MAP_ENTRY_SIZE)
+        .map(|entry| ThreadMapEntry {
+            thread: read_u64(entry, 0),
+            pid: read_u32(entry, 8) as i32,
+            command: read_c_string(&entry[12..]),
+        })
+        .collect())
+}
+
+fn invalid(message: String) -> KperfError {
+    KperfError::InvalidTrace(message)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// String up to the first NUL of `bytes`, or all of them.
+fn read_c_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampling::ActionId;
+
+    // See fixtures/ktrace/generate.py for how the trace is written
+    /// Header with a 125/3 timebase, padding, a CPU map of two CPUs and an IOP, a thread map,
+    /// the records of `kdebug/interleaved.kdbuf` split in two event chunks around a
+    /// `0x8002` chunk holding "stackshot".
+    const TRACE: &[u8] = include_bytes!("../fixtures/ktrace/interleaved.ktrace");
+    const INTERLEAVED: &[u8] = include_bytes!("../fixtures/kdebug/interleaved.kdbuf");
+
+    #[test]
+    fn test_parse_trace() {
+        let trace = TraceFile::parse(TRACE).unwrap();
+        assert_eq!(
+            *trace.header(),
+            TraceHeader {
+                timebase: Timebase::new(125, 3),
+                timestamp: 900,
+                walltime_secs: 1_700_000_000,
+                walltime_usecs: 250_000,
+                flags: 1,
+            }
+        );
+
+        let cpus = trace.cpus();
+        assert_eq!(cpus.len(), 3);
+        assert_eq!((cpus[1].id, cpus[1].name.as_str()), (1, "AP1"));
+        assert!(!cpus[0].is_iop() && cpus[2].is_iop());
+
+        assert_eq!(trace.threads().len(), 2);
+        let thread = trace.thread(0x202).unwrap();
+        assert_eq!(
+            (thread.pid, thread.command.as_str()),
+            (7, "a_very_long_command")
+        );
+        assert_eq!(trace.thread(0x303), None);
+
+        assert_eq!(
+            trace.chunks(),
+            [Chunk {
+                tag: 0x8002,
+                sub_tag: 0,
+                data: b"stackshot".to_vec(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_samples_match_live_decoding() {
+        let trace = TraceFile::parse(TRACE).unwrap();
+        let live = crate::kdebug::decode_samples(INTERLEAVED).unwrap();
+        assert_eq!(trace.records(), KdRecord::parse_all(INTERLEAVED).unwrap());
+        let samples = trace.samples();
+        assert_eq!(samples, live);
+        assert_eq!(samples[1].action, ActionId(2));
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        assert!(matches!(
+            TraceFile::parse(&[]),
+            Err(KperfError::InvalidTrace(_))
+        ));
+        // Cut in the middle of the events
+        assert!(matches!(
+            TraceFile::parse(&TRACE[..TRACE.len() - 10]),
+            Err(KperfError::InvalidTrace(_))
+        ));
+        let mut version1 = TRACE.to_vec();
+        version1[..4].copy_from_slice(&RAW_VERSION1.to_le_bytes());
+        assert!(TraceFile::parse(&version1)
+            .unwrap_err()
+            .to_string()
+            .contains("version 1"));
+        assert!(TraceFile::open("/nonexistent/trace.ktrace").is_err());
+    }
+
+    #[test]
+    fn test_cpu_map_versions() {
+        let map = |version: u32, name_size: usize| {
+            let mut map = [version, 2].map(u32::to_le_bytes).concat();
+            for (id, name) in [(0u32, "cpu0"), (1, "cpu1")] {
+                map.extend([id, 0].map(u32::to_le_bytes).concat());
+                map.extend(name.as_bytes());
+                map.resize(map.len() + name_size - name.len(), 0);
+            }
+            map
+        };
+        let cpus = parse_cpu_map(&map(KDBG_CPUMAP_VERSION2, 32)).unwrap();
+        assert_eq!(cpus.len(), 2);
+        assert_eq!((cpus[1].id, cpus[1].name.as_str()), (1, "cpu1"));
+        let cpus = parse_cpu_map(&map(KDBG_CPUMAP_VERSION1, 8)).unwrap();
+        assert_eq!((cpus[1].id, cpus[1].name.as_str()), (1, "cpu1"));
+        assert!(parse_cpu_map(&map(KDBG_CPUMAP_VERSION2, 8)).is_err());
+        assert!(parse_cpu_map(&map(0x55aa0300, 8))
+            .unwrap_err()
+            .to_string()
+            .contains("unknown CPU map version"));
+    }
+}
@@ -1,78 +1,129 @@
+pub mod backend;
+pub mod calibration;
 pub mod error;
 pub mod event;
+pub mod flamegraph;
+pub mod kdebug;
 pub mod kperf;
+pub mod ktrace;
+pub mod measure;
+pub mod metrics;
+pub mod multiplex;
+pub mod sampling;
+pub mod snapshot;
+pub mod symbolicate;
+pub mod timebase;
 
+use backend::CounterBackend;
+use calibration::{Calibration, Overhead};
 use error::KperfError;
-use event::Event;
+use event::{CountingMode, Event};
+use kperf::counter_delta;
 use kperf::KProbesConfig;
 use kperf::KProbesDatabase;
+use kperf::KpcStateGuard;
 pub use kperf_sys;
-use kperf_sys::functions::{kpc_force_all_ctrs_set, kpc_get_thread_counters};
 use libc::{c_int, c_uint, c_ulonglong, size_t};
-use std::error::Error;
-use std::io;
+use measure::MeasureGuard;
+use multiplex::{MultiplexedCounter, Multiplexing};
+use snapshot::{CounterSnapshot, CpuSnapshot};
 
+/// What a [`PerfCounter`] counts.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
 pub enum Track {
+    /// The calling thread, wherever it runs.
+    #[default]
     Thread,
+    /// The CPU the calling thread runs on when read, whatever runs on it.
+    Cpu,
+    /// Every CPU, in the counting mode of each event. `read` sums them.
+    AllCpus,
+    /// Every CPU, always counting user space and kernel, to see the load of the whole system.
+    /// `read` sums them.
+    System,
 }
 
-pub struct PerfCounterBuilder {
-    kprobes_config: KProbesConfig, // TODO: this var should be created on build_counter!
-    kprobes_db: KProbesDatabase,   // TODO: this var should be created on build_counter!
-    tracked_event: Event,          // TODO: Make this an option
+pub struct PerfCounterBuilder<B: CounterBackend> {
+    kprobes_config: KProbesConfig<B>,
+    kprobes_db: KProbesDatabase<B>,
+    tracked_events: Vec<Event>,
+    counting_modes: Vec<CountingMode>,
+    track: Track,
+    software_fallback: bool,
 }
 
-impl PerfCounterBuilder {
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+impl PerfCounterBuilder<backend::DefaultBackend> {
+    /// Builder over the default backend of the platform.
+    pub fn try_new() -> Result<Self, KperfError> {
+        Self::try_with_backend(backend::DefaultBackend::default())
+    }
+
+    /// Builder over the default backend of the platform, panicking if it can't be created.
+    #[deprecated(note = "use `try_new`, which returns the error instead of panicking")]
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
-        let mut kprobes_db =
-            KProbesDatabase::load_database().expect("Couldn't load kprobes database");
-        let kprobes_config =
-            KProbesConfig::from_database(&mut kprobes_db).expect("Couldn't create kpc config");
-        Self {
+        Self::try_new().expect("Couldn't create the counter builder")
+    }
+}
+
+impl<B: CounterBackend> PerfCounterBuilder<B> {
+    /// Builder over `backend`, failing if the backend is unavailable or its event database or
+    /// config can't be created.
+    pub fn try_with_backend(backend: B) -> Result<Self, KperfError> {
+        backend.check_available()?;
+        let mut kprobes_db = KProbesDatabase::load_database_with(backend)?;
+        let kprobes_config = KProbesConfig::from_database(&mut kprobes_db)?;
+        Ok(Self {
             kprobes_db,
             kprobes_config,
-            tracked_event: Event::Cycles,
-        }
+            tracked_events: Vec::new(),
+            counting_modes: Vec::new(),
+            track: Track::Thread,
+            software_fallback: false,
+        })
     }
 
-    pub fn build_counter(mut self) -> Result<PerfCounter, KperfError> {
-        // let mut classes = 0;
-        // let mut reg_count = 0;
-        // let mut counter_map: [size_t; KPC_MAX_COUNTERS] = [0; KPC_MAX_COUNTERS];
-        // let mut kpc_registers: [kpc_config_t; KPC_MAX_COUNTERS] = [0; KPC_MAX_COUNTERS];
+    /// Build a counter for every tracked event, or for [`Event::Cycles`] if none were tracked.
+    ///
+    /// An event missing from the CPU's database fails the build, unless
+    /// [`PerfCounterBuilder::allow_software_fallback`] was called and it has an
+    /// [`Event::software_substitute`].
+    pub fn build_counter(mut self) -> Result<PerfCounter<B>, KperfError> {
         self.kprobes_config.force_counters()?;
 
-        match self.tracked_event {
-            Event::Cycles => {
-                self.kprobes_config
-                    .add_event(&self.kprobes_db, Event::Cycles)?;
-                // self.kprobes_config.add_event(&self.kprobes_db, Event::Branches)?;
-                // self.kprobes_config.add_event(&self.kprobes_db, Event::Instructions)?;
-                // self.kprobes_config.add_event(&self.kprobes_db, Event::BranchMisses)?;
-            }
-            Event::Branches => {
-                self.kprobes_config
-                    .add_event(&self.kprobes_db, Event::Branches)?;
-            }
-            Event::BranchMisses => {
-                self.kprobes_config
-                    .add_event(&self.kprobes_db, Event::BranchMisses)?;
-            }
-            Event::Instructions => {
+        if self.tracked_events.is_empty() {
+            self = self.track_event(Event::Cycles);
+        }
+        if self.track == Track::System {
+            self.counting_modes.fill(CountingMode::All);
+        }
+        let mut substitutes = Vec::with_capacity(self.tracked_events.len());
+        for (event, &mode) in self.tracked_events.iter().zip(&self.counting_modes) {
+            let res =
                 self.kprobes_config
-                    .add_event(&self.kprobes_db, Event::Instructions)?;
-            }
-            _ => {
-                return Err(KperfError::PerfCounterBuildError(format!(
-                    "Event type {:?} not implemented yet!",
-                    self.tracked_event
-                )))
+                    .add_event_with_mode(&self.kprobes_db, event.clone(), mode);
+            let substitute = event
+                .software_substitute()
+                .filter(|_| self.software_fallback);
+            match (res, substitute) {
+                (Err(err @ KperfError::EventNotFound { .. }), Some(substitute)) => {
+                    self.kprobes_config
+                        .add_event_with_mode(&self.kprobes_db, substitute.clone(), mode)
+                        .map_err(|_| err)?;
+                    substitutes.push(Some(substitute));
+                }
+                (res, _) => {
+                    res?;
+                    substitutes.push(None);
+                }
             }
         }
 
         self.kprobes_config.fill_config_variables()?;
 
-        let res = unsafe { kpc_force_all_ctrs_set(1) }; // Set config to kernel
+        let kpc_state = KpcStateGuard::save(self.kprobes_db.backend())?;
+        let res = unsafe { self.kprobes_db.backend().kpc_force_all_ctrs_set(1) }; // Set config to kernel
         if res != 0 {
             return Err(KperfError::PerfCounterBuildError(format!(
                 "Failed to force_all_ctrs_set, error: {}",
@@ -82,115 +133,1114 @@ impl PerfCounterBuilder {
 
         self.kprobes_config.set_kpc_config()?;
 
-        // self.kprobes_config.start_kpc_counting()?;
-        //
-        // self.kprobes_config.start_kpc_thread_counting()?;
-        // TODO: What functions are actually usefull?
-
-        let counter_idx = self.kprobes_config.get_counter_index();
-        let mut counter = PerfCounter {
+        let counter_idxs = (0..self.tracked_events.len())
+            .map(|i| self.kprobes_config.get_counter_index(i))
+            .collect();
+        let (counter_count, buffer_len) = match self.track {
+            Track::Thread => (KPC_MAX_COUNTERS, KPC_MAX_COUNTERS),
+            Track::Cpu | Track::AllCpus | Track::System => {
+                let backend = self.kprobes_db.backend();
+                let classes = self.kprobes_config.classes();
+                let counter_count = unsafe { backend.kpc_get_counter_count(classes) } as usize;
+                (counter_count, counter_count * backend.cpu_count())
+            }
+        };
+        let counter_bits = self
+            .kprobes_db
+            .counter_bits(self.kprobes_config.classes(), counter_count);
+        let counter = PerfCounter {
+            _kpc_state: kpc_state,
             kprobes_db: self.kprobes_db,
             kprobes_config: self.kprobes_config,
-            counters_start: [0 as c_ulonglong; KPC_MAX_COUNTERS],
-            counters_end: [0 as c_ulonglong; KPC_MAX_COUNTERS],
-            counter_idx,
-            started: false,
+            track: self.track,
+            counter_count,
+            counter_bits,
+            current_cpu: 0,
+            counters_start: vec![0; buffer_len],
+            counters_end: vec![0; buffer_len],
+            tracked_events: self.tracked_events,
+            counting_modes: self.counting_modes,
+            substitutes,
+            counter_idxs,
+            totals: vec![0; buffer_len],
+            wrapped: vec![false; buffer_len],
+            segments: 0,
+            running: false,
+            last_measurement: None,
+            calibration: None,
+            subtract_overhead: false,
         };
 
         Ok(counter)
     }
 
-    pub fn track_event(mut self, tracked_event: Event) -> Self {
-        self.tracked_event = tracked_event;
+    /// Build a counter rotating the tracked events through the hardware counters, in groups that
+    /// can be counted together, for when they don't all fit at once.
+    ///
+    /// Multiplexed counters only count the calling thread, other tracks fail to build.
+    pub fn build_multiplexed(
+        mut self,
+        multiplexing: Multiplexing,
+    ) -> Result<MultiplexedCounter<B>, KperfError> {
+        if self.track != Track::Thread {
+            return Err(KperfError::PerfCounterBuildError(format!(
+                "multiplexed counters can't track {:?}, only Thread",
+                self.track
+            )));
+        }
+        if self.tracked_events.is_empty() {
+            self = self.track_event(Event::Cycles);
+        }
+        MultiplexedCounter::new(
+            self.kprobes_db,
+            self.tracked_events,
+            self.counting_modes,
+            multiplexing,
+        )
+    }
+
+    /// Count `track` instead of the calling thread.
+    pub fn track(mut self, track: Track) -> Self {
+        self.track = track;
+        self
+    }
+
+    /// Count the events missing from the CPU's database as their [`Event::software_substitute`],
+    /// as in VMs hiding the PMU. The snapshots record the substitutes, and metrics using a
+    /// substituted event evaluate to `None`.
+    pub fn allow_software_fallback(mut self) -> Self {
+        self.software_fallback = true;
         self
     }
+
+    /// Add `tracked_event` to the events counted together, tracking it twice has no effect.
+    pub fn track_event(self, tracked_event: Event) -> Self {
+        self.track_event_with_mode(tracked_event, CountingMode::All)
+    }
+
+    /// Same as [`PerfCounterBuilder::track_event`], counting only in the privilege levels of
+    /// `mode`.
+    pub fn track_event_with_mode(mut self, tracked_event: Event, mode: CountingMode) -> Self {
+        if !self.tracked_events.contains(&tracked_event) {
+            self.tracked_events.push(tracked_event);
+            self.counting_modes.push(mode);
+        }
+        self
+    }
+
+    pub fn track_events<I: IntoIterator<Item = Event>>(self, tracked_events: I) -> Self {
+        tracked_events
+            .into_iter()
+            .fold(self, |builder, event| builder.track_event(event))
+    }
 }
 
 const KPC_MAX_COUNTERS: size_t = 32;
 
-pub struct PerfCounter {
-    kprobes_config: KProbesConfig,
-    kprobes_db: KProbesDatabase,
-    counters_start: [c_ulonglong; KPC_MAX_COUNTERS],
-    counters_end: [c_ulonglong; KPC_MAX_COUNTERS],
-    counter_idx: usize,
-    started: bool,
+pub struct PerfCounter<B: CounterBackend> {
+    // Dropped first, so the kpc state is restored before the kpep objects are freed
+    _kpc_state: KpcStateGuard<B>,
+    kprobes_config: KProbesConfig<B>,
+    kprobes_db: KProbesDatabase<B>,
+    track: Track,
+    /// Counters per CPU in the buffers of the per-CPU tracks.
+    counter_count: usize,
+    /// Width of each counter of a CPU.
+    counter_bits: Vec<c_uint>,
+    current_cpu: usize,
+    counters_start: Vec<c_ulonglong>,
+    counters_end: Vec<c_ulonglong>,
+    tracked_events: Vec<Event>,
+    counting_modes: Vec<CountingMode>,
+    /// Event counted in place of each tracked event, if any.
+    substitutes: Vec<Option<Event>>,
+    counter_idxs: Vec<usize>,
+    /// What every counter counted while the counter ran, up to its last stop.
+    totals: Vec<c_ulonglong>,
+    /// Whether a counter wrapped around in what the totals counted.
+    wrapped: Vec<bool>,
+    /// Number of start/stop pairs the totals add up, each reading the counters twice.
+    segments: u64,
+    running: bool,
+    last_measurement: Option<CounterSnapshot>,
+    calibration: Option<Calibration>,
+    subtract_overhead: bool,
 }
 
-impl PerfCounter {
-    fn fill_start(&mut self) -> Result<(), KperfError> {
-
+impl<B: CounterBackend> PerfCounter<B> {
+    /// Read the tracked counters in `buf`, returns the CPU the calling thread runs on.
+    fn read_counters(&self, buf: &mut [c_ulonglong]) -> Result<usize, KperfError> {
+        let backend = self.kprobes_db.backend();
+        let mut cpu: c_int = 0;
         let res = unsafe {
-            kpc_get_thread_counters(
-                0,
-                KPC_MAX_COUNTERS as c_uint,
-                self.counters_start.as_mut_ptr(),
-            )
+            match self.track {
+                Track::Thread => {
+                    backend.kpc_get_thread_counters(0, buf.len() as c_uint, buf.as_mut_ptr())
+                }
+                Track::Cpu | Track::AllCpus | Track::System => backend.kpc_get_cpu_counters(
+                    true,
+                    self.kprobes_config.classes(),
+                    &mut cpu,
+                    buf.as_mut_ptr(),
+                ),
+            }
         };
         if res != 0 {
             return Err(KperfError::UnknownError(format!(
-                "Failed to get thread counters, error: {}",
+                "Failed to get {} counters, error: {}",
+                if self.track == Track::Thread {
+                    "thread"
+                } else {
+                    "cpu"
+                },
                 res
             )));
         }
-        Ok(())
+        Ok(cpu.max(0) as usize)
+    }
+
+    fn fill_start(&mut self) -> Result<(), KperfError> {
+        let mut counters = std::mem::take(&mut self.counters_start);
+        let res = self.read_counters(&mut counters);
+        self.counters_start = counters;
+        res.map(|_| ())
     }
 
     fn fill_end(&mut self) -> Result<(), KperfError> {
-        // if !self.started {
-        //     return Ok(());
-        // }
-        let res = unsafe {
-            kpc_get_thread_counters(
-                0,
-                KPC_MAX_COUNTERS as c_uint,
-                self.counters_end.as_mut_ptr(),
-            )
-        };
-        if res != 0 {
-            return Err(KperfError::UnknownError(format!(
-                "Failed to get thread counters, error: {}",
-                res
-            )));
+        let mut counters = std::mem::take(&mut self.counters_end);
+        let res = self.read_counters(&mut counters);
+        self.counters_end = counters;
+        self.current_cpu = res?;
+        Ok(())
+    }
+
+    fn start_counting(&mut self) -> Result<(), KperfError> {
+        self.kprobes_config.start_kpc_counting()?;
+        if self.track == Track::Thread {
+            self.kprobes_config.start_kpc_thread_counting()?;
         }
-        // self.started = false;
-        return Ok(());
+        Ok(())
     }
 
+    fn stop_counting(&mut self) -> Result<(), KperfError> {
+        match self.track {
+            Track::Thread => self.kprobes_config.stop_kpc_thread_counting(),
+            Track::Cpu | Track::AllCpus | Track::System => self.kprobes_config.stop_kpc_counting(),
+        }
+    }
+
+    /// Start counting, or resume after [`PerfCounter::stop`]: nothing is counted while the
+    /// counter is stopped. Does nothing if it is already running.
     pub fn start(&mut self) -> Result<(), KperfError> {
-        self.kprobes_config.start_kpc_counting()?;
-        self.kprobes_config.start_kpc_thread_counting()?;
-        if !self.started {
-            self.fill_start()?;
-            self.started = true;
+        if self.running {
+            return Ok(());
         }
+        self.start_counting()?;
+        self.fill_start()?;
+        self.running = true;
         Ok(())
     }
 
+    /// Stop counting, adding what was counted since the last start to the totals [`read`]
+    /// returns. Does nothing if the counter is already stopped.
+    ///
+    /// [`read`]: PerfCounter::read
     pub fn stop(&mut self) -> Result<(), KperfError> {
-        self.kprobes_config.stop_kpc_thread_counting()
+        if !self.running {
+            return Ok(());
+        }
+        self.running = false;
+        let res = self.fill_end();
+        self.stop_counting()?;
+        res?;
+        let (segment, wrapped) = self.segment()?;
+        for (total, count) in self.totals.iter_mut().zip(segment) {
+            *total += count;
+        }
+        for (total, wrapped) in self.wrapped.iter_mut().zip(wrapped) {
+            *total |= wrapped;
+        }
+        self.segments += 1;
+        Ok(())
+    }
+
+    /// Same as [`PerfCounter::stop`].
+    pub fn pause(&mut self) -> Result<(), KperfError> {
+        self.stop()
     }
 
+    /// Same as [`PerfCounter::start`].
+    pub fn resume(&mut self) -> Result<(), KperfError> {
+        self.start()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Zero the totals of this counter, a running counter keeps running from there.
+    ///
+    /// The hardware counters are left alone, so other counters aren't affected.
     pub fn reset(&mut self) -> Result<(), KperfError> {
-        self.kprobes_config.reset_counters()?;
-        self.fill_start()?;
+        self.totals.fill(0);
+        self.wrapped.fill(false);
+        self.segments = 0;
+        if self.running {
+            self.fill_start()?;
+        }
         Ok(())
     }
 
-    pub fn read(&mut self) -> Result<u64, KperfError> {
-        self.fill_end()?;
-        return Ok(self.counters_end[self.counter_idx] - self.counters_start[self.counter_idx]);
+    /// Run `f` while counting, and return its result with what it counted.
+    ///
+    /// Only `f` is measured, from a fresh start: there is no need to reset the counter between
+    /// measurements. The counter is stopped even if `f` panics.
+    pub fn measure<T, F: FnOnce() -> T>(
+        &mut self,
+        f: F,
+    ) -> Result<(T, CounterSnapshot), KperfError> {
+        let guard = self.measure_guard()?;
+        let result = f();
+        Ok((result, guard.finish()?))
+    }
+
+    /// Start a measurement lasting until the returned guard is finished or dropped.
+    pub fn measure_guard(&mut self) -> Result<MeasureGuard<'_, B>, KperfError> {
+        MeasureGuard::new(self)
+    }
+
+    /// What the last measurement counted, once its guard was finished or dropped.
+    pub fn last_measurement(&self) -> Option<&CounterSnapshot> {
+        self.last_measurement.as_ref()
+    }
+
+    pub(crate) fn begin_measurement(&mut self) -> Result<(), KperfError> {
+        self.reset()?;
+        self.start()
+    }
+
+    pub(crate) fn end_measurement(&mut self) -> Result<CounterSnapshot, KperfError> {
+        self.stop()?;
+        let snapshot = self.read()?;
+        self.last_measurement = Some(snapshot.clone());
+        Ok(snapshot)
+    }
+
+    /// Measure `repetitions` empty regions to estimate what reading the counters adds to the
+    /// value of each event.
+    ///
+    /// Stops the counter, keeping its totals.
+    pub fn calibrate(&mut self, repetitions: usize) -> Result<&Calibration, KperfError> {
+        self.stop()?;
+        let mut samples = Vec::with_capacity(repetitions);
+        let res = self.start_counting().and_then(|_| {
+            for _ in 0..repetitions {
+                self.fill_start()?;
+                self.fill_end()?;
+                let (segment, _) = self.segment()?;
+                samples.push(self.event_values(&segment));
+            }
+            Ok(())
+        });
+        self.stop_counting()?;
+        res?;
+        Ok(self
+            .calibration
+            .insert(Calibration::from_samples(&self.tracked_events, &samples)))
+    }
+
+    pub fn calibration(&self) -> Option<&Calibration> {
+        self.calibration.as_ref()
+    }
+
+    /// Subtract the overhead measured by [`PerfCounter::calibrate`] from the values `read`
+    /// returns, once for every start/stop pair they add up. The snapshots report the correction
+    /// applied to each event.
+    pub fn set_subtract_overhead(&mut self, subtract_overhead: bool) {
+        self.subtract_overhead = subtract_overhead;
+    }
+
+    pub fn track(&self) -> Track {
+        self.track
+    }
+
+    pub fn tracked_events(&self) -> &[Event] {
+        &self.tracked_events
+    }
+
+    pub fn counting_modes(&self) -> &[CountingMode] {
+        &self.counting_modes
+    }
+
+    /// Values of the tracked events counted while the counter ran, since it was built or reset.
+    ///
+    /// For [`Track::Cpu`] those are the values of the CPU the calling thread runs on, and for
+    /// [`Track::AllCpus`] and [`Track::System`] the sum of every CPU.
+    pub fn read(&mut self) -> Result<CounterSnapshot, KperfError> {
+        let (counts, wrapped) = self.counts()?;
+        let values = self.event_values(&counts);
+        let wrapped = match self.track {
+            Track::Thread => self.cpu_values(&wrapped, 0),
+            Track::Cpu => self.cpu_values(&wrapped, self.current_cpu),
+            Track::AllCpus | Track::System => (0..self.cpu_count())
+                .map(|cpu| self.cpu_values(&wrapped, cpu))
+                .reduce(|any, wrapped| any.iter().zip(wrapped).map(|(a, b)| a | b).collect())
+                .unwrap_or_default(),
+        };
+        let snapshot = match &self.calibration {
+            Some(calibration) if self.subtract_overhead => {
+                // A running counter adds what it counted since its last start to the totals
+                let segments = self.segments + self.running as u64;
+                let overheads: Vec<Overhead> = calibration
+                    .overheads()
+                    .iter()
+                    .map(|overhead| overhead.times(segments))
+                    .collect();
+                let values = values
+                    .into_iter()
+                    .zip(&overheads)
+                    .map(|(value, overhead)| value.saturating_sub(overhead.correction()))
+                    .collect();
+                self.snapshot(values).with_corrections(overheads)
+            }
+            _ => self.snapshot(values),
+        };
+        Ok(snapshot.with_wrapped(wrapped))
+    }
+
+    /// Counts of every counter, and whether it wrapped around: the totals, and what was counted
+    /// since the last start if the counter is running.
+    fn counts(&mut self) -> Result<(Vec<c_ulonglong>, Vec<bool>), KperfError> {
+        let mut counts = self.totals.clone();
+        let mut wrapped = self.wrapped.clone();
+        if self.running {
+            self.fill_end()?;
+            let (segment, segment_wrapped) = self.segment()?;
+            for (count, segment) in counts.iter_mut().zip(segment) {
+                *count += segment;
+            }
+            for (wrapped, segment_wrapped) in wrapped.iter_mut().zip(segment_wrapped) {
+                *wrapped |= segment_wrapped;
+            }
+        }
+        Ok((counts, wrapped))
+    }
+
+    /// What the tracked counters counted between the last reads of the start and end buffers,
+    /// and whether they wrapped around.
+    fn segment(&self) -> Result<(Vec<c_ulonglong>, Vec<bool>), KperfError> {
+        let mut segment = vec![0; self.counters_end.len()];
+        let mut wrapped = vec![false; self.counters_end.len()];
+        for offset in (0..segment.len()).step_by(self.counter_count.max(1)) {
+            for &idx in &self.counter_idxs {
+                let counter = offset + idx;
+                let (delta, counter_wrapped) = counter_delta(
+                    self.counters_start[counter],
+                    self.counters_end[counter],
+                    self.counter_bits[idx],
+                )?;
+                segment[counter] = delta;
+                wrapped[counter] = counter_wrapped;
+            }
+        }
+        Ok((segment, wrapped))
+    }
+
+    /// Values of the tracked events in `counts`, for the CPUs of the track.
+    fn event_values(&self, counts: &[c_ulonglong]) -> Vec<u64> {
+        match self.track {
+            Track::Thread => self.cpu_values(counts, 0),
+            Track::Cpu => self.cpu_values(counts, self.current_cpu),
+            Track::AllCpus | Track::System => {
+                let mut values = vec![0u64; self.tracked_events.len()];
+                for cpu in 0..self.cpu_count() {
+                    for (total, value) in values.iter_mut().zip(self.cpu_values(counts, cpu)) {
+                        *total += value;
+                    }
+                }
+                values
+            }
+        }
+    }
+
+    /// Values of the tracked events on each CPU since the counter started: only the CPU the
+    /// calling thread runs on for [`Track::Cpu`], every CPU for [`Track::AllCpus`] and
+    /// [`Track::System`].
+    pub fn read_cpus(&mut self) -> Result<Vec<CpuSnapshot>, KperfError> {
+        let counts = match self.track {
+            Track::Thread => {
+                return Err(KperfError::UnknownError(
+                    "Per-CPU values need Track::Cpu, Track::AllCpus or Track::System".to_string(),
+                ))
+            }
+            Track::Cpu | Track::AllCpus | Track::System => self.counts()?,
+        };
+        let (counts, wrapped) = counts;
+        let cpus = match self.track {
+            Track::Cpu => self.current_cpu..self.current_cpu + 1,
+            _ => 0..self.cpu_count(),
+        };
+        Ok(cpus
+            .map(|cpu| CpuSnapshot {
+                cpu,
+                snapshot: self
+                    .snapshot(self.cpu_values(&counts, cpu))
+                    .with_wrapped(self.cpu_values(&wrapped, cpu)),
+            })
+            .collect())
+    }
+
+    fn cpu_count(&self) -> usize {
+        self.counters_end.len() / self.counter_count.max(1)
     }
+
+    /// Values of the tracked events in the block of `cpu`, the only block of the thread buffers.
+    fn cpu_values<T: Copy>(&self, counts: &[T], cpu: usize) -> Vec<T> {
+        let offset = cpu * self.counter_count;
+        self.counter_idxs
+            .iter()
+            .map(|&idx| counts[offset + idx])
+            .collect()
+    }
+
+    fn snapshot(&self, values: Vec<u64>) -> CounterSnapshot {
+        let values = self.tracked_events.iter().cloned().zip(values).collect();
+        CounterSnapshot::new(values)
+            .with_counting_modes(self.counting_modes.clone())
+            .with_substitutes(self.substitutes.clone())
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+pub fn check_kpc_permission() -> Result<(), KperfError> {
+    check_kpc_permission_with(&backend::DefaultBackend::default())
 }
 
+/// Fails, there are no counters without a backend for the platform.
+#[cfg(not(any(target_os = "macos", target_os = "linux")))]
 pub fn check_kpc_permission() -> Result<(), KperfError> {
+    Err(KperfError::UnknownError(
+        "no counter backend for this platform".to_string(),
+    ))
+}
+
+pub fn check_kpc_permission_with<B: CounterBackend>(backend: &B) -> Result<(), KperfError> {
     let mut force_ctrs: c_int = 0;
     unsafe {
-        let res = kperf_sys::functions::kpc_force_all_ctrs_get(&mut force_ctrs);
+        let res = backend.kpc_force_all_ctrs_get(&mut force_ctrs);
         if res != 0 {
-            // println!("Permission denied, xnu/kpc requires root privileges (error code: {})", res);
             return Err(KperfError::PermissionDenied);
         }
     }
-    return Ok(());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{SimulatedBackend, SimulatedDatabase, SimulatedEvent};
+    use kperf_sys::constants::KPC_CLASS_FIXED_MASK;
+
+    #[test]
+    fn test_database_from_backend() {
+        let db = KProbesDatabase::load_database_with(SimulatedBackend::default()).unwrap();
+        assert_eq!(db.get_fixed_counter_count(), 2);
+        assert_eq!(db.get_configurable_counter_count(), 8);
+        assert_eq!(db.get_db_name().as_deref(), Some("a15"));
+    }
+
+    #[test]
+    fn test_event_catalog() {
+        let db = KProbesDatabase::load_database_with(SimulatedBackend::default()).unwrap();
+        let events: Vec<_> = db.events().unwrap().collect();
+        assert_eq!(events.len(), 8);
+        assert_eq!(events.iter().filter(|event| event.is_fixed).count(), 2);
+
+        let instructions = &events[1];
+        assert_eq!(instructions.name, "FIXED_INSTRUCTIONS");
+        assert_eq!(instructions.alias.as_deref(), Some("Instructions"));
+        assert_eq!(instructions.fallback.as_deref(), Some("INST_ALL"));
+        assert_eq!(instructions.number, 0x8c);
+        assert_eq!(instructions.mask, 0x02);
+
+        let branch = events
+            .iter()
+            .find(|event| event.name == "INST_BRANCH")
+            .unwrap();
+        assert!(!branch.is_fixed);
+        assert_eq!(branch.alias, None);
+        assert_eq!(
+            branch.description.as_deref(),
+            Some("Retired branch instructions")
+        );
+        assert_eq!(branch.event(), Event::Named("INST_BRANCH".to_string()));
+    }
+
+    #[test]
+    fn test_counter_reads_through_backend() {
+        let backend = SimulatedBackend::default();
+        backend.set_counter_source(|_| 100);
+        let mut counter = PerfCounterBuilder::try_with_backend(backend.clone())
+            .unwrap()
+            .track_event(Event::Cycles)
+            .build_counter()
+            .unwrap();
+        counter.start().unwrap();
+        assert_eq!(backend.thread_counting(), KPC_CLASS_FIXED_MASK);
+        assert_eq!(counter.read().unwrap()[Event::Cycles], 100);
+        counter.stop().unwrap();
+        assert_eq!(backend.thread_counting(), 0);
+    }
+
+    #[test]
+    fn test_missing_event_fails_build() {
+        let result = PerfCounterBuilder::try_with_backend(SimulatedBackend::default())
+            .unwrap()
+            .track_event(Event::TaskClock)
+            .build_counter();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_software_substitute() {
+        // Like a VM, where the kernel has no hardware events
+        let backend = SimulatedBackend::new(SimulatedDatabase {
+            events: vec![SimulatedEvent::configurable("task-clock").with_alias("TaskClock")],
+            ..SimulatedDatabase::apple_m2()
+        });
+        backend.set_counter_source(|_| 5);
+        let result = PerfCounterBuilder::try_with_backend(backend.clone())
+            .unwrap()
+            .track_event(Event::Cycles)
+            .build_counter();
+        assert!(matches!(result, Err(KperfError::EventNotFound { .. })));
+
+        let mut counter = PerfCounterBuilder::try_with_backend(backend.clone())
+            .unwrap()
+            .allow_software_fallback()
+            .track_event(Event::Cycles)
+            .build_counter()
+            .unwrap();
+        counter.start().unwrap();
+        let snapshot = counter.read().unwrap();
+        assert_eq!(snapshot[Event::Cycles], 5);
+        assert_eq!(snapshot.substitute(&Event::Cycles), Some(&Event::TaskClock));
+        assert_eq!(
+            snapshot.to_string(),
+            "Cycles:uk: 5 (counted as TaskClock)\n"
+        );
+
+        // Events without a substitute still fail
+        let result = PerfCounterBuilder::try_with_backend(backend)
+            .unwrap()
+            .allow_software_fallback()
+            .track_event(Event::Instructions)
+            .build_counter();
+        assert!(matches!(result, Err(KperfError::EventNotFound { .. })));
+    }
+
+    #[test]
+    #[cfg(all(feature = "dynamic", not(target_os = "macos")))]
+    fn test_unloadable_frameworks_fail_build() {
+        let result = PerfCounterBuilder::try_with_backend(backend::KperfBackend);
+        assert!(matches!(
+            result,
+            Err(KperfError::Load(kperf_sys::dynamic::LoadError::Unavailable))
+        ));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    #[allow(deprecated)]
+    fn test_default_backend_api() {
+        // Still there for callers from before the backends, the database needs no permission
+        let builder = PerfCounterBuilder::new();
+        assert_eq!(
+            builder.kprobes_db.get_db_name().as_deref(),
+            Some("perf_event")
+        );
+        assert!(check_kpc_permission().is_ok());
+        // No kperf ticks without the frameworks
+        assert_eq!(kperf::get_tick_frequency(), 0);
+        assert_eq!(kperf::ticks_to_nanoseconds(1000), 0);
+        assert_eq!(kperf::nanaseconds_to_ticks(1000), 0);
+    }
+
+    #[test]
+    fn test_drop_restores_kpc_state() {
+        let backend = SimulatedBackend::default();
+        let mut counter = PerfCounterBuilder::try_with_backend(backend.clone())
+            .unwrap()
+            .track_events([Event::Cycles, Event::Branches])
+            .build_counter()
+            .unwrap();
+        counter.start().unwrap();
+        assert!(backend.force_all_ctrs());
+        assert_ne!(backend.counting(), 0);
+        assert_ne!(backend.thread_counting(), 0);
+        assert_eq!(backend.live_kpep_objects(), 2);
+
+        drop(counter);
+        assert!(!backend.force_all_ctrs());
+        assert_eq!(backend.counting(), 0);
+        assert_eq!(backend.thread_counting(), 0);
+        assert_eq!(backend.live_kpep_objects(), 0);
+    }
+
+    #[test]
+    fn test_last_counter_dropped_restores_kpc_state() {
+        let backend = SimulatedBackend::default();
+        backend.set_counter_source(|_| 10);
+        let build = || {
+            PerfCounterBuilder::try_with_backend(backend.clone())
+                .unwrap()
+                .track_event(Event::Cycles)
+                .build_counter()
+                .unwrap()
+        };
+        let mut first = build();
+        let mut second = build();
+        first.start().unwrap();
+        second.start().unwrap();
+
+        // Dropped before the counter created after it, which keeps counting
+        drop(first);
+        assert!(backend.force_all_ctrs());
+        assert_ne!(backend.thread_counting(), 0);
+        second.stop().unwrap();
+        assert!(second.read().unwrap()[Event::Cycles] > 0);
+
+        drop(second);
+        assert!(!backend.force_all_ctrs());
+        assert_eq!(backend.thread_counting(), 0);
+
+        // Saving the state of another backend fails on its own errno
+        let other = SimulatedBackend::default();
+        other.set_root(false);
+        assert!(matches!(
+            KpcStateGuard::save(&other),
+            Err(KperfError::PermissionDenied)
+        ));
+    }
+
+    #[test]
+    fn test_panic_restores_kpc_state() {
+        let backend = SimulatedBackend::default();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let mut counter = PerfCounterBuilder::try_with_backend(backend.clone())
+                .unwrap()
+                .build_counter()
+                .unwrap();
+            counter.start().unwrap();
+            panic!("measured code panicked");
+        }));
+        assert!(result.is_err());
+        assert!(!backend.force_all_ctrs());
+        assert_eq!(backend.thread_counting(), 0);
+        assert_eq!(backend.live_kpep_objects(), 0);
+    }
+
+    #[test]
+    fn test_failed_build_frees_kpep_objects() {
+        let backend = SimulatedBackend::default();
+        let result = PerfCounterBuilder::try_with_backend(backend.clone())
+            .unwrap()
+            .track_event(Event::TaskClock)
+            .build_counter();
+        assert!(result.is_err());
+        assert_eq!(backend.live_kpep_objects(), 0);
+    }
+
+    #[test]
+    fn test_conflicting_events_are_named() {
+        let backend = SimulatedBackend::new(SimulatedDatabase {
+            config_counter_count: 2,
+            ..SimulatedDatabase::apple_m2()
+        });
+        let result = PerfCounterBuilder::try_with_backend(backend)
+            .unwrap()
+            .track_events([Event::Cycles, Event::Branches, Event::BranchMisses])
+            .track_event(Event::Named("INST_ALL".to_string()))
+            .build_counter();
+        match result {
+            Err(KperfError::ConflictingEvents { event, conflicts }) => {
+                assert_eq!(event, "INST_ALL");
+                assert_eq!(conflicts, ["Branches", "BranchMisses"]);
+            }
+            _ => panic!("Expected ConflictingEvents"),
+        }
+    }
+
+    #[test]
+    fn test_group_events() {
+        let backend = SimulatedBackend::new(SimulatedDatabase {
+            config_counter_count: 2,
+            ..SimulatedDatabase::apple_m2()
+        });
+        let mut db = KProbesDatabase::load_database_with(backend.clone()).unwrap();
+        let named = |name: &str| Event::Named(name.to_string());
+        let groups = db
+            .group_events(&[
+                Event::Cycles,
+                Event::Branches,
+                Event::BranchMisses,
+                named("INST_ALL"),
+                named("FIXED_CYCLES"),
+                named("L1D_CACHE_MISS_LD"),
+            ])
+            .unwrap();
+        assert_eq!(
+            groups,
+            [
+                vec![Event::Cycles, Event::Branches, Event::BranchMisses],
+                vec![
+                    named("INST_ALL"),
+                    named("FIXED_CYCLES"),
+                    named("L1D_CACHE_MISS_LD")
+                ],
+            ]
+        );
+        assert_eq!(backend.live_kpep_objects(), 1);
+
+        assert!(matches!(
+            db.group_events(&[Event::TaskClock]),
+            Err(KperfError::EventNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_permission_denied() {
+        let backend = SimulatedBackend::default();
+        assert!(check_kpc_permission_with(&backend).is_ok());
+        backend.set_root(false);
+        assert!(matches!(
+            check_kpc_permission_with(&backend),
+            Err(KperfError::PermissionDenied)
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_of_every_tracked_event() {
+        let backend = SimulatedBackend::default();
+        backend.set_counter_source(|event| match event {
+            "FIXED_CYCLES" => 400,
+            "FIXED_INSTRUCTIONS" => 300,
+            "BRANCH_MISPRED_NONSPEC" => 2,
+            _ => 1,
+        });
+        let mut counter = PerfCounterBuilder::try_with_backend(backend)
+            .unwrap()
+            .track_events([Event::Cycles, Event::Instructions, Event::BranchMisses])
+            .track_event(Event::Cycles)
+            .build_counter()
+            .unwrap();
+        assert_eq!(counter.tracked_events().len(), 3);
+        counter.start().unwrap();
+        let snapshot = counter.read().unwrap();
+        assert_eq!(snapshot.len(), 3);
+        assert_eq!(snapshot[Event::Cycles], 400);
+        assert_eq!(snapshot[Event::Instructions], 300);
+        assert_eq!(snapshot[Event::BranchMisses], 2);
+        assert_eq!(snapshot.get(&Event::Branches), None);
+    }
+
+    #[test]
+    fn test_counting_modes() {
+        let backend = SimulatedBackend::default();
+        backend.set_counter_source(|_| 5);
+        let mut counter = PerfCounterBuilder::try_with_backend(backend.clone())
+            .unwrap()
+            .track_event_with_mode(Event::Cycles, CountingMode::UserOnly)
+            .track_event(Event::Branches)
+            .build_counter()
+            .unwrap();
+        assert_eq!(
+            backend.added_events(),
+            [
+                ("FIXED_CYCLES".to_string(), 1),
+                ("INST_BRANCH".to_string(), 0)
+            ]
+        );
+
+        counter.start().unwrap();
+        let snapshot = counter.read().unwrap();
+        assert_eq!(
+            snapshot.counting_mode(&Event::Cycles),
+            Some(CountingMode::UserOnly)
+        );
+        assert_eq!(
+            snapshot.counting_mode(&Event::Branches),
+            Some(CountingMode::All)
+        );
+        assert_eq!(snapshot.to_string(), "Cycles:u: 5\nBranches:uk: 5\n");
+    }
+
+    #[test]
+    fn test_track_all_cpus() {
+        let backend = SimulatedBackend::default();
+        backend.set_cpu_count(3);
+        backend.set_current_cpu(1);
+        backend.set_cpu_counter_source(|cpu, _| (cpu as u64 + 1) * 10);
+        let mut counter = PerfCounterBuilder::try_with_backend(backend.clone())
+            .unwrap()
+            .track(Track::AllCpus)
+            .track_event(Event::Cycles)
+            .track_event_with_mode(Event::Branches, CountingMode::UserOnly)
+            .build_counter()
+            .unwrap();
+
+        counter.start().unwrap();
+        let cpus = counter.read_cpus().unwrap();
+        assert_eq!(cpus.len(), 3);
+        for (cpu, snapshot) in cpus.iter().enumerate() {
+            assert_eq!(snapshot.cpu, cpu);
+            assert_eq!(snapshot.snapshot[Event::Cycles], (cpu as u64 + 1) * 10);
+            assert_eq!(
+                snapshot.snapshot.counting_mode(&Event::Branches),
+                Some(CountingMode::UserOnly)
+            );
+        }
+        let snapshot = counter.read().unwrap();
+        assert_eq!(snapshot[Event::Cycles], 2 * (10 + 20 + 30));
+        assert_eq!(snapshot[Event::Branches], 2 * (10 + 20 + 30));
+
+        counter.stop().unwrap();
+        assert_eq!(backend.counting(), 0);
+    }
+
+    #[test]
+    fn test_track_cpu() {
+        let backend = SimulatedBackend::default();
+        backend.set_cpu_count(2);
+        backend.set_cpu_counter_source(|cpu, _| if cpu == 0 { 1 } else { 100 });
+        let mut counter = PerfCounterBuilder::try_with_backend(backend.clone())
+            .unwrap()
+            .track(Track::Cpu)
+            .build_counter()
+            .unwrap();
+
+        counter.start().unwrap();
+        backend.set_current_cpu(1);
+        let cpus = counter.read_cpus().unwrap();
+        assert_eq!(cpus.len(), 1);
+        assert_eq!(cpus[0].cpu, 1);
+        assert_eq!(cpus[0].snapshot[Event::Cycles], 100);
+        assert_eq!(counter.read().unwrap()[Event::Cycles], 200);
+    }
+
+    #[test]
+    fn test_track_system_counts_kernel() {
+        let backend = SimulatedBackend::default();
+        let mut counter = PerfCounterBuilder::try_with_backend(backend.clone())
+            .unwrap()
+            .track(Track::System)
+            .track_event_with_mode(Event::Cycles, CountingMode::UserOnly)
+            .build_counter()
+            .unwrap();
+        assert_eq!(backend.added_events(), [("FIXED_CYCLES".to_string(), 0)]);
+        assert_eq!(counter.counting_modes(), [CountingMode::All]);
+        assert_eq!(counter.track(), Track::System);
+        counter.start().unwrap();
+        assert_eq!(counter.read_cpus().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_stopped_regions_accumulate() {
+        let backend = SimulatedBackend::default();
+        // Increments of the reads at: start, stop, resume, read, stop, start, reset, read
+        backend.script_event("FIXED_CYCLES", &[5, 100, 1000, 20, 7, 40, 3, 9]);
+        let mut counter = PerfCounterBuilder::try_with_backend(backend.clone())
+            .unwrap()
+            .build_counter()
+            .unwrap();
+        assert_eq!(counter.read().unwrap()[Event::Cycles], 0);
+
+        counter.start().unwrap();
+        counter.start().unwrap();
+        assert!(counter.is_running());
+        counter.stop().unwrap();
+        assert!(!counter.is_running());
+        assert_eq!(backend.thread_counting(), 0);
+        assert_eq!(counter.read().unwrap()[Event::Cycles], 100);
+        counter.stop().unwrap();
+
+        // What the resume read sees happened while stopped
+        counter.resume().unwrap();
+        assert_eq!(counter.read().unwrap()[Event::Cycles], 120);
+        counter.pause().unwrap();
+        assert_eq!(counter.read().unwrap()[Event::Cycles], 127);
+
+        counter.reset().unwrap();
+        assert_eq!(counter.read().unwrap()[Event::Cycles], 0);
+        counter.start().unwrap();
+        counter.reset().unwrap();
+        assert_eq!(counter.read().unwrap()[Event::Cycles], 9);
+    }
+
+    #[test]
+    fn test_wraparound_is_flagged() {
+        let mut database = SimulatedDatabase::apple_m2();
+        database.fixed_counter_bits = 8;
+        let backend = SimulatedBackend::new(database);
+        backend.script_event("FIXED_CYCLES", &[250, 10, 5]);
+        let mut counter = PerfCounterBuilder::try_with_backend(backend.clone())
+            .unwrap()
+            .build_counter()
+            .unwrap();
+        counter.start().unwrap();
+        let snapshot = counter.read().unwrap();
+        assert_eq!(snapshot[Event::Cycles], 10);
+        assert!(snapshot.wrapped(&Event::Cycles));
+        assert_eq!(snapshot.to_string(), "Cycles:uk: 10 (wrapped)\n");
+
+        counter.reset().unwrap();
+        let snapshot = counter.read().unwrap();
+        assert_eq!(snapshot[Event::Cycles], 0);
+        assert!(!snapshot.wrapped(&Event::Cycles));
+    }
+
+    #[test]
+    fn test_measure() {
+        let backend = SimulatedBackend::default();
+        backend.set_counter_source(|_| 100);
+        let mut counter = PerfCounterBuilder::try_with_backend(backend.clone())
+            .unwrap()
+            .build_counter()
+            .unwrap();
+        for _ in 0..3 {
+            let (result, snapshot) = counter
+                .measure(|| {
+                    assert_eq!(backend.thread_counting(), KPC_CLASS_FIXED_MASK);
+                    42
+                })
+                .unwrap();
+            assert_eq!(result, 42);
+            assert_eq!(snapshot[Event::Cycles], 100);
+            assert_eq!(backend.thread_counting(), 0);
+        }
+        assert_eq!(counter.last_measurement().unwrap()[Event::Cycles], 100);
+    }
+
+    #[test]
+    fn test_measure_guard_records_on_panic() {
+        let backend = SimulatedBackend::default();
+        backend.set_counter_source(|_| 7);
+        let mut counter = PerfCounterBuilder::try_with_backend(backend.clone())
+            .unwrap()
+            .build_counter()
+            .unwrap();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            counter.measure(|| panic!("measured code panicked"))
+        }));
+        assert!(result.is_err());
+        assert_eq!(backend.thread_counting(), 0);
+        assert_eq!(counter.last_measurement().unwrap()[Event::Cycles], 7);
+
+        {
+            let _guard = counter.measure_guard().unwrap();
+            assert_eq!(backend.thread_counting(), KPC_CLASS_FIXED_MASK);
+        }
+        assert_eq!(backend.thread_counting(), 0);
+        assert_eq!(counter.last_measurement().unwrap()[Event::Cycles], 7);
+    }
+
+    #[test]
+    fn test_calibration_is_subtracted() {
+        let backend = SimulatedBackend::default();
+        backend.script_event("FIXED_CYCLES", &[0, 90, 0, 100, 0, 110, 0, 350]);
+        let mut counter = PerfCounterBuilder::try_with_backend(backend.clone())
+            .unwrap()
+            .build_counter()
+            .unwrap();
+        let overhead = counter.calibrate(3).unwrap().get(&Event::Cycles).unwrap();
+        assert_eq!(overhead.mean, 100.);
+        assert_eq!(overhead.variance, 100.);
+        assert_eq!(backend.thread_counting(), 0);
+
+        counter.start().unwrap();
+        assert_eq!(counter.read().unwrap()[Event::Cycles], 350);
+        counter.set_subtract_overhead(true);
+        let snapshot = counter.read().unwrap();
+        assert_eq!(snapshot[Event::Cycles], 250);
+        assert_eq!(snapshot.correction(&Event::Cycles), Some(overhead));
+        assert_eq!(
+            snapshot.to_string(),
+            "Cycles:uk: 250 (overhead 100.0 ± 10.0 subtracted)\n"
+        );
+    }
+
+    #[test]
+    fn test_calibration_is_subtracted_per_segment() {
+        let backend = SimulatedBackend::default();
+        // Three calibration repetitions, then three start/stop pairs
+        backend.script_event(
+            "FIXED_CYCLES",
+            &[0, 100, 0, 100, 0, 100, 0, 1100, 0, 2100, 0, 3100],
+        );
+        let mut counter = PerfCounterBuilder::try_with_backend(backend)
+            .unwrap()
+            .build_counter()
+            .unwrap();
+        counter.calibrate(3).unwrap();
+        counter.set_subtract_overhead(true);
+        counter.start().unwrap();
+        counter.stop().unwrap();
+        counter.start().unwrap();
+        counter.stop().unwrap();
+        let snapshot = counter.read().unwrap();
+        assert_eq!(snapshot[Event::Cycles], 3000);
+        assert_eq!(snapshot.correction(&Event::Cycles).unwrap().mean, 200.);
+
+        // The running segment is corrected too
+        counter.start().unwrap();
+        assert_eq!(counter.read().unwrap()[Event::Cycles], 6000);
+
+        counter.reset().unwrap();
+        assert_eq!(counter.read().unwrap()[Event::Cycles], 0);
+    }
+
+    #[test]
+    fn test_thread_has_no_cpu_values() {
+        let mut counter = PerfCounterBuilder::try_with_backend(SimulatedBackend::default())
+            .unwrap()
+            .build_counter()
+            .unwrap();
+        counter.start().unwrap();
+        assert!(counter.read_cpus().is_err());
+    }
+
+    #[test]
+    fn test_named_events() {
+        let backend = SimulatedBackend::default();
+        backend.set_counter_source(|event| match event {
+            "FIXED_CYCLES" => 10,
+            "L1D_CACHE_MISS_LD" => 3,
+            _ => 0,
+        });
+        let mut counter = PerfCounterBuilder::try_with_backend(backend)
+            .unwrap()
+            .track_event(Event::Named("L1D_CACHE_MISS_LD".to_string()))
+            .track_event(Event::Named("Cycles".to_string()))
+            .build_counter()
+            .unwrap();
+        counter.start().unwrap();
+        let snapshot = counter.read().unwrap();
+        assert_eq!(snapshot[Event::Named("L1D_CACHE_MISS_LD".to_string())], 3);
+        assert_eq!(snapshot[Event::Named("Cycles".to_string())], 10);
+    }
+
+    #[test]
+    fn test_unknown_named_event() {
+        let result = PerfCounterBuilder::try_with_backend(SimulatedBackend::default())
+            .unwrap()
+            .track_event(Event::Named("MEM_LOAD_RETIRED.L3_MISS".to_string()))
+            .build_counter();
+        match result {
+            Err(KperfError::EventNotFound { event, database }) => {
+                assert_eq!(event, "MEM_LOAD_RETIRED.L3_MISS");
+                assert_eq!(database.as_deref(), Some("a15"));
+            }
+            _ => panic!("Expected EventNotFound"),
+        }
+    }
 }
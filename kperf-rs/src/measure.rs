@@ -0,0 +1,38 @@
+use crate::backend::CounterBackend;
+use crate::error::KperfError;
+use crate::snapshot::CounterSnapshot;
+use crate::PerfCounter;
+
+/// Measurement of a [`PerfCounter`] for as long as the guard lives.
+///
+/// The counter is started when the guard is created, and stopped and read when it is finished or
+/// dropped, also when unwinding from a panic. The values counted are then available from
+/// [`PerfCounter::last_measurement`].
+pub struct MeasureGuard<'a, B: CounterBackend> {
+    counter: &'a mut PerfCounter<B>,
+    finished: bool,
+}
+
+impl<'a, B: CounterBackend> MeasureGuard<'a, B> {
+    pub(crate) fn new(counter: &'a mut PerfCounter<B>) -> Result<Self, KperfError> {
+        counter.begin_measurement()?;
+        Ok(Self {
+            counter,
+            finished: false,
+        })
+    }
+
+    /// Stop the measurement and return what was counted since the guard was created.
+    pub fn finish(mut self) -> Result<CounterSnapshot, KperfError> {
+        self.finished = true;
+        self.counter.end_measurement()
+    }
+}
+
+impl<B: CounterBackend> Drop for MeasureGuard<'_, B> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.counter.end_measurement();
+        }
+    }
+}
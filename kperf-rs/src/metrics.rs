@@ -0,0 +1,460 @@
+use crate::error::MetricError;
+use crate::event::Event;
+use crate::snapshot::CounterSnapshot;
+use std::fmt;
+use std::fmt::Formatter;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+/// Arithmetic expression over event counts.
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Number(f64),
+    Event(Event),
+    Neg(Box<Expr>),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+    Mul(Box<Expr>, Box<Expr>),
+    Div(Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    /// `None` if an event is missing from `snapshot`, was counted as a substitute, or a division
+    /// by zero occurs.
+    fn evaluate(&self, snapshot: &CounterSnapshot) -> Option<f64> {
+        match self {
+            Expr::Number(value) => Some(*value),
+            Expr::Event(event) if snapshot.substitute(event).is_some() => None,
+            Expr::Event(event) => snapshot.get(event).map(|value| value as f64),
+            Expr::Neg(expr) => Some(-expr.evaluate(snapshot)?),
+            Expr::Add(lhs, rhs) => Some(lhs.evaluate(snapshot)? + rhs.evaluate(snapshot)?),
+            Expr::Sub(lhs, rhs) => Some(lhs.evaluate(snapshot)? - rhs.evaluate(snapshot)?),
+            Expr::Mul(lhs, rhs) => Some(lhs.evaluate(snapshot)? * rhs.evaluate(snapshot)?),
+            Expr::Div(lhs, rhs) => {
+                let divisor = rhs.evaluate(snapshot)?;
+                if divisor == 0.0 {
+                    return None;
+                }
+                Some(lhs.evaluate(snapshot)? / divisor)
+            }
+        }
+    }
+
+    fn events<'a>(&'a self, events: &mut Vec<&'a Event>) {
+        match self {
+            Expr::Number(_) => {}
+            Expr::Event(event) => {
+                if !events.contains(&event) {
+                    events.push(event);
+                }
+            }
+            Expr::Neg(expr) => expr.events(events),
+            Expr::Add(lhs, rhs)
+            | Expr::Sub(lhs, rhs)
+            | Expr::Mul(lhs, rhs)
+            | Expr::Div(lhs, rhs) => {
+                lhs.events(events);
+                rhs.events(events);
+            }
+        }
+    }
+}
+
+/// Recursive descent parser of formulas:
+/// ```text
+/// expr   := term (('+' | '-') term)*
+/// term   := factor (('*' | '/') factor)*
+/// factor := number | event | '-' factor | '(' expr ')'
+/// event  := [A-Za-z_][A-Za-z0-9_.:]* | '"' [^"]* '"'
+/// ```
+struct Parser<'a> {
+    formula: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn parse(formula: &'a str) -> Result<Expr, MetricError> {
+        let mut parser = Self {
+            formula,
+            chars: formula.char_indices().peekable(),
+        };
+        let expr = parser.expr()?;
+        match parser.next_token_start() {
+            Some((position, c)) => Err(parser.error(position, format!("unexpected {:?}", c))),
+            None => Ok(expr),
+        }
+    }
+
+    fn error(&self, position: usize, message: String) -> MetricError {
+        MetricError::Parse {
+            formula: self.formula.to_string(),
+            position,
+            message,
+        }
+    }
+
+    fn next_token_start(&mut self) -> Option<(usize, char)> {
+        while self.chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+        self.chars.peek().copied()
+    }
+
+    fn expr(&mut self) -> Result<Expr, MetricError> {
+        let mut expr = self.term()?;
+        loop {
+            match self.next_token_start() {
+                Some((_, '+')) => {
+                    self.chars.next();
+                    expr = Expr::Add(Box::new(expr), Box::new(self.term()?));
+                }
+                Some((_, '-')) => {
+                    self.chars.next();
+                    expr = Expr::Sub(Box::new(expr), Box::new(self.term()?));
+                }
+                _ => return Ok(expr),
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, MetricError> {
+        let mut expr = self.factor()?;
+        loop {
+            match self.next_token_start() {
+                Some((_, '*')) => {
+                    self.chars.next();
+                    expr = Expr::Mul(Box::new(expr), Box::new(self.factor()?));
+                }
+                Some((_, '/')) => {
+                    self.chars.next();
+                    expr = Expr::Div(Box::new(expr), Box::new(self.factor()?));
+                }
+                _ => return Ok(expr),
+            }
+        }
+    }
+
+    fn factor(&mut self) -> Result<Expr, MetricError> {
+        match self.next_token_start() {
+            None => Err(self.error(self.formula.len(), "unexpected end".to_string())),
+            Some((_, '-')) => {
+                self.chars.next();
+                Ok(Expr::Neg(Box::new(self.factor()?)))
+            }
+            Some((position, '(')) => {
+                self.chars.next();
+                let expr = self.expr()?;
+                match self.next_token_start() {
+                    Some((_, ')')) => {
+                        self.chars.next();
+                        Ok(expr)
+                    }
+                    _ => Err(self.error(position, "unclosed parenthesis".to_string())),
+                }
+            }
+            Some((position, '"')) => {
+                self.chars.next();
+                let start = position + 1;
+                for (end, c) in self.chars.by_ref() {
+                    if c == '"' {
+                        let name = &self.formula[start..end];
+                        return Ok(Expr::Event(name.parse().unwrap()));
+                    }
+                }
+                Err(self.error(position, "unclosed quote".to_string()))
+            }
+            Some((start, c)) if c.is_ascii_digit() || c == '.' => {
+                let end = self.take_while(|c| c.is_ascii_digit() || c == '.');
+                self.formula[start..end]
+                    .parse()
+                    .map(Expr::Number)
+                    .map_err(|_| self.error(start, "invalid number".to_string()))
+            }
+            Some((start, c)) if c.is_ascii_alphabetic() || c == '_' => {
+                let end = self.take_while(|c| c.is_ascii_alphanumeric() || "_.:".contains(c));
+                Ok(Expr::Event(self.formula[start..end].parse().unwrap()))
+            }
+            Some((position, c)) => Err(self.error(position, format!("unexpected {:?}", c))),
+        }
+    }
+
+    /// Consume the characters matching `predicate`, returns the end of what was consumed.
+    fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> usize {
+        while self.chars.next_if(|&(_, c)| predicate(c)).is_some() {}
+        self.chars
+            .peek()
+            .map_or(self.formula.len(), |&(position, _)| position)
+    }
+}
+
+/// A value derived from event counts, such as instructions per cycle.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metric {
+    name: String,
+    formula: String,
+    expr: Expr,
+}
+
+impl Metric {
+    /// Metric computed by `formula`, an arithmetic expression over numbers and event names.
+    ///
+    /// Names are parsed as [`Event`]s (`Cycles`, `Instructions`, `L1D_CACHE_MISS_LD`, ...).
+    /// Names with other characters than letters, digits, `_`, `.` and `:` must be in double
+    /// quotes, such as `"cpu-cycles"`.
+    pub fn new(name: &str, formula: &str) -> Result<Self, MetricError> {
+        Ok(Self {
+            name: name.to_string(),
+            formula: formula.to_string(),
+            expr: Parser::parse(formula)?,
+        })
+    }
+
+    fn builtin(name: &str, formula: &str) -> Self {
+        Self::new(name, formula).expect("Built-in formulas are valid")
+    }
+
+    /// Instructions per cycle.
+    pub fn ipc() -> Self {
+        Self::builtin("IPC", "Instructions / Cycles")
+    }
+
+    /// Cycles per instruction.
+    pub fn cpi() -> Self {
+        Self::builtin("CPI", "Cycles / Instructions")
+    }
+
+    /// Fraction of the branches that were mispredicted.
+    pub fn branch_miss_ratio() -> Self {
+        Self::builtin("branch miss ratio", "BranchMisses / Branches")
+    }
+
+    /// Branches per thousand instructions.
+    pub fn branches_per_kilo_instruction() -> Self {
+        Self::builtin(
+            "branches per kilo-instruction",
+            "1000 * Branches / Instructions",
+        )
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn formula(&self) -> &str {
+        &self.formula
+    }
+
+    /// Every event the formula uses.
+    pub fn events(&self) -> Vec<&Event> {
+        let mut events = Vec::new();
+        self.expr.events(&mut events);
+        events
+    }
+
+    /// Value of the metric for `snapshot`, `None` if an event is missing from it or was counted as
+    /// its [`Event::software_substitute`], or the formula divides by zero.
+    pub fn evaluate(&self, snapshot: &CounterSnapshot) -> Option<f64> {
+        self.expr.evaluate(snapshot)
+    }
+}
+
+/// Metrics over a fixed set of tracked events, checked when they are added.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    tracked_events: Vec<Event>,
+    metrics: Vec<Metric>,
+}
+
+impl Metrics {
+    pub fn new(tracked_events: &[Event]) -> Self {
+        Self {
+            tracked_events: tracked_events.to_vec(),
+            metrics: Vec::new(),
+        }
+    }
+
+    /// Every built-in metric the tracked events allow computing.
+    pub fn builtins(tracked_events: &[Event]) -> Self {
+        let mut metrics = Self::new(tracked_events);
+        for metric in [
+            Metric::ipc(),
+            Metric::cpi(),
+            Metric::branch_miss_ratio(),
+            Metric::branches_per_kilo_instruction(),
+        ] {
+            // Metrics over untracked events are left out on purpose
+            let _ = metrics.add(metric);
+        }
+        metrics
+    }
+
+    /// Add `metric`, failing if it uses an event that isn't tracked.
+    pub fn add(&mut self, metric: Metric) -> Result<(), MetricError> {
+        if let Some(event) = metric
+            .events()
+            .into_iter()
+            .find(|event| !self.tracked_events.contains(event))
+        {
+            return Err(MetricError::UntrackedEvent {
+                metric: metric.name.clone(),
+                event: event.to_string(),
+            });
+        }
+        self.metrics.push(metric);
+        Ok(())
+    }
+
+    pub fn with(mut self, metric: Metric) -> Result<Self, MetricError> {
+        self.add(metric)?;
+        Ok(self)
+    }
+
+    pub fn metrics(&self) -> &[Metric] {
+        &self.metrics
+    }
+
+    /// Value of every metric for `snapshot`, in the order they were added.
+    pub fn evaluate(&self, snapshot: &CounterSnapshot) -> MetricValues {
+        MetricValues {
+            values: self
+                .metrics
+                .iter()
+                .map(|metric| (metric.name.clone(), metric.evaluate(snapshot)))
+                .collect(),
+        }
+    }
+}
+
+/// Values computed by [`Metrics::evaluate`], `None` where a formula divided by zero.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MetricValues {
+    values: Vec<(String, Option<f64>)>,
+}
+
+impl MetricValues {
+    pub fn get(&self, name: &str) -> Option<f64> {
+        self.values
+            .iter()
+            .find(|(metric, _)| metric == name)
+            .and_then(|(_, value)| *value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Option<f64>)> {
+        self.values
+            .iter()
+            .map(|(metric, value)| (metric.as_str(), *value))
+    }
+}
+
+impl fmt::Display for MetricValues {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (metric, value) in &self.values {
+            match value {
+                Some(value) => writeln!(f, "{}: {:.3}", metric, value)?,
+                None => writeln!(f, "{}: n/a", metric)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> CounterSnapshot {
+        CounterSnapshot::new(vec![
+            (Event::Cycles, 400),
+            (Event::Instructions, 800),
+            (Event::Branches, 100),
+            (Event::BranchMisses, 0),
+            (Event::Named("L1D_CACHE_MISS_LD".to_string()), 20),
+            (Event::Named("cpu-cycles".to_string()), 200),
+        ])
+    }
+
+    #[test]
+    fn test_builtin_metrics() {
+        let values = Metrics::builtins(&[
+            Event::Cycles,
+            Event::Instructions,
+            Event::Branches,
+            Event::BranchMisses,
+        ])
+        .evaluate(&snapshot());
+        assert_eq!(values.get("IPC"), Some(2.0));
+        assert_eq!(values.get("CPI"), Some(0.5));
+        assert_eq!(values.get("branch miss ratio"), Some(0.0));
+        assert_eq!(values.get("branches per kilo-instruction"), Some(125.0));
+
+        let values = Metrics::builtins(&[Event::Cycles, Event::Instructions]).evaluate(&snapshot());
+        assert_eq!(values.iter().count(), 2);
+    }
+
+    #[test]
+    fn test_substituted_events_have_no_value() {
+        let mut substitutes = vec![None; 6];
+        substitutes[0] = Some(Event::TaskClock);
+        let snapshot = snapshot().with_substitutes(substitutes);
+        let values = Metrics::builtins(&[
+            Event::Cycles,
+            Event::Instructions,
+            Event::Branches,
+            Event::BranchMisses,
+        ])
+        .evaluate(&snapshot);
+        assert_eq!(values.get("IPC"), None);
+        assert_eq!(values.get("CPI"), None);
+        assert_eq!(values.get("branch miss ratio"), Some(0.0));
+    }
+
+    #[test]
+    fn test_formula() {
+        let metric = Metric::new(
+            "misses per kilo-cycle",
+            "L1D_CACHE_MISS_LD * 1000 / (Cycles - -\"cpu-cycles\" * 0.5 - 100)",
+        )
+        .unwrap();
+        assert_eq!(
+            metric.events(),
+            [
+                &Event::Named("L1D_CACHE_MISS_LD".to_string()),
+                &Event::Cycles,
+                &Event::Named("cpu-cycles".to_string())
+            ]
+        );
+        assert_eq!(metric.evaluate(&snapshot()), Some(50.0));
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let metric = Metric::new("inverse", "Branches / BranchMisses").unwrap();
+        assert_eq!(metric.evaluate(&snapshot()), None);
+        let metric = Metric::new("inverse", "Branches / (Cycles - 400)").unwrap();
+        assert_eq!(metric.evaluate(&snapshot()), None);
+    }
+
+    #[test]
+    fn test_invalid_formulas() {
+        for (formula, position) in [("Cycles /", 8), ("(Cycles", 0), ("Cycles $", 7), ("\"a", 0)] {
+            match Metric::new("invalid", formula) {
+                Err(MetricError::Parse { position: p, .. }) => {
+                    assert_eq!(p, position, "{}", formula)
+                }
+                _ => panic!("{} should not parse", formula),
+            }
+        }
+    }
+
+    #[test]
+    fn test_untracked_event() {
+        let metrics = Metrics::new(&[Event::Cycles, Event::Instructions])
+            .with(Metric::ipc())
+            .unwrap();
+        assert_eq!(
+            metrics.with(Metric::branch_miss_ratio()).unwrap_err(),
+            MetricError::UntrackedEvent {
+                metric: "branch miss ratio".to_string(),
+                event: "BranchMisses".to_string(),
+            }
+        );
+    }
+}
@@ -0,0 +1,473 @@
+use crate::backend::CounterBackend;
+use crate::error::KperfError;
+use crate::event::{CountingMode, Event};
+use crate::kperf::{counter_delta, KProbesConfig, KProbesDatabase, KpcStateGuard};
+use crate::snapshot::CounterSnapshot;
+use crate::KPC_MAX_COUNTERS;
+use libc::{c_uint, c_ulonglong};
+use std::fmt;
+use std::fmt::Formatter;
+use std::time::{Duration, Instant};
+
+/// When a [`MultiplexedCounter`] switches to its next group of events.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Multiplexing {
+    /// Every time the counter is started again.
+    PerRepetition,
+    /// Once the current group counted for the given time, over one or more start/stop pairs.
+    ///
+    /// Checked whenever the counter is read, stopped or ticked: nothing rotates the groups in
+    /// the background, so long regions should call [`MultiplexedCounter::tick`] regularly.
+    TimeSlice(Duration),
+}
+
+/// Events counted at the same time, and what they counted while their group was active.
+struct CounterGroup<B: CounterBackend> {
+    config: KProbesConfig<B>,
+    counter_idxs: Vec<usize>,
+    /// Width of each counter kpc reads for the classes of the group.
+    counter_bits: Vec<c_uint>,
+    values: Vec<u64>,
+    running: Duration,
+}
+
+/// Counter for more events than the hardware has counters, by rotating groups of events that fit
+/// on the counters together.
+///
+/// Every value comes with the time its group was active ("running") and the time the counter
+/// was active at all ("enabled"), see [`MultiplexedValue::estimate`].
+pub struct MultiplexedCounter<B: CounterBackend> {
+    // Dropped first, so the kpc state is restored before the kpep objects are freed
+    _kpc_state: KpcStateGuard<B>,
+    groups: Vec<CounterGroup<B>>,
+    kprobes_db: KProbesDatabase<B>,
+    tracked_events: Vec<Event>,
+    counting_modes: Vec<CountingMode>,
+    multiplexing: Multiplexing,
+    active: usize,
+    started: bool,
+    repetitions: usize,
+    counters_start: [c_ulonglong; KPC_MAX_COUNTERS],
+    slice_start: Duration,
+    /// Time the active group had run when it became active.
+    slice_origin: Duration,
+    clock: Box<dyn FnMut() -> Duration>,
+}
+
+impl<B: CounterBackend> MultiplexedCounter<B> {
+    pub(crate) fn new(
+        mut kprobes_db: KProbesDatabase<B>,
+        tracked_events: Vec<Event>,
+        counting_modes: Vec<CountingMode>,
+        multiplexing: Multiplexing,
+    ) -> Result<Self, KperfError> {
+        let events: Vec<_> = tracked_events
+            .iter()
+            .cloned()
+            .zip(counting_modes.iter().copied())
+            .collect();
+        let mut groups = Vec::new();
+        for mut config in kprobes_db.group_configs(&events)? {
+            config.fill_config_variables()?;
+            let counter_idxs = (0..config.events().len())
+                .map(|i| config.get_counter_index(i))
+                .collect();
+            let counter_bits = kprobes_db.counter_bits(config.classes(), KPC_MAX_COUNTERS);
+            groups.push(CounterGroup {
+                values: vec![0; config.events().len()],
+                config,
+                counter_idxs,
+                counter_bits,
+                running: Duration::ZERO,
+            });
+        }
+
+        let kpc_state = KpcStateGuard::save(kprobes_db.backend())?;
+        let res = unsafe { kprobes_db.backend().kpc_force_all_ctrs_set(1) };
+        if res != 0 {
+            return Err(KperfError::PerfCounterBuildError(format!(
+                "Failed to force_all_ctrs_set, error: {}",
+                res
+            )));
+        }
+
+        let origin = Instant::now();
+        Ok(Self {
+            _kpc_state: kpc_state,
+            groups,
+            kprobes_db,
+            tracked_events,
+            counting_modes,
+            multiplexing,
+            active: 0,
+            started: false,
+            repetitions: 0,
+            counters_start: [0; KPC_MAX_COUNTERS],
+            slice_start: Duration::ZERO,
+            slice_origin: Duration::ZERO,
+            clock: Box::new(move || origin.elapsed()),
+        })
+    }
+
+    /// Measure the time groups are active with `clock` instead of the monotonic clock.
+    /// It must never go backwards.
+    pub fn set_clock<F: FnMut() -> Duration + 'static>(&mut self, clock: F) {
+        self.clock = Box::new(clock);
+    }
+
+    pub fn tracked_events(&self) -> &[Event] {
+        &self.tracked_events
+    }
+
+    /// Events of every group, in rotation order.
+    pub fn groups(&self) -> impl Iterator<Item = &[Event]> {
+        self.groups.iter().map(|group| group.config.events())
+    }
+
+    fn read_counters(&self) -> Result<[c_ulonglong; KPC_MAX_COUNTERS], KperfError> {
+        let mut counters = [0; KPC_MAX_COUNTERS];
+        let res = unsafe {
+            self.kprobes_db.backend().kpc_get_thread_counters(
+                0,
+                KPC_MAX_COUNTERS as c_uint,
+                counters.as_mut_ptr(),
+            )
+        };
+        if res != 0 {
+            return Err(KperfError::UnknownError(format!(
+                "Failed to get thread counters, error: {}",
+                res
+            )));
+        }
+        Ok(counters)
+    }
+
+    /// Program the active group on the counters and start counting it.
+    fn start_group(&mut self) -> Result<(), KperfError> {
+        let config = &mut self.groups[self.active].config;
+        config.set_kpc_config()?;
+        config.start_kpc_counting()?;
+        config.start_kpc_thread_counting()?;
+        self.counters_start = self.read_counters()?;
+        self.slice_start = (self.clock)();
+        Ok(())
+    }
+
+    /// Add what the active group counted since it started or was last accumulated.
+    fn accumulate(&mut self) -> Result<(), KperfError> {
+        let counters_end = self.read_counters()?;
+        let now = (self.clock)();
+        let group = &mut self.groups[self.active];
+        for (value, &idx) in group.values.iter_mut().zip(&group.counter_idxs) {
+            let bits = group.counter_bits[idx];
+            *value += counter_delta(self.counters_start[idx], counters_end[idx], bits)?.0;
+        }
+        group.running += now - self.slice_start;
+        self.counters_start = counters_end;
+        self.slice_start = now;
+        Ok(())
+    }
+
+    /// Switch to the next group if the active one used up its time slice, right after the
+    /// active group was accumulated.
+    fn rotate_if_due(&mut self) -> Result<(), KperfError> {
+        let Multiplexing::TimeSlice(slice) = self.multiplexing else {
+            return Ok(());
+        };
+        if self.groups[self.active].running - self.slice_origin < slice {
+            return Ok(());
+        }
+        if self.started {
+            self.groups[self.active].config.stop_kpc_thread_counting()?;
+        }
+        self.active = (self.active + 1) % self.groups.len();
+        self.slice_origin = self.groups[self.active].running;
+        if self.started {
+            self.start_group()?;
+        }
+        Ok(())
+    }
+
+    pub fn start(&mut self) -> Result<(), KperfError> {
+        if self.started {
+            return Ok(());
+        }
+        if self.multiplexing == Multiplexing::PerRepetition && self.repetitions > 0 {
+            self.active = (self.active + 1) % self.groups.len();
+        }
+        self.start_group()?;
+        self.started = true;
+        self.repetitions += 1;
+        Ok(())
+    }
+
+    pub fn stop(&mut self) -> Result<(), KperfError> {
+        if !self.started {
+            return Ok(());
+        }
+        self.accumulate()?;
+        self.groups[self.active].config.stop_kpc_thread_counting()?;
+        self.started = false;
+        self.rotate_if_due()
+    }
+
+    /// Switch to the next group if the active one used up its time slice.
+    ///
+    /// Reads and stops check it too, call it from long regions that do neither.
+    pub fn tick(&mut self) -> Result<(), KperfError> {
+        if !self.started || !matches!(self.multiplexing, Multiplexing::TimeSlice(_)) {
+            return Ok(());
+        }
+        self.accumulate()?;
+        self.rotate_if_due()
+    }
+
+    pub fn read(&mut self) -> Result<MultiplexedSnapshot, KperfError> {
+        if self.started {
+            self.accumulate()?;
+            self.rotate_if_due()?;
+        }
+        let enabled = self.groups.iter().map(|group| group.running).sum();
+        let values = self
+            .tracked_events
+            .iter()
+            .map(|event| {
+                let (group, idx) = self
+                    .groups
+                    .iter()
+                    .find_map(|group| {
+                        let idx = group.config.events().iter().position(|e| e == event)?;
+                        Some((group, idx))
+                    })
+                    .expect("Every tracked event is in a group");
+                let value = MultiplexedValue {
+                    raw: group.values[idx],
+                    running: group.running,
+                    enabled,
+                };
+                (event.clone(), value)
+            })
+            .collect();
+        Ok(MultiplexedSnapshot {
+            values,
+            counting_modes: self.counting_modes.clone(),
+        })
+    }
+}
+
+/// Value counted by a [`MultiplexedCounter`] during part of the measurement.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MultiplexedValue {
+    /// What the event counted while its group was active.
+    pub raw: u64,
+    /// Time the event's group was active.
+    pub running: Duration,
+    /// Time the counter was active, whatever the group.
+    pub enabled: Duration,
+}
+
+impl MultiplexedValue {
+    /// Fraction of the measurement the event was actually counted, 1 if it always was.
+    pub fn ratio(&self) -> f64 {
+        if self.enabled.is_zero() {
+            return 0.0;
+        }
+        self.running.as_secs_f64() / self.enabled.as_secs_f64()
+    }
+
+    /// What the event would have counted during the whole measurement, extrapolated from `raw`.
+    /// 0 if the event was never counted.
+    pub fn estimate(&self) -> u64 {
+        if self.running.is_zero() {
+            return 0;
+        }
+        (self.raw as u128 * self.enabled.as_nanos() / self.running.as_nanos()) as u64
+    }
+}
+
+impl fmt::Display for MultiplexedValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({:.2}%)", self.estimate(), self.ratio() * 100.0)
+    }
+}
+
+/// Values of every event tracked by a [`MultiplexedCounter`], in the order they were tracked.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MultiplexedSnapshot {
+    values: Vec<(Event, MultiplexedValue)>,
+    counting_modes: Vec<CountingMode>,
+}
+
+impl MultiplexedSnapshot {
+    pub fn get(&self, event: &Event) -> Option<MultiplexedValue> {
+        self.values
+            .iter()
+            .find(|(tracked, _)| tracked == event)
+            .map(|(_, value)| *value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Event, MultiplexedValue)> {
+        self.values.iter().map(|(event, value)| (event, *value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Scaled estimate of every event, see [`MultiplexedValue::estimate`].
+    pub fn estimates(&self) -> CounterSnapshot {
+        CounterSnapshot::new(
+            self.values
+                .iter()
+                .map(|(event, value)| (event.clone(), value.estimate()))
+                .collect(),
+        )
+        .with_counting_modes(self.counting_modes.clone())
+    }
+}
+
+impl fmt::Display for MultiplexedSnapshot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for ((event, value), mode) in self.values.iter().zip(&self.counting_modes) {
+            writeln!(f, "{}{}: {}", event, mode, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{SimulatedBackend, SimulatedDatabase};
+    use crate::{PerfCounterBuilder, Track};
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    fn multiplexed_counter(
+        multiplexing: Multiplexing,
+    ) -> (MultiplexedCounter<SimulatedBackend>, Rc<Cell<u64>>) {
+        let backend = SimulatedBackend::new(SimulatedDatabase {
+            config_counter_count: 2,
+            ..SimulatedDatabase::apple_m2()
+        });
+        backend.set_counter_source(|_| 10);
+        let mut counter = PerfCounterBuilder::try_with_backend(backend)
+            .unwrap()
+            .track_events([Event::Cycles, Event::Branches, Event::BranchMisses])
+            .track_event(Event::Named("INST_ALL".to_string()))
+            .build_multiplexed(multiplexing)
+            .unwrap();
+        let millis = Rc::new(Cell::new(0));
+        let clock = millis.clone();
+        counter.set_clock(move || Duration::from_millis(clock.get()));
+        (counter, millis)
+    }
+
+    #[test]
+    fn test_rotate_per_repetition() {
+        let (mut counter, millis) = multiplexed_counter(Multiplexing::PerRepetition);
+        assert_eq!(counter.groups().count(), 2);
+
+        counter.start().unwrap();
+        millis.set(10);
+        counter.stop().unwrap();
+        counter.start().unwrap();
+        millis.set(40);
+        counter.stop().unwrap();
+
+        let snapshot = counter.read().unwrap();
+        let cycles = snapshot.get(&Event::Cycles).unwrap();
+        assert_eq!(cycles.raw, 10);
+        assert_eq!(cycles.running, Duration::from_millis(10));
+        assert_eq!(cycles.enabled, Duration::from_millis(40));
+        assert_eq!(cycles.ratio(), 0.25);
+        assert_eq!(cycles.estimate(), 40);
+
+        let inst_all = snapshot.get(&Event::Named("INST_ALL".to_string())).unwrap();
+        assert_eq!(inst_all.raw, 10);
+        assert_eq!(inst_all.ratio(), 0.75);
+        assert_eq!(inst_all.estimate(), 13);
+        assert_eq!(snapshot.estimates()[Event::BranchMisses], 40);
+    }
+
+    #[test]
+    fn test_rotate_on_time_slice() {
+        let (mut counter, millis) =
+            multiplexed_counter(Multiplexing::TimeSlice(Duration::from_millis(5)));
+        counter.start().unwrap();
+        millis.set(3);
+        counter.tick().unwrap();
+        millis.set(6);
+        counter.tick().unwrap();
+        millis.set(10);
+        counter.stop().unwrap();
+
+        let snapshot = counter.read().unwrap();
+        let branches = snapshot.get(&Event::Branches).unwrap();
+        assert_eq!(branches.running, Duration::from_millis(6));
+        assert_eq!(branches.enabled, Duration::from_millis(10));
+        let inst_all = snapshot.get(&Event::Named("INST_ALL".to_string())).unwrap();
+        assert_eq!(inst_all.running, Duration::from_millis(4));
+        assert_eq!(inst_all.raw, 10);
+    }
+
+    #[test]
+    fn test_rotate_on_time_slice_without_ticks() {
+        let (mut counter, millis) =
+            multiplexed_counter(Multiplexing::TimeSlice(Duration::from_millis(5)));
+        // The slice adds up over repetitions, the group rotates on the stop using it up
+        counter.start().unwrap();
+        millis.set(3);
+        counter.stop().unwrap();
+        counter.start().unwrap();
+        millis.set(6);
+        counter.stop().unwrap();
+        counter.start().unwrap();
+        millis.set(8);
+        let snapshot = counter.read().unwrap();
+        assert_eq!(
+            snapshot.get(&Event::Cycles).unwrap().running,
+            Duration::from_millis(6)
+        );
+        let inst_all = snapshot.get(&Event::Named("INST_ALL".to_string())).unwrap();
+        assert_eq!(inst_all.running, Duration::from_millis(2));
+
+        // Reads rotate a running counter
+        millis.set(11);
+        counter.read().unwrap();
+        millis.set(13);
+        counter.stop().unwrap();
+        let snapshot = counter.read().unwrap();
+        assert_eq!(
+            snapshot.get(&Event::Cycles).unwrap().running,
+            Duration::from_millis(8)
+        );
+        // Counted over three start/stop pairs
+        assert_eq!(snapshot.get(&Event::Cycles).unwrap().raw, 30);
+    }
+
+    #[test]
+    fn test_only_thread_is_multiplexed() {
+        let result = PerfCounterBuilder::try_with_backend(SimulatedBackend::default())
+            .unwrap()
+            .track(Track::AllCpus)
+            .build_multiplexed(Multiplexing::PerRepetition);
+        assert!(matches!(result, Err(KperfError::PerfCounterBuildError(_))));
+    }
+
+    #[test]
+    fn test_never_counted_event() {
+        let (mut counter, millis) = multiplexed_counter(Multiplexing::PerRepetition);
+        counter.start().unwrap();
+        millis.set(10);
+        let snapshot = counter.read().unwrap();
+        let inst_all = snapshot.get(&Event::Named("INST_ALL".to_string())).unwrap();
+        assert_eq!(inst_all.ratio(), 0.0);
+        assert_eq!(inst_all.estimate(), 0);
+        assert_eq!(snapshot.get(&Event::Cycles).unwrap().ratio(), 1.0);
+    }
+}
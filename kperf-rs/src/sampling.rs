@@ -0,0 +1,533 @@
+use crate::backend::CounterBackend;
+#[cfg(target_os = "macos")]
+use crate::backend::KperfBackend;
+use crate::error::KperfError;
+use crate::timebase::Timebase;
+use kperf_sys::constants::{
+    KPERF_ACTION_MAX, KPERF_SAMPLER_KSTACK, KPERF_SAMPLER_MEMINFO, KPERF_SAMPLER_PMC_CONFIG,
+    KPERF_SAMPLER_PMC_CPU, KPERF_SAMPLER_PMC_THREAD, KPERF_SAMPLER_SYS_MEM,
+    KPERF_SAMPLER_TH_DISPATCH, KPERF_SAMPLER_TH_INFO, KPERF_SAMPLER_TH_INSCYC,
+    KPERF_SAMPLER_TH_SCHEDULING, KPERF_SAMPLER_TH_SNAPSHOT, KPERF_SAMPLER_TK_INFO,
+    KPERF_SAMPLER_TK_SNAPSHOT, KPERF_SAMPLER_USTACK, KPERF_TIMER_MAX,
+};
+use libc::{c_int, c_uint, EACCES, EPERM};
+use std::fmt;
+use std::fmt::Formatter;
+use std::io;
+use std::ops::{BitOr, BitOrAssign};
+use std::time::Duration;
+
+/// What kperf records when an action fires, a combination of the `KPERF_SAMPLER_*` flags.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Samplers(u32);
+
+impl Samplers {
+    /// Thread info: pid, tid, run mode...
+    pub const THREAD_INFO: Self = Self(KPERF_SAMPLER_TH_INFO);
+    pub const THREAD_SNAPSHOT: Self = Self(KPERF_SAMPLER_TH_SNAPSHOT);
+    /// Kernel callstack.
+    pub const KERNEL_STACK: Self = Self(KPERF_SAMPLER_KSTACK);
+    /// User callstack.
+    pub const USER_STACK: Self = Self(KPERF_SAMPLER_USTACK);
+    /// Counters of the sampled thread.
+    pub const PMC_THREAD: Self = Self(KPERF_SAMPLER_PMC_THREAD);
+    /// Counters of the CPU the action fired on.
+    pub const PMC_CPU: Self = Self(KPERF_SAMPLER_PMC_CPU);
+    pub const PMC_CONFIG: Self = Self(KPERF_SAMPLER_PMC_CONFIG);
+    pub const MEMINFO: Self = Self(KPERF_SAMPLER_MEMINFO);
+    pub const THREAD_SCHEDULING: Self = Self(KPERF_SAMPLER_TH_SCHEDULING);
+    pub const THREAD_DISPATCH: Self = Self(KPERF_SAMPLER_TH_DISPATCH);
+    pub const TASK_SNAPSHOT: Self = Self(KPERF_SAMPLER_TK_SNAPSHOT);
+    pub const SYSTEM_MEMORY: Self = Self(KPERF_SAMPLER_SYS_MEM);
+    /// Instructions and cycles of the sampled thread.
+    pub const THREAD_INSTRUCTIONS_CYCLES: Self = Self(KPERF_SAMPLER_TH_INSCYC);
+    pub const TASK_INFO: Self = Self(KPERF_SAMPLER_TK_INFO);
+
+    const ALL: u32 = (KPERF_SAMPLER_TK_INFO << 1) - 1;
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Samplers of the `bits` flags, `None` if some of them aren't `KPERF_SAMPLER_*` flags.
+    pub const fn from_bits(bits: u32) -> Option<Self> {
+        if bits & !Self::ALL != 0 {
+            None
+        } else {
+            Some(Self(bits))
+        }
+    }
+
+    /// Samplers of the `KPERF_SAMPLER_*` flags in `bits`, ignoring the others.
+    pub const fn from_bits_truncate(bits: u32) -> Self {
+        Self(bits & Self::ALL)
+    }
+
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub const fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitOr for Samplers {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for Samplers {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+/// Id of an action of a sampling session. kperf numbers actions from 1, in the order they were
+/// added to the [`SamplingSessionBuilder`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ActionId(pub u32);
+
+impl fmt::Display for ActionId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "action {}", self.0)
+    }
+}
+
+/// Timer firing `action` every `period`, `ticks` long in the timebase of the session.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SamplingTimer {
+    pub period: Duration,
+    pub ticks: u64,
+    pub action: ActionId,
+}
+
+/// How the PET timer of a session samples threads.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum PetMode {
+    /// Profile every thread: each time the timer fires, every thread is sampled, running or not.
+    #[default]
+    Pet,
+    /// Threads are sampled as they go on and off CPU during each period instead, which costs
+    /// less than waking up to sample all of them.
+    Lightweight,
+}
+
+pub struct SamplingSessionBuilder<B: CounterBackend> {
+    backend: B,
+    timebase: Option<Timebase>,
+    actions: Vec<Samplers>,
+    timers: Vec<(Duration, ActionId)>,
+    /// Index and mode of every timer added with `pet`.
+    pet_timers: Vec<(usize, PetMode)>,
+}
+
+#[cfg(target_os = "macos")]
+impl SamplingSessionBuilder<KperfBackend> {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self::with_backend(KperfBackend)
+    }
+}
+
+impl<B: CounterBackend> SamplingSessionBuilder<B> {
+    pub fn with_backend(backend: B) -> Self {
+        Self {
+            backend,
+            timebase: None,
+            actions: Vec::new(),
+            timers: Vec::new(),
+            pet_timers: Vec::new(),
+        }
+    }
+
+    /// Convert timer periods to ticks with `timebase` instead of the one of the system, which
+    /// is only known on macOS.
+    pub fn timebase(mut self, timebase: Timebase) -> Self {
+        self.timebase = Some(timebase);
+        self
+    }
+
+    /// Add an action recording `samplers`, with the next [`ActionId`].
+    pub fn action(mut self, samplers: Samplers) -> Self {
+        self.actions.push(samplers);
+        self
+    }
+
+    /// Add a timer firing `action` every `period`.
+    pub fn timer(mut self, period: Duration, action: ActionId) -> Self {
+        self.timers.push((period, action));
+        self
+    }
+
+    /// Add an action recording `samplers`, and a timer firing it every `period`.
+    pub fn sample_every(self, period: Duration, samplers: Samplers) -> Self {
+        let action = ActionId(self.actions.len() as u32 + 1);
+        self.action(samplers).timer(period, action)
+    }
+
+    /// Add an action recording `samplers`, and a timer sampling every thread with it every
+    /// `period`. kperf has a single PET timer, so this can only be used once per session.
+    pub fn pet(mut self, period: Duration, samplers: Samplers, mode: PetMode) -> Self {
+        self.pet_timers.push((self.timers.len(), mode));
+        self.sample_every(period, samplers)
+    }
+
+    /// Program the actions and timers, sampling starts with [`SamplingSession::start`].
+    pub fn build(self) -> Result<SamplingSession<B>, KperfError> {
+        if self.actions.len() > KPERF_ACTION_MAX as usize {
+            return Err(KperfError::InvalidSampling(format!(
+                "{} actions, kperf supports up to {}",
+                self.actions.len(),
+                KPERF_ACTION_MAX
+            )));
+        }
+        if self.timers.len() > KPERF_TIMER_MAX as usize {
+            return Err(KperfError::InvalidSampling(format!(
+                "{} timers, kperf supports up to {}",
+                self.timers.len(),
+                KPERF_TIMER_MAX
+            )));
+        }
+        if self.pet_timers.len() > 1 {
+            return Err(KperfError::InvalidSampling(
+                "kperf supports a single PET timer".to_string(),
+            ));
+        }
+        let timebase = match self.timebase {
+            Some(timebase) => timebase,
+            None => system_timebase()?,
+        };
+        let mut timers = Vec::with_capacity(self.timers.len());
+        for (period, action) in self.timers {
+            if action.0 == 0 || action.0 as usize > self.actions.len() {
+                return Err(KperfError::InvalidSampling(format!(
+                    "timer fires {}, which wasn't added",
+                    action
+                )));
+            }
+            let ticks = timebase.duration_to_ticks(period);
+            if ticks == 0 {
+                return Err(KperfError::InvalidSampling(format!(
+                    "timer period {:?} is shorter than a tick",
+                    period
+                )));
+            }
+            timers.push(SamplingTimer {
+                period,
+                ticks,
+                action,
+            });
+        }
+
+        // Created first, so dropping it tears down whatever was programmed if a step fails
+        let session = SamplingSession {
+            backend: self.backend,
+            actions: self.actions,
+            timers,
+            pet: self.pet_timers.first().copied(),
+        };
+        let backend = &session.backend;
+        check(
+            unsafe { backend.kperf_action_count_set(KPERF_ACTION_MAX) },
+            "set kperf action count",
+        )?;
+        for (idx, samplers) in session.actions.iter().enumerate() {
+            check(
+                unsafe { backend.kperf_action_samplers_set(idx as c_uint + 1, samplers.bits()) },
+                "set kperf action samplers",
+            )?;
+        }
+        check(
+            unsafe { backend.kperf_timer_count_set(KPERF_TIMER_MAX) },
+            "set kperf timer count",
+        )?;
+        for (timer, sampling_timer) in session.timers.iter().enumerate() {
+            check(
+                unsafe { backend.kperf_timer_period_set(timer as c_uint, sampling_timer.ticks) },
+                "set kperf timer period",
+            )?;
+            check(
+                unsafe { backend.kperf_timer_action_set(timer as c_uint, sampling_timer.action.0) },
+                "set kperf timer action",
+            )?;
+        }
+        if let Some((timer, mode)) = session.pet {
+            check(
+                unsafe { backend.kperf_timer_pet_set(timer as c_uint) },
+                "set kperf PET timer",
+            )?;
+            check(
+                unsafe { backend.kperf_lightweight_pet_set((mode == PetMode::Lightweight).into()) },
+                "set kperf lightweight PET",
+            )?;
+        }
+        Ok(session)
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn system_timebase() -> Result<Timebase, KperfError> {
+    Timebase::system()
+}
+
+#[cfg(not(target_os = "macos"))]
+fn system_timebase() -> Result<Timebase, KperfError> {
+    Err(KperfError::InvalidSampling(
+        "no system timebase, set one on the builder".to_string(),
+    ))
+}
+
+/// `Ok` if `res` is 0, the error in `errno` otherwise, so it must be called right after the call
+/// returning `res`.
+pub(crate) fn check(res: c_int, action: &str) -> Result<(), KperfError> {
+    if res == 0 {
+        return Ok(());
+    }
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(EPERM | EACCES) => Err(KperfError::PermissionDenied),
+        _ => Err(KperfError::UnknownError(format!(
+            "Failed to {}, error: {}",
+            action, error
+        ))),
+    }
+}
+
+/// kperf actions and timers, programmed by a [`SamplingSessionBuilder`].
+///
+/// Dropping the session stops sampling and resets kperf, also when unwinding from a panic.
+pub struct SamplingSession<B: CounterBackend> {
+    backend: B,
+    actions: Vec<Samplers>,
+    timers: Vec<SamplingTimer>,
+    pet: Option<(usize, PetMode)>,
+}
+
+impl<B: CounterBackend> SamplingSession<B> {
+    pub fn start(&mut self) -> Result<(), KperfError> {
+        check(
+            unsafe { self.backend.kperf_sample_set(1) },
+            "start kperf sampling",
+        )
+    }
+
+    pub fn stop(&mut self) -> Result<(), KperfError> {
+        check(
+            unsafe { self.backend.kperf_sample_set(0) },
+            "stop kperf sampling",
+        )
+    }
+
+    pub fn is_sampling(&self) -> Result<bool, KperfError> {
+        let mut enabled: c_uint = 0;
+        check(
+            unsafe { self.backend.kperf_sample_get(&mut enabled) },
+            "get kperf sampling",
+        )?;
+        Ok(enabled != 0)
+    }
+
+    /// Samplers of every action, the first one being `ActionId(1)`.
+    pub fn actions(&self) -> &[Samplers] {
+        &self.actions
+    }
+
+    pub fn timers(&self) -> &[SamplingTimer] {
+        &self.timers
+    }
+
+    /// Index in [`SamplingSession::timers`] and mode of the PET timer, if there is one.
+    pub fn pet(&self) -> Option<(usize, PetMode)> {
+        self.pet
+    }
+
+    /// Switch the PET timer to `mode`, which kperf only allows while sampling is stopped.
+    pub fn set_pet_mode(&mut self, mode: PetMode) -> Result<(), KperfError> {
+        let Some((timer, _)) = self.pet else {
+            return Err(KperfError::InvalidSampling(
+                "the session has no PET timer".to_string(),
+            ));
+        };
+        check(
+            unsafe {
+                self.backend
+                    .kperf_lightweight_pet_set((mode == PetMode::Lightweight).into())
+            },
+            "set kperf lightweight PET",
+        )?;
+        self.pet = Some((timer, mode));
+        Ok(())
+    }
+}
+
+impl<B: CounterBackend> Drop for SamplingSession<B> {
+    fn drop(&mut self) {
+        // Nothing sensible to do if tearing down fails
+        unsafe {
+            self.backend.kperf_sample_set(0);
+            if self.pet.is_some() {
+                self.backend.kperf_lightweight_pet_set(0);
+            }
+            self.backend.kperf_reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::SimulatedBackend;
+
+    fn timebase() -> Timebase {
+        Timebase::from_frequency(24_000_000).unwrap()
+    }
+
+    #[test]
+    fn test_samplers() {
+        let samplers = Samplers::USER_STACK | Samplers::PMC_THREAD;
+        assert_eq!(
+            samplers.bits(),
+            KPERF_SAMPLER_USTACK | KPERF_SAMPLER_PMC_THREAD
+        );
+        assert!(samplers.contains(Samplers::USER_STACK));
+        assert!(!samplers.contains(Samplers::KERNEL_STACK));
+        assert_eq!(Samplers::from_bits(samplers.bits()), Some(samplers));
+        assert_eq!(Samplers::from_bits(1 << 20), None);
+        assert!(Samplers::empty().is_empty());
+    }
+
+    #[test]
+    fn test_session_programs_kperf() {
+        let backend = SimulatedBackend::default();
+        let samplers = Samplers::THREAD_INFO | Samplers::USER_STACK;
+        let mut session = SamplingSessionBuilder::with_backend(backend.clone())
+            .timebase(timebase())
+            .action(Samplers::PMC_CPU)
+            .sample_every(Duration::from_millis(1), samplers)
+            .timer(Duration::from_micros(10), ActionId(1))
+            .build()
+            .unwrap();
+        assert_eq!(backend.action_samplers().len(), KPERF_ACTION_MAX as usize);
+        assert_eq!(
+            &backend.action_samplers()[..2],
+            [KPERF_SAMPLER_PMC_CPU, samplers.bits()]
+        );
+        assert_eq!(&backend.timers()[..2], [(24_000, 2), (240, 1)]);
+        assert_eq!(session.timers()[0].action, ActionId(2));
+
+        session.start().unwrap();
+        assert!(session.is_sampling().unwrap());
+        session.stop().unwrap();
+        assert!(!backend.sampling());
+        session.start().unwrap();
+        drop(session);
+        assert!(!backend.sampling());
+        assert!(backend.action_samplers().is_empty());
+        assert!(backend.timers().is_empty());
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut builder =
+            SamplingSessionBuilder::with_backend(SimulatedBackend::default()).timebase(timebase());
+        for _ in 0..=KPERF_TIMER_MAX {
+            builder = builder.sample_every(Duration::from_millis(1), Samplers::USER_STACK);
+        }
+        assert!(matches!(
+            builder.build(),
+            Err(KperfError::InvalidSampling(_))
+        ));
+
+        let mut builder =
+            SamplingSessionBuilder::with_backend(SimulatedBackend::default()).timebase(timebase());
+        for _ in 0..=KPERF_ACTION_MAX {
+            builder = builder.action(Samplers::USER_STACK);
+        }
+        assert!(builder.build().is_err());
+
+        for builder in [
+            SamplingSessionBuilder::with_backend(SimulatedBackend::default())
+                .timer(Duration::from_millis(1), ActionId(1)),
+            SamplingSessionBuilder::with_backend(SimulatedBackend::default())
+                .sample_every(Duration::from_nanos(1), Samplers::USER_STACK),
+        ] {
+            assert!(builder.timebase(timebase()).build().is_err());
+        }
+    }
+
+    #[test]
+    fn test_pet() {
+        let backend = SimulatedBackend::default();
+        let mut session = SamplingSessionBuilder::with_backend(backend.clone())
+            .timebase(timebase())
+            .sample_every(Duration::from_millis(10), Samplers::PMC_CPU)
+            .pet(
+                Duration::from_millis(1),
+                Samplers::THREAD_INFO | Samplers::USER_STACK,
+                PetMode::Lightweight,
+            )
+            .build()
+            .unwrap();
+        assert_eq!(session.pet(), Some((1, PetMode::Lightweight)));
+        assert_eq!(backend.pet_timer(), Some(1));
+        assert!(backend.lightweight_pet());
+
+        // The mode can't change while sampling
+        session.start().unwrap();
+        let busy = io::Error::from_raw_os_error(libc::EBUSY).to_string();
+        assert!(session
+            .set_pet_mode(PetMode::Pet)
+            .unwrap_err()
+            .to_string()
+            .ends_with(&busy));
+        assert_eq!(session.pet(), Some((1, PetMode::Lightweight)));
+        session.stop().unwrap();
+        session.set_pet_mode(PetMode::Pet).unwrap();
+        assert!(!backend.lightweight_pet());
+        session.start().unwrap();
+
+        drop(session);
+        assert!(!backend.sampling());
+        assert_eq!(backend.pet_timer(), None);
+        assert!(!backend.lightweight_pet());
+    }
+
+    #[test]
+    fn test_single_pet_timer() {
+        let builder = SamplingSessionBuilder::with_backend(SimulatedBackend::default())
+            .timebase(timebase())
+            .pet(Duration::from_millis(1), Samplers::USER_STACK, PetMode::Pet)
+            .pet(Duration::from_millis(1), Samplers::USER_STACK, PetMode::Pet);
+        assert!(matches!(
+            builder.build(),
+            Err(KperfError::InvalidSampling(_))
+        ));
+
+        let mut session = SamplingSessionBuilder::with_backend(SimulatedBackend::default())
+            .timebase(timebase())
+            .sample_every(Duration::from_millis(1), Samplers::USER_STACK)
+            .build()
+            .unwrap();
+        assert_eq!(session.pet(), None);
+        assert!(session.set_pet_mode(PetMode::Lightweight).is_err());
+    }
+
+    #[test]
+    fn test_permission_denied() {
+        let backend = SimulatedBackend::default();
+        backend.set_root(false);
+        let result = SamplingSessionBuilder::with_backend(backend)
+            .timebase(timebase())
+            .sample_every(Duration::from_millis(1), Samplers::USER_STACK)
+            .build();
+        assert!(matches!(result, Err(KperfError::PermissionDenied)));
+    }
+}
@@ -0,0 +1,171 @@
+use crate::calibration::Overhead;
+use crate::event::{CountingMode, Event};
+use std::fmt;
+use std::fmt::Formatter;
+use std::ops::Index;
+
+/// Counter values of every tracked event, in the order they were tracked.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CounterSnapshot {
+    values: Vec<(Event, u64)>,
+    counting_modes: Vec<CountingMode>,
+    /// Overhead subtracted from each value, empty if none was.
+    corrections: Vec<Overhead>,
+    /// Whether the hardware counter of each value wrapped around, empty if none did.
+    wrapped: Vec<bool>,
+    /// Event counted in place of each tracked event, empty if none was substituted.
+    substitutes: Vec<Option<Event>>,
+}
+
+impl CounterSnapshot {
+    /// Snapshot of events counted in every privilege level.
+    pub fn new(values: Vec<(Event, u64)>) -> Self {
+        let counting_modes = vec![CountingMode::All; values.len()];
+        Self {
+            values,
+            counting_modes,
+            corrections: Vec::new(),
+            wrapped: Vec::new(),
+            substitutes: Vec::new(),
+        }
+    }
+
+    /// Record the mode each event was counted in, in the same order as the values.
+    pub fn with_counting_modes(mut self, counting_modes: Vec<CountingMode>) -> Self {
+        assert_eq!(counting_modes.len(), self.values.len());
+        self.counting_modes = counting_modes;
+        self
+    }
+
+    /// Record the overhead subtracted from each value, in the same order as the values.
+    pub fn with_corrections(mut self, corrections: Vec<Overhead>) -> Self {
+        assert_eq!(corrections.len(), self.values.len());
+        self.corrections = corrections;
+        self
+    }
+
+    /// Overhead subtracted from the value of `event`, `None` if it wasn't corrected.
+    pub fn correction(&self, event: &Event) -> Option<Overhead> {
+        let idx = self
+            .values
+            .iter()
+            .position(|(tracked, _)| tracked == event)?;
+        self.corrections.get(idx).copied()
+    }
+
+    /// Record whether the counter of each value wrapped around, in the same order as the values.
+    pub fn with_wrapped(mut self, wrapped: Vec<bool>) -> Self {
+        assert_eq!(wrapped.len(), self.values.len());
+        self.wrapped = if wrapped.contains(&true) {
+            wrapped
+        } else {
+            Vec::new()
+        };
+        self
+    }
+
+    /// Whether the hardware counter of `event` wrapped around while counting.
+    ///
+    /// Wrapping around once is accounted for, but a flagged value is too small if the counter
+    /// wrapped more than once.
+    pub fn wrapped(&self, event: &Event) -> bool {
+        self.values
+            .iter()
+            .position(|(tracked, _)| tracked == event)
+            .and_then(|idx| self.wrapped.get(idx).copied())
+            .unwrap_or(false)
+    }
+
+    /// Record the event counted in place of each tracked event, in the same order as the values.
+    pub fn with_substitutes(mut self, substitutes: Vec<Option<Event>>) -> Self {
+        assert_eq!(substitutes.len(), self.values.len());
+        self.substitutes = if substitutes.iter().any(Option::is_some) {
+            substitutes
+        } else {
+            Vec::new()
+        };
+        self
+    }
+
+    /// Event counted in place of `event`, see [`Event::software_substitute`]. The value of
+    /// `event` is then the value of the substitute.
+    pub fn substitute(&self, event: &Event) -> Option<&Event> {
+        let idx = self
+            .values
+            .iter()
+            .position(|(tracked, _)| tracked == event)?;
+        self.substitutes.get(idx)?.as_ref()
+    }
+
+    pub fn counting_mode(&self, event: &Event) -> Option<CountingMode> {
+        let idx = self
+            .values
+            .iter()
+            .position(|(tracked, _)| tracked == event)?;
+        Some(self.counting_modes[idx])
+    }
+
+    pub fn get(&self, event: &Event) -> Option<u64> {
+        self.values
+            .iter()
+            .find(|(tracked, _)| tracked == event)
+            .map(|(_, value)| *value)
+    }
+
+    pub fn events(&self) -> impl Iterator<Item = &Event> {
+        self.values.iter().map(|(event, _)| event)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Event, u64)> {
+        self.values.iter().map(|(event, value)| (event, *value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+/// Counter values of one CPU.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CpuSnapshot {
+    pub cpu: usize,
+    pub snapshot: CounterSnapshot,
+}
+
+impl Index<Event> for CounterSnapshot {
+    type Output = u64;
+
+    /// Panics if `event` wasn't tracked, use [`CounterSnapshot::get`] otherwise.
+    fn index(&self, event: Event) -> &u64 {
+        self.values
+            .iter()
+            .find(|(tracked, _)| *tracked == event)
+            .map(|(_, value)| value)
+            .unwrap_or_else(|| panic!("Event {} isn't part of this snapshot", event))
+    }
+}
+
+impl fmt::Display for CounterSnapshot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (idx, ((event, value), mode)) in
+            self.values.iter().zip(&self.counting_modes).enumerate()
+        {
+            write!(f, "{}{}: {}", event, mode, value)?;
+            if let Some(overhead) = self.corrections.get(idx) {
+                write!(f, " (overhead {} subtracted)", overhead)?;
+            }
+            if self.wrapped.get(idx) == Some(&true) {
+                write!(f, " (wrapped)")?;
+            }
+            if let Some(Some(substitute)) = self.substitutes.get(idx) {
+                write!(f, " (counted as {})", substitute)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
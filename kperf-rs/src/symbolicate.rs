@@ -0,0 +1,353 @@
+use crate::error::KperfError;
+use crate::kdebug::Callstack;
+use addr2line::Loader;
+use object::read::ReadCache;
+use object::{Object, ObjectSegment};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// Function an address is in, or one inlined into it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolFrame {
+    /// Demangled name of the function, `None` if unknown.
+    pub function: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    /// Whether the function was inlined into the function of the next frame.
+    pub inlined: bool,
+}
+
+/// What an address of a sampled process maps to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolicatedAddress {
+    pub address: u64,
+    /// Image the address is in, `None` if no image added to the symbolicator holds it.
+    pub image: Option<PathBuf>,
+    /// Functions inlined at the address, innermost first, then the function it is in. Empty if
+    /// neither the debug info nor the symbol table of the image know the address.
+    pub frames: Vec<SymbolFrame>,
+}
+
+/// Binary loaded in the address space of the sampled process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    path: PathBuf,
+    start: u64,
+    end: u64,
+    /// Address the lowest segment of the binary is linked at.
+    linked_address: u64,
+}
+
+impl Image {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Address the image was loaded at.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Address of the end of the last segment of the image.
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    pub fn contains(&self, address: u64) -> bool {
+        (self.start..self.end).contains(&address)
+    }
+
+    /// Address of `address` in the binary as it was linked, which its debug info uses.
+    fn linked(&self, address: u64) -> u64 {
+        address - self.start + self.linked_address
+    }
+}
+
+/// Debug info and symbols of a binary.
+struct LoadedObject {
+    loader: Loader,
+    linked_address: u64,
+    size: u64,
+}
+
+/// Maps addresses of sampled stacks to functions, files and lines, from DWARF or the symbol
+/// table of ELF and Mach-O binaries.
+///
+/// The debug info of a Mach-O binary is read from the dSYM bundle next to it, or from the
+/// object files of its debug map. Binaries are loaded once, however many images map them.
+#[derive(Default)]
+pub struct Symbolicator {
+    objects: HashMap<PathBuf, LoadedObject>,
+    /// Sorted by start address.
+    images: Vec<Image>,
+}
+
+impl Symbolicator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the binary at `path`, loaded with its lowest segment at `load_address`.
+    pub fn add_image(
+        &mut self,
+        path: impl AsRef<Path>,
+        load_address: u64,
+    ) -> Result<&Image, KperfError> {
+        let path = path.as_ref();
+        if !self.objects.contains_key(path) {
+            self.objects.insert(path.to_path_buf(), load_object(path)?);
+        }
+        let object = &self.objects[path];
+        let image = Image {
+            path: path.to_path_buf(),
+            start: load_address,
+            end: load_address.saturating_add(object.size),
+            linked_address: object.linked_address,
+        };
+        let idx = self
+            .images
+            .partition_point(|other| other.start <= image.start);
+        self.images.insert(idx, image);
+        Ok(&self.images[idx])
+    }
+
+    pub fn images(&self) -> &[Image] {
+        &self.images
+    }
+
+    /// Image holding `address`, the last one loaded there if several overlap.
+    pub fn image(&self, address: u64) -> Option<&Image> {
+        let idx = self.images.partition_point(|image| image.start <= address);
+        self.images[..idx]
+            .iter()
+            .rev()
+            .find(|image| image.contains(address))
+    }
+
+    pub fn symbolicate(&self, address: u64) -> SymbolicatedAddress {
+        self.lookup(address, address)
+    }
+
+    /// Symbolicate the frames of a sampled `stack`.
+    ///
+    /// Every frame but the first is a return address, after the call it returns from, so they
+    /// are looked up one byte earlier to land on the line of the call.
+    pub fn symbolicate_stack(&self, stack: &Callstack) -> Vec<SymbolicatedAddress> {
+        stack
+            .frames
+            .iter()
+            .enumerate()
+            .map(|(idx, &address)| match idx {
+                0 => self.lookup(address, address),
+                _ => self.lookup(address, address.saturating_sub(1)),
+            })
+            .collect()
+    }
+
+    fn lookup(&self, address: u64, probe: u64) -> SymbolicatedAddress {
+        let Some(image) = self.image(probe) else {
+            return SymbolicatedAddress {
+                address,
+                image: None,
+                frames: Vec::new(),
+            };
+        };
+        let loader = &self.objects[&image.path].loader;
+        let probe = image.linked(probe);
+        let mut frames = debug_info_frames(loader, probe);
+        if frames.iter().all(|frame| frame.function.is_none()) {
+            // Without debug info, the symbol table still tells the function
+            if let Some(name) = loader.find_symbol(probe) {
+                frames = vec![SymbolFrame {
+                    function: Some(addr2line::demangle_auto(Cow::from(name), None).into_owned()),
+                    file: None,
+                    line: None,
+                    inlined: false,
+                }];
+            }
+        }
+        SymbolicatedAddress {
+            address,
+            image: Some(image.path.clone()),
+            frames,
+        }
+    }
+}
+
+fn load_object(path: &Path) -> Result<LoadedObject, KperfError> {
+    let failed = |err: &dyn std::fmt::Display| {
+        KperfError::UnknownError(format!("Failed to load {}, error: {}", path.display(), err))
+    };
+    let file = File::open(path).map_err(|err| failed(&err))?;
+    let cache = ReadCache::new(file);
+    let object = object::File::parse(&cache).map_err(|err| failed(&err))?;
+    // __PAGEZERO only reserves the low addresses of Mach-O executables, it isn't loaded
+    let (start, end) = object
+        .segments()
+        .filter(|segment| segment.name().ok().flatten() != Some("__PAGEZERO"))
+        .fold((u64::MAX, 0), |(start, end), segment| {
+            (
+                start.min(segment.address()),
+                end.max(segment.address() + segment.size()),
+            )
+        });
+    if start >= end {
+        return Err(failed(&"no loadable segment"));
+    }
+    let loader = Loader::new(path).map_err(|err| failed(&err))?;
+    Ok(LoadedObject {
+        loader,
+        linked_address: start,
+        size: end - start,
+    })
+}
+
+/// Frames the DWARF of `loader` has for `probe`, innermost first.
+fn debug_info_frames(loader: &Loader, probe: u64) -> Vec<SymbolFrame> {
+    let mut frames = Vec::new();
+    let Ok(mut iter) = loader.find_frames(probe) else {
+        return frames;
+    };
+    while let Ok(Some(frame)) = iter.next() {
+        let function = frame
+            .function
+            .as_ref()
+            .and_then(|function| function.demangle().ok())
+            .map(Cow::into_owned);
+        let location = frame.location.as_ref();
+        frames.push(SymbolFrame {
+            function,
+            file: location
+                .and_then(|location| location.file)
+                .map(str::to_string),
+            line: location.and_then(|location| location.line),
+            inlined: true,
+        });
+    }
+    if let Some(last) = frames.last_mut() {
+        last.inlined = false;
+    }
+    frames
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // See fixtures/symbols/fixture.rs for how the binaries are built
+    const ELF: &str = "fixtures/symbols/fixture.elf";
+    const STRIPPED_ELF: &str = "fixtures/symbols/fixture.stripped.elf";
+    const MACHO: &str = "fixtures/symbols/fixture.macho";
+    /// Where the tests pretend the fixtures were loaded.
+    const LOAD_ADDRESS: u64 = 0x5555_0000_0000;
+
+    fn functions(address: &SymbolicatedAddress) -> Vec<&str> {
+        address
+            .frames
+            .iter()
+            .map(|frame| frame.function.as_deref().unwrap_or("?"))
+            .collect()
+    }
+
+    /// Stack sampled in `leaf`, called by `helper`, inlined in `compute`, called by `_start`,
+    /// at the offsets of their instructions from the start of the text segment.
+    fn stack(text: u64, leaf: u64, call_leaf: u64, call_compute: u64) -> Callstack {
+        Callstack {
+            flags: 0,
+            frames: vec![text + leaf, text + call_leaf, text + call_compute],
+        }
+    }
+
+    fn check_stack(symbolicator: &Symbolicator, stack: &Callstack) {
+        let addresses = symbolicator.symbolicate_stack(stack);
+        assert_eq!(functions(&addresses[0]), ["leaf"]);
+        assert_eq!(
+            functions(&addresses[1]),
+            ["fixture::helper", "fixture::compute"]
+        );
+        let helper = &addresses[1].frames[0];
+        assert!(helper.inlined);
+        assert!(helper.file.as_ref().unwrap().ends_with("fixture.rs"));
+        assert_eq!(helper.line, Some(40));
+        // The line of the call to helper
+        assert_eq!(addresses[1].frames[1].line, Some(45));
+        assert!(!addresses[1].frames[1].inlined);
+        assert_eq!(functions(&addresses[2]), ["_start"]);
+        assert_eq!(addresses[2].address, stack.frames[2]);
+    }
+
+    #[test]
+    fn test_elf() {
+        let mut symbolicator = Symbolicator::new();
+        let image = symbolicator.add_image(ELF, LOAD_ADDRESS).unwrap();
+        // The text segment follows a page of headers
+        assert_eq!(image.end() - image.start(), 0x2084);
+        check_stack(
+            &symbolicator,
+            &stack(LOAD_ADDRESS + 0x1000, 0x33, 0x13, 0x2b),
+        );
+
+        let outside = symbolicator.symbolicate(LOAD_ADDRESS - 1);
+        assert_eq!(outside.image, None);
+        assert!(outside.frames.is_empty());
+    }
+
+    #[test]
+    fn test_symbol_table_fallback() {
+        let mut symbolicator = Symbolicator::new();
+        symbolicator.add_image(STRIPPED_ELF, LOAD_ADDRESS).unwrap();
+        let compute = symbolicator.symbolicate(LOAD_ADDRESS + 0x100e);
+        assert_eq!(
+            compute.frames,
+            [SymbolFrame {
+                function: Some("fixture::compute".to_string()),
+                file: None,
+                line: None,
+                inlined: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_macho_debug_map() {
+        let mut symbolicator = Symbolicator::new();
+        // Mach-O binaries are loaded from their __TEXT segment, which starts with the headers
+        symbolicator.add_image(MACHO, LOAD_ADDRESS).unwrap();
+        let text = symbolicator.objects[Path::new(MACHO)]
+            .loader
+            .get_section_range(b"__text")
+            .unwrap();
+        let offset = LOAD_ADDRESS + text.begin - symbolicator.images[0].linked_address;
+        let start = symbolicator.symbolicate(offset);
+        assert_eq!(functions(&start), ["fixture::compute"]);
+        // Read from the DWARF of the object file, the symbol table has no lines
+        assert_eq!(start.frames[0].line, Some(44));
+        assert_eq!(start.image.as_deref(), Some(Path::new(MACHO)));
+    }
+
+    #[test]
+    fn test_images() {
+        let mut symbolicator = Symbolicator::new();
+        symbolicator.add_image(ELF, LOAD_ADDRESS).unwrap();
+        symbolicator.add_image(ELF, 0x1000_0000).unwrap();
+        symbolicator.add_image(MACHO, 0x2000_0000).unwrap();
+        // The ELF binary is loaded once for both images
+        assert_eq!(symbolicator.objects.len(), 2);
+        let starts: Vec<u64> = symbolicator.images().iter().map(Image::start).collect();
+        assert_eq!(starts, [0x1000_0000, 0x2000_0000, LOAD_ADDRESS]);
+        assert_eq!(
+            symbolicator.image(0x1000_1033).unwrap().path(),
+            Path::new(ELF)
+        );
+        assert_eq!(functions(&symbolicator.symbolicate(0x1000_1033)), ["leaf"]);
+        assert!(symbolicator.image(0x1fff_ffff).is_none());
+        assert!(symbolicator
+            .add_image("fixtures/symbols/missing", 0)
+            .is_err());
+        assert!(symbolicator
+            .add_image("fixtures/symbols/fixture.rs", 0)
+            .is_err());
+    }
+}
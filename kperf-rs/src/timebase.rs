@@ -0,0 +1,193 @@
+#[cfg(target_os = "macos")]
+use crate::backend::kperf::functions::kperf_tick_frequency;
+#[cfg(target_os = "macos")]
+use crate::error::KperfError;
+use std::time::Duration;
+
+const NANOSECONDS_PER_SECOND: u64 = 1_000_000_000;
+
+/// Ratio converting ticks of the system clock to nanoseconds: `ns = ticks * numer / denom`.
+///
+/// The ratio depends on the machine, 1/1 on Intel Macs and 125/3 on most Apple silicon.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Timebase {
+    numer: u64,
+    denom: u64,
+}
+
+impl Timebase {
+    /// Timebase of `numer / denom` nanoseconds per tick, `None` if either is 0.
+    pub fn new(numer: u64, denom: u64) -> Option<Self> {
+        if numer == 0 || denom == 0 {
+            return None;
+        }
+        let divisor = gcd(numer, denom);
+        Some(Self {
+            numer: numer / divisor,
+            denom: denom / divisor,
+        })
+    }
+
+    /// Timebase of a clock ticking `frequency` times per second, `None` if it is 0.
+    pub fn from_frequency(frequency: u64) -> Option<Self> {
+        Self::new(NANOSECONDS_PER_SECOND, frequency)
+    }
+
+    /// Timebase of the ticks kperf reports, from `kperf_tick_frequency`.
+    #[cfg(target_os = "macos")]
+    pub fn from_kperf() -> Result<Self, KperfError> {
+        let frequency = unsafe { kperf_tick_frequency() } as u64;
+        Self::from_frequency(frequency)
+            .ok_or_else(|| KperfError::UnknownError("kperf tick frequency unavailable".to_string()))
+    }
+
+    /// Timebase of `mach_absolute_time`, from `mach_timebase_info`.
+    #[cfg(target_os = "macos")]
+    #[allow(deprecated)]
+    pub fn mach() -> Result<Self, KperfError> {
+        let mut info = libc::mach_timebase_info { numer: 0, denom: 0 };
+        let res = unsafe { libc::mach_timebase_info(&mut info) };
+        if res != 0 {
+            return Err(KperfError::UnknownError(format!(
+                "Failed to get mach timebase info, error: {}",
+                res
+            )));
+        }
+        Self::new(info.numer as u64, info.denom as u64)
+            .ok_or_else(|| KperfError::UnknownError("mach timebase info unavailable".to_string()))
+    }
+
+    /// Timebase of kperf ticks, from mach timebase info when kperf can't tell.
+    #[cfg(target_os = "macos")]
+    pub fn system() -> Result<Self, KperfError> {
+        Self::from_kperf().or_else(|_| Self::mach())
+    }
+
+    pub fn numer(&self) -> u64 {
+        self.numer
+    }
+
+    pub fn denom(&self) -> u64 {
+        self.denom
+    }
+
+    /// Nanoseconds lasted by `ticks`, rounded down and saturating at `u64::MAX`.
+    pub fn ticks_to_nanoseconds(&self, ticks: u64) -> u64 {
+        mul_div(ticks as u128, self.numer, self.denom)
+            .try_into()
+            .unwrap_or(u64::MAX)
+    }
+
+    /// Ticks in `nanoseconds`, rounded down and saturating at `u64::MAX`.
+    pub fn nanoseconds_to_ticks(&self, nanoseconds: u64) -> u64 {
+        mul_div(nanoseconds as u128, self.denom, self.numer)
+            .try_into()
+            .unwrap_or(u64::MAX)
+    }
+
+    /// Time lasted by `ticks`, rounded down to the nanosecond.
+    pub fn ticks_to_duration(&self, ticks: u64) -> Duration {
+        let nanoseconds = mul_div(ticks as u128, self.numer, self.denom);
+        let secs = nanoseconds / NANOSECONDS_PER_SECOND as u128;
+        let nanos = nanoseconds % NANOSECONDS_PER_SECOND as u128;
+        match secs.try_into() {
+            Ok(secs) => Duration::new(secs, nanos as u32),
+            Err(_) => Duration::MAX,
+        }
+    }
+
+    /// Ticks in `duration`, rounded down and saturating at `u64::MAX`.
+    pub fn duration_to_ticks(&self, duration: Duration) -> u64 {
+        mul_div(duration.as_nanos(), self.denom, self.numer)
+            .try_into()
+            .unwrap_or(u64::MAX)
+    }
+}
+
+/// `value * mul / div` rounded down, saturating at `u128::MAX`.
+fn mul_div(value: u128, mul: u64, div: u64) -> u128 {
+    let (mul, div) = (mul as u128, div as u128);
+    // value * mul / div = (value / div) * mul + (value % div) * mul / div, where the last
+    // product is below div * mul so it can't overflow
+    (value / div)
+        .checked_mul(mul)
+        .and_then(|quotient| quotient.checked_add(value % div * mul / div))
+        .unwrap_or(u128::MAX)
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn timebase() -> impl Strategy<Value = Timebase> {
+        (1..=u32::MAX as u64, 1..=u32::MAX as u64)
+            .prop_map(|(numer, denom)| Timebase::new(numer, denom).unwrap())
+    }
+
+    #[test]
+    fn test_known_timebases() {
+        let apple_silicon = Timebase::from_frequency(24_000_000).unwrap();
+        assert_eq!((apple_silicon.numer(), apple_silicon.denom()), (125, 3));
+        assert_eq!(
+            apple_silicon.ticks_to_nanoseconds(24_000_000),
+            1_000_000_000
+        );
+        assert_eq!(
+            apple_silicon.duration_to_ticks(Duration::from_millis(1)),
+            24_000
+        );
+        assert_eq!(Timebase::new(3, 3), Timebase::new(1, 1));
+        assert_eq!(Timebase::new(0, 1), None);
+        assert_eq!(Timebase::from_frequency(0), None);
+    }
+
+    proptest! {
+        #[test]
+        fn ticks_to_nanoseconds_is_exact(timebase in timebase(), ticks: u64) {
+            let exact = ticks as u128 * timebase.numer() as u128 / timebase.denom() as u128;
+            prop_assert_eq!(
+                timebase.ticks_to_nanoseconds(ticks) as u128,
+                exact.min(u64::MAX as u128)
+            );
+            prop_assert_eq!(timebase.ticks_to_duration(ticks).as_nanos(), exact);
+        }
+
+        #[test]
+        fn conversions_are_monotonic(timebase in timebase(), a: u64, b: u64) {
+            let (low, high) = (a.min(b), a.max(b));
+            prop_assert!(timebase.ticks_to_nanoseconds(low) <= timebase.ticks_to_nanoseconds(high));
+            prop_assert!(timebase.nanoseconds_to_ticks(low) <= timebase.nanoseconds_to_ticks(high));
+        }
+
+        #[test]
+        fn round_trip_loses_less_than_a_tick(
+            timebase in timebase(),
+            secs in 0..1u64 << 32,
+            nanos in 0..1_000_000_000u32,
+        ) {
+            let duration = Duration::new(secs, nanos);
+            let ticks = timebase.duration_to_ticks(duration);
+            prop_assume!(ticks < u64::MAX);
+            let back = timebase.ticks_to_duration(ticks);
+            prop_assert!(back <= duration);
+            let tick = timebase.numer().div_ceil(timebase.denom());
+            prop_assert!((duration - back).as_nanos() <= tick as u128);
+        }
+
+        #[test]
+        fn extremes_saturate(timebase in timebase()) {
+            timebase.ticks_to_duration(u64::MAX);
+            timebase.duration_to_ticks(Duration::MAX);
+            prop_assert_eq!(timebase.ticks_to_nanoseconds(0), 0);
+            prop_assert_eq!(timebase.duration_to_ticks(Duration::ZERO), 0);
+        }
+    }
+}
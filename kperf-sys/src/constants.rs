@@ -75,3 +75,79 @@ pub const KPEP_ARCH_I386: u32 = 0;
 pub const KPEP_ARCH_X86_64: u32 = 1;
 pub const KPEP_ARCH_ARM: u32 = 2;
 pub const KPEP_ARCH_ARM64: u32 = 3;
+
+// kdebug debugid layout: class (8 bits), subclass (8 bits), code (14 bits), function (2 bits).
+// Defined in https://github.com/apple/darwin-xnu/blob/main/bsd/sys/kdebug.h
+pub const DBG_FUNC_NONE: u32 = 0;
+pub const DBG_FUNC_START: u32 = 1;
+pub const DBG_FUNC_END: u32 = 2;
+pub const DBG_FUNC_MASK: u32 = 0x3;
+
+// kdebug class of the records kperf emits.
+pub const DBG_PERF: u32 = 37;
+
+// Subclasses of DBG_PERF.
+// Defined in https://github.com/apple/darwin-xnu/blob/main/osfmk/kperf/buffer.h
+pub const PERF_GENERIC: u32 = 0;
+pub const PERF_THREADINFO: u32 = 1;
+pub const PERF_CALLSTACK: u32 = 2;
+pub const PERF_TIMER: u32 = 3;
+pub const PERF_PET: u32 = 4;
+pub const PERF_AST: u32 = 5;
+pub const PERF_KPC: u32 = 6;
+pub const PERF_KDBG: u32 = 7;
+pub const PERF_TASK: u32 = 8;
+pub const PERF_LAZY: u32 = 9;
+pub const PERF_MEMINFO: u32 = 10;
+
+// Codes of PERF_GENERIC.
+pub const PERF_GEN_EVENT: u32 = 0;
+
+// Codes of PERF_THREADINFO.
+pub const PERF_TI_SAMPLE: u32 = 0;
+pub const PERF_TI_DATA: u32 = 1;
+pub const PERF_TI_SCHEDDATA: u32 = 7;
+pub const PERF_TI_SCHEDDATA_2: u32 = 19;
+
+// Codes of PERF_CALLSTACK.
+pub const PERF_CS_KSAMPLE: u32 = 0;
+pub const PERF_CS_UPEND: u32 = 1;
+pub const PERF_CS_USAMPLE: u32 = 2;
+pub const PERF_CS_KDATA: u32 = 3;
+pub const PERF_CS_UDATA: u32 = 4;
+pub const PERF_CS_KHDR: u32 = 5;
+pub const PERF_CS_UHDR: u32 = 6;
+pub const PERF_CS_ERROR: u32 = 7;
+
+// Codes of PERF_KPC.
+pub const PERF_KPC_DATA: u32 = 3;
+pub const PERF_KPC_CONFIG: u32 = 4;
+pub const PERF_KPC_CFG_REG: u32 = 5;
+pub const PERF_KPC_DATA_THREAD: u32 = 8;
+pub const PERF_KPC_CPU_SAMPLE: u32 = 10;
+pub const PERF_KPC_THREAD_SAMPLE: u32 = 11;
+
+// Flags of the PERF_CS_KHDR and PERF_CS_UHDR records.
+pub const CALLSTACK_VALID: u32 = 1 << 0;
+pub const CALLSTACK_DEFERRED: u32 = 1 << 1;
+pub const CALLSTACK_64BIT: u32 = 1 << 2;
+pub const CALLSTACK_KERNEL: u32 = 1 << 3;
+pub const CALLSTACK_TRUNCATED: u32 = 1 << 4;
+
+// Chunk tags of the version 3 trace files written by `ktrace record` and `ktrace artrace`.
+// Defined in https://github.com/apple/darwin-xnu/blob/main/bsd/sys/kdebug.h
+pub const RAW_VERSION1: u32 = 0x55aa0101;
+pub const RAW_VERSION3: u32 = 0x00001000;
+pub const V3_CONFIG: u32 = 0x00001b00;
+pub const V3_CPU_MAP: u32 = 0x00001c00;
+pub const V3_THREAD_MAP: u32 = 0x00001d00;
+pub const V3_RAW_EVENTS: u32 = 0x00001e00;
+pub const V3_NULL_CHUNK: u32 = 0x00002000;
+
+// Versions of the CPU map, with 8 byte names in kd_cpumap and 32 byte ones in kd_cpumap_ext.
+// Defined in https://github.com/apple/xnu/blob/main/bsd/sys/kdebug_private.h
+pub const KDBG_CPUMAP_VERSION1: u32 = RAW_VERSION1;
+pub const KDBG_CPUMAP_VERSION2: u32 = 0x55aa0200;
+
+// Flags of the CPU map entries.
+pub const KDBG_CPUMAP_IS_IOP: u32 = 0x1;
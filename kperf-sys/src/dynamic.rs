@@ -0,0 +1,273 @@
+//! Runtime loading of the kperf and kperfdata frameworks.
+//!
+//! With the `dynamic` feature the frameworks aren't linked: every function is resolved with
+//! `dlopen`/`dlsym` into a [`KperfFunctions`] table instead, so a renamed or missing symbol is
+//! reported as a [`LoadError`] rather than failing the link, and the crate builds on any OS.
+
+use crate::structs::{kpc_config_t, kpep_config, kpep_db, kpep_event};
+use libc::{c_char, c_int, c_uchar, c_uint, c_ulonglong, size_t};
+use std::ffi::CStr;
+use std::fmt;
+use std::fmt::Formatter;
+use std::sync::OnceLock;
+
+pub const KPERF_PATH: &CStr = c"/System/Library/PrivateFrameworks/kperf.framework/kperf";
+pub const KPERFDATA_PATH: &CStr =
+    c"/System/Library/PrivateFrameworks/kperfdata.framework/kperfdata";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    /// The frameworks only exist on macOS.
+    Unavailable,
+    /// `dlopen` failed, `reason` is the message from `dlerror`.
+    LibraryNotFound { library: String, reason: String },
+    /// The library was loaded but doesn't export this symbol.
+    MissingSymbol {
+        library: String,
+        symbol: &'static str,
+    },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Unavailable => {
+                write!(f, "kperf frameworks are unavailable on this platform")
+            }
+            LoadError::LibraryNotFound { library, reason } => {
+                write!(f, "Couldn't load {}: {}", library, reason)
+            }
+            LoadError::MissingSymbol { library, symbol } => {
+                write!(f, "Symbol {} is missing from {}", symbol, library)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+#[cfg(unix)]
+struct Library {
+    path: String,
+    handle: *mut libc::c_void,
+}
+
+#[cfg(unix)]
+impl Library {
+    /// Open `path`, the library is never unloaded as the table can outlive it.
+    unsafe fn open(path: &CStr) -> Result<Self, LoadError> {
+        let handle = libc::dlopen(path.as_ptr(), libc::RTLD_LAZY | libc::RTLD_LOCAL);
+        let path = path.to_string_lossy().into_owned();
+        if handle.is_null() {
+            return Err(LoadError::LibraryNotFound {
+                library: path,
+                reason: dlerror_message(),
+            });
+        }
+        Ok(Self { path, handle })
+    }
+
+    unsafe fn symbol(
+        &self,
+        symbol: &'static str,
+        name: &CStr,
+    ) -> Result<*mut libc::c_void, LoadError> {
+        let address = libc::dlsym(self.handle, name.as_ptr());
+        if address.is_null() {
+            return Err(LoadError::MissingSymbol {
+                library: self.path.clone(),
+                symbol,
+            });
+        }
+        Ok(address)
+    }
+}
+
+#[cfg(unix)]
+unsafe fn dlerror_message() -> String {
+    let message = libc::dlerror();
+    if message.is_null() {
+        return String::from("unknown error");
+    }
+    CStr::from_ptr(message).to_string_lossy().into_owned()
+}
+
+macro_rules! function_table {
+    ($($library:ident {
+        $(fn $name:ident($($arg:ident: $ty:ty),* $(,)?) $(-> $ret:ty)?;)*
+    })*) => {
+        /// Every function of `crate::functions`, resolved at runtime.
+        /// See the declarations there for the documentation of each one.
+        #[derive(Debug, Copy, Clone)]
+        pub struct KperfFunctions {
+            $($(pub $name: unsafe extern "C" fn($($ty),*) $(-> $ret)?,)*)*
+        }
+
+        impl KperfFunctions {
+            /// Resolve every function from the libraries at the given paths.
+            ///
+            /// # Safety
+            /// The libraries must be the kperf and kperfdata frameworks, or export functions
+            /// with the same signatures under the same names.
+            #[cfg(unix)]
+            pub unsafe fn load_from($($library: &CStr),*) -> Result<Self, LoadError> {
+                $(let $library = Library::open($library)?;)*
+                Ok(Self {
+                    $($($name: std::mem::transmute::<
+                        *mut libc::c_void,
+                        unsafe extern "C" fn($($ty),*) $(-> $ret)?,
+                    >($library.symbol(
+                        stringify!($name),
+                        CStr::from_bytes_with_nul(concat!(stringify!($name), "\0").as_bytes())
+                            .unwrap(),
+                    )?),)*)*
+                })
+            }
+        }
+    };
+}
+
+function_table! {
+    kperf {
+        fn kpc_cpu_string(buf: *mut u8, buf_size: size_t) -> c_int;
+        fn kpc_pmu_version() -> c_uint;
+        fn kpc_get_counting() -> c_uint;
+        fn kpc_set_counting(classes: c_uint) -> c_int;
+        fn kpc_get_thread_counting() -> c_uint;
+        fn kpc_set_thread_counting(classes: c_uint) -> c_int;
+        fn kpc_get_config_count(classes: c_uint) -> c_uint;
+        fn kpc_get_config(classes: c_uint, config: *mut kpc_config_t) -> c_int;
+        fn kpc_set_config(classes: c_uint, config: *mut kpc_config_t) -> c_int;
+        fn kpc_get_counter_count(classes: c_uint) -> c_uint;
+        fn kpc_get_cpu_counters(
+            all_cpus: bool,
+            classes: c_uint,
+            curcpu: *mut c_int,
+            buf: *mut c_ulonglong,
+        ) -> c_int;
+        fn kpc_get_thread_counters(tid: c_uint, buf_count: c_uint, buf: *mut c_ulonglong) -> c_int;
+        fn kpc_force_all_ctrs_set(val: c_int) -> c_int;
+        fn kpc_force_all_ctrs_get(val_out: *mut c_int) -> c_int;
+        fn kperf_action_count_set(count: c_uint) -> c_int;
+        fn kperf_action_count_get(count: *mut c_uint) -> c_int;
+        fn kperf_action_samplers_set(actionid: c_uint, sample: c_uint) -> c_int;
+        fn kperf_action_samplers_get(actionid: c_uint, sample: *mut c_uint) -> c_int;
+        fn kperf_action_filter_set_by_task(actionid: c_uint, port: c_int) -> c_int;
+        fn kperf_action_filter_set_by_pid(actionid: c_uint, pid: c_int) -> c_int;
+        fn kperf_timer_count_set(count: c_uint) -> c_int;
+        fn kperf_timer_count_get(count: *mut c_uint) -> c_int;
+        fn kperf_timer_period_set(actionid: c_uint, tick: c_ulonglong) -> c_int;
+        fn kperf_timer_period_get(actionid: c_uint, tick: *mut c_ulonglong) -> c_int;
+        fn kperf_timer_action_set(actionid: c_uint, timerid: c_uint) -> c_int;
+        fn kperf_timer_action_get(actionid: c_uint, timerid: *mut c_uint) -> c_int;
+        fn kperf_timer_pet_set(timerid: c_uint) -> c_int;
+        fn kperf_timer_pet_get(timerid: *mut c_uint) -> c_int;
+        fn kperf_sample_set(enabled: c_uint) -> c_int;
+        fn kperf_sample_get(enabled: *mut c_uint) -> c_int;
+        fn kperf_reset() -> c_int;
+        fn kperf_ns_to_ticks(ns: c_ulonglong) -> c_ulonglong;
+        fn kperf_ticks_to_ns(ticks: c_ulonglong) -> c_ulonglong;
+        fn kperf_tick_frequency() -> c_ulonglong;
+    }
+    kperfdata {
+        fn kpep_config_create(db: *mut kpep_db, cfg_ptr: *mut *mut kpep_config) -> c_int;
+        fn kpep_config_free(cfg: *mut kpep_config);
+        fn kpep_config_add_event(
+            cfg: *mut kpep_config,
+            ev_ptr: *mut *mut kpep_event,
+            flag: c_uint,
+            err: *mut c_uint,
+        ) -> c_int;
+        fn kpep_config_remove_event(cfg: *mut kpep_config, idx: size_t) -> c_int;
+        fn kpep_config_force_counters(cfg: *mut kpep_config) -> c_int;
+        fn kpep_config_events_count(cfg: *mut kpep_config, count_ptr: *mut size_t) -> c_int;
+        fn kpep_config_events(
+            cfg: *mut kpep_config,
+            buf: *mut *mut kpep_event,
+            buf_size: size_t,
+        ) -> c_int;
+        fn kpep_config_kpc(
+            cfg: *mut kpep_config,
+            buf: *mut kpc_config_t,
+            buf_size: size_t,
+        ) -> c_int;
+        fn kpep_config_kpc_count(cfg: *mut kpep_config, count_ptr: *mut size_t) -> c_int;
+        fn kpep_config_kpc_classes(cfg: *mut kpep_config, classes_ptr: *mut c_uint) -> c_int;
+        fn kpep_config_kpc_map(cfg: *mut kpep_config, buf: *mut size_t, buf_size: size_t) -> c_int;
+        fn kpep_db_create(name: *const c_char, db_ptr: *mut *mut kpep_db) -> c_int;
+        fn kpep_db_free(db: *mut kpep_db);
+        fn kpep_db_name(db: *mut kpep_db, name: *const *mut c_char) -> c_int;
+        fn kpep_db_aliases_count(db: *mut kpep_db, count: *mut size_t) -> c_int;
+        fn kpep_db_aliases(db: *mut kpep_db, buf: *const *mut c_char, buf_size: size_t) -> c_int;
+        fn kpep_db_counters_count(db: *mut kpep_db, classes: c_uchar, count: *mut size_t) -> c_int;
+        fn kpep_db_events_count(db: *mut kpep_db, count: *mut size_t) -> c_int;
+        fn kpep_db_events(db: *mut kpep_db, buf: *mut *mut kpep_event, buf_size: size_t) -> c_int;
+        fn kpep_db_event(
+            db: *mut kpep_db,
+            name: *const c_char,
+            ev_ptr: *mut *mut kpep_event,
+        ) -> c_int;
+        fn kpep_event_name(ev: *mut kpep_event, name_ptr: *const *mut c_char) -> c_int;
+        fn kpep_event_alias(ev: *mut kpep_event, alias_ptr: *const *mut c_char) -> c_int;
+        fn kpep_event_description(ev: *mut kpep_event, str_ptr: *const *mut c_char) -> c_int;
+    }
+}
+
+/// Load the frameworks from `/System/Library/PrivateFrameworks` the first time it is called.
+///
+/// Returns [`LoadError::Unavailable`] on every OS but macOS.
+pub fn load() -> Result<&'static KperfFunctions, LoadError> {
+    static FUNCTIONS: OnceLock<Result<KperfFunctions, LoadError>> = OnceLock::new();
+    FUNCTIONS
+        .get_or_init(load_system)
+        .as_ref()
+        .map_err(Clone::clone)
+}
+
+#[cfg(target_os = "macos")]
+fn load_system() -> Result<KperfFunctions, LoadError> {
+    unsafe { KperfFunctions::load_from(KPERF_PATH, KPERFDATA_PATH) }
+}
+
+#[cfg(not(target_os = "macos"))]
+fn load_system() -> Result<KperfFunctions, LoadError> {
+    Err(LoadError::Unavailable)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn test_unavailable_off_macos() {
+        assert_eq!(load().unwrap_err(), LoadError::Unavailable);
+    }
+
+    #[test]
+    fn test_missing_library() {
+        let err = unsafe { KperfFunctions::load_from(c"/nonexistent/kperf", KPERFDATA_PATH) };
+        assert!(matches!(err, Err(LoadError::LibraryNotFound { .. })));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_missing_symbol() {
+        let err = unsafe { KperfFunctions::load_from(c"libc.so.6", c"libc.so.6") };
+        assert_eq!(
+            err.unwrap_err(),
+            LoadError::MissingSymbol {
+                library: String::from("libc.so.6"),
+                symbol: "kpc_cpu_string",
+            }
+        );
+    }
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn test_load_frameworks() {
+        let functions = load().unwrap();
+        let pmu_version = unsafe { (functions.kpc_pmu_version)() };
+        assert_ne!(pmu_version, 0);
+    }
+}
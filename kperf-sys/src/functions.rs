@@ -1,7 +1,10 @@
 use crate::structs::{kpc_config_t, kpep_config, kpep_db, kpep_event};
 use libc::{c_char, c_int, c_uchar, c_uint, c_ulonglong, size_t};
 
-#[link(name = "kperf", kind = "framework")]
+#[cfg_attr(
+    all(target_os = "macos", not(feature = "dynamic")),
+    link(name = "kperf", kind = "framework")
+)]
 extern "C" {
     /// Print current CPU identification string to the buffer (same as snprintf),
     /// such as "cpu_7_8_10b282dc_46". This string can be used to locate the PMC
@@ -157,20 +160,41 @@ extern "C" {
     pub fn kperf_tick_frequency() -> c_ulonglong;
 }
 
-//TODO: include those two functions to the lib
-// /// Get lightweight PET mode (not in kperf.framework).
-// static int kperf_lightweight_pet_get(u32 *enabled) {
-// if (!enabled) return -1;
-// usize size = 4;
-// return sysctlbyname("kperf.lightweight_pet", enabled, &size, NULL, 0);
-// }
-//
-// /// Set lightweight PET mode (not in kperf.framework).
-// static int kperf_lightweight_pet_set(u32 enabled) {
-// return sysctlbyname("kperf.lightweight_pet", NULL, NULL, &enabled, 4);
-// }
+/// Get lightweight PET mode (not in kperf.framework).
+/// @details sysctl get(kperf.lightweight_pet)
+#[cfg(target_os = "macos")]
+pub unsafe fn kperf_lightweight_pet_get(enabled: *mut c_uint) -> c_int {
+    if enabled.is_null() {
+        return -1;
+    }
+    let mut size = std::mem::size_of::<c_uint>();
+    libc::sysctlbyname(
+        c"kperf.lightweight_pet".as_ptr(),
+        enabled as *mut libc::c_void,
+        &mut size,
+        std::ptr::null_mut(),
+        0,
+    )
+}
+
+/// Set lightweight PET mode (not in kperf.framework).
+/// @details sysctl set(kperf.lightweight_pet)
+#[cfg(target_os = "macos")]
+pub unsafe fn kperf_lightweight_pet_set(enabled: c_uint) -> c_int {
+    let mut enabled = enabled;
+    libc::sysctlbyname(
+        c"kperf.lightweight_pet".as_ptr(),
+        std::ptr::null_mut(),
+        std::ptr::null_mut(),
+        &mut enabled as *mut c_uint as *mut libc::c_void,
+        std::mem::size_of::<c_uint>(),
+    )
+}
 
-#[link(name = "kperfdata", kind = "framework")]
+#[cfg_attr(
+    all(target_os = "macos", not(feature = "dynamic")),
+    link(name = "kperfdata", kind = "framework")
+)]
 extern "C" {
     /// Create a config.
     /// @param db A kpep db, see kpep_db_create()
@@ -1,8 +1,10 @@
 pub mod constants;
+#[cfg(feature = "dynamic")]
+pub mod dynamic;
 pub mod functions;
 pub mod structs;
 
-#[cfg(test)]
+#[cfg(all(test, target_os = "macos", not(feature = "dynamic")))]
 mod tests {
     use crate::{constants::*, functions::*, structs::*};
     use std::ptr::{null, null_mut};
@@ -26,9 +28,6 @@ mod tests {
     fn test_create_kpep_db() {
         unsafe {
             let mut db_ptr: *mut kpep_db = null_mut();
-            // let mut name: &[c_char; 12] = b"test_rust_db";
-            // let name = CStr::from_bytes_until_nul().unwrap();
-            // let name_ptr: *const c_char = name.as_ptr() as *const c_char;
             let result = kpep_db_create(null(), &mut db_ptr);
             println!("db creation result: {}", result);
         }
@@ -84,3 +84,23 @@ pub struct kpep_config {
     pub power_counter: c_uint,
     pub reserved: c_uint,
 }
+
+/// kdebug trace record, as read from the trace buffer (size: 64 bytes on 64 bit OS)
+#[repr(C)]
+#[allow(non_camel_case_types)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct kd_buf {
+    /// Time of the record, in mach absolute time ticks.
+    pub timestamp: u64,
+    pub arg1: usize,
+    pub arg2: usize,
+    pub arg3: usize,
+    pub arg4: usize,
+    /// Id of the thread that emitted the record.
+    pub arg5: usize,
+    /// Class, subclass, code and function of the record, see `DBG_FUNC_MASK`.
+    pub debugid: u32,
+    /// CPU the record was emitted on.
+    pub cpuid: u32,
+    pub unused: usize,
+}